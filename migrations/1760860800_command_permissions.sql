@@ -0,0 +1,9 @@
+-- Permissions are keyed by command name rather than id so that they survive
+-- commands being re-registered (`add --replace`, `rm --all` followed by `add`).
+CREATE TABLE IF NOT EXISTS "command_permissions" (
+    "command_name" text NOT NULL,
+    "user_id" text NOT NULL,
+    "created_at" datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY ("command_name", "user_id"),
+    FOREIGN KEY ("user_id") REFERENCES "users" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
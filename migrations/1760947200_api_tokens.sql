@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS "api_tokens" (
+    "id" text NOT NULL PRIMARY KEY,
+    "user_id" text NOT NULL,
+    "name" text NOT NULL,
+    "token_hash" text NOT NULL UNIQUE,
+    "last_used_at" datetime,
+    "created_at" datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY ("user_id") REFERENCES "users" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS "command_tags" (
+    "command_id" text NOT NULL,
+    "tag" text NOT NULL,
+    PRIMARY KEY ("command_id", "tag"),
+    FOREIGN KEY ("command_id") REFERENCES "commands" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS "command_tags_tag" ON "command_tags" ("tag");
@@ -0,0 +1,4 @@
+ALTER TABLE "commands" ADD COLUMN "description" text;
+ALTER TABLE "commands" ADD COLUMN "owner" text;
+ALTER TABLE "commands" ADD COLUMN "icon" text;
+ALTER TABLE "commands" ADD COLUMN "placeholder_help" json NOT NULL DEFAULT '{}';
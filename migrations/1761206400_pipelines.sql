@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS "pipelines" (
+    "id" text NOT NULL PRIMARY KEY,
+    "name" text NOT NULL UNIQUE,
+    "on_failure" text NOT NULL DEFAULT 'stop',
+    "created_at" datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "updated_at" datetime NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS "pipeline_steps" (
+    "pipeline_id" text NOT NULL,
+    "position" integer NOT NULL,
+    "command_id" text NOT NULL,
+    "placeholders" json NOT NULL DEFAULT '{}',
+    "stdin" boolean NOT NULL DEFAULT false,
+    PRIMARY KEY ("pipeline_id", "position"),
+    FOREIGN KEY ("pipeline_id") REFERENCES "pipelines" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    FOREIGN KEY ("command_id") REFERENCES "commands" ("id") ON DELETE RESTRICT ON UPDATE CASCADE
+);
+
+ALTER TABLE "history" ADD COLUMN "pipeline_id" text REFERENCES "pipelines" ("id") ON DELETE SET NULL;
+ALTER TABLE "history" ADD COLUMN "pipeline_run_id" text;
@@ -0,0 +1,2 @@
+ALTER TABLE "pipeline_steps" ADD COLUMN "condition" json;
+ALTER TABLE "history" ADD COLUMN "decision" json;
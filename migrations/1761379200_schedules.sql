@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS "schedules" (
+    "id" text NOT NULL PRIMARY KEY,
+    "command_id" text NOT NULL,
+    "cron" text NOT NULL,
+    "timezone" text NOT NULL DEFAULT 'UTC',
+    "placeholders" json NOT NULL DEFAULT '{}',
+    "paused" boolean NOT NULL DEFAULT false,
+    "last_run_at" datetime,
+    "created_at" datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY ("command_id") REFERENCES "commands" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+ALTER TABLE "history" ADD COLUMN "triggered_by" text NOT NULL DEFAULT 'manual';
+ALTER TABLE "history" ADD COLUMN "schedule_id" text REFERENCES "schedules" ("id") ON DELETE SET NULL;
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS "webhooks" (
+    "id" text NOT NULL PRIMARY KEY,
+    "command_id" text NOT NULL,
+    "secret" text NOT NULL,
+    "placeholders" json NOT NULL DEFAULT '{}',
+    "created_at" datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY ("command_id") REFERENCES "commands" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+ALTER TABLE "history" ADD COLUMN "webhook_id" text REFERENCES "webhooks" ("id") ON DELETE SET NULL;
+ALTER TABLE "history" ADD COLUMN "trigger_metadata" json;
@@ -0,0 +1,27 @@
+CREATE TABLE IF NOT EXISTS "audit_log" (
+    "id" text NOT NULL PRIMARY KEY,
+    "actor" text NOT NULL,
+    "actor_name" text,
+    "source_ip" text,
+    "action" text NOT NULL,
+    -- No foreign key, entries outlive the commands they mention
+    "command_id" text,
+    "command_name" text,
+    "placeholders" json NOT NULL DEFAULT '{}',
+    "details" json,
+    "created_at" datetime NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS "audit_log_command_name" ON "audit_log" ("command_name");
+
+CREATE TRIGGER IF NOT EXISTS "audit_log_no_update"
+BEFORE UPDATE ON "audit_log"
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS "audit_log_no_delete"
+BEFORE DELETE ON "audit_log"
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
@@ -0,0 +1 @@
+ALTER TABLE "commands" ADD COLUMN "secret_placeholders" json NOT NULL DEFAULT '[]';
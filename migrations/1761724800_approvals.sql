@@ -0,0 +1,20 @@
+ALTER TABLE "commands" ADD COLUMN "requires_approval" boolean NOT NULL DEFAULT false;
+
+CREATE TABLE IF NOT EXISTS "approvals" (
+    "id" text NOT NULL PRIMARY KEY,
+    "command_id" text NOT NULL,
+    -- Shown to approvers, sensitive values redacted
+    "placeholders" json NOT NULL DEFAULT '{}',
+    -- Values the command runs with, cleared once the request is decided
+    "pending_placeholders" json NOT NULL DEFAULT '{}',
+    "requested_by" text,
+    "requested_from" text,
+    "status" text NOT NULL DEFAULT 'pending',
+    "decided_by" text,
+    "created_at" datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "expires_at" datetime NOT NULL,
+    "decided_at" datetime,
+    FOREIGN KEY ("command_id") REFERENCES "commands" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS "approvals_status" ON "approvals" ("status");
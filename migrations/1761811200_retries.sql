@@ -0,0 +1,5 @@
+ALTER TABLE "commands" ADD COLUMN "retry" text;
+
+-- Attempts of one run share its run_id
+ALTER TABLE "history" ADD COLUMN "run_id" text;
+ALTER TABLE "history" ADD COLUMN "attempt" integer NOT NULL DEFAULT 1;
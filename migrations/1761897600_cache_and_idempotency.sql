@@ -0,0 +1,16 @@
+ALTER TABLE "commands" ADD COLUMN "cache_seconds" integer;
+
+-- Responses to runs with an Idempotency-Key, replayed when the key is sent again
+CREATE TABLE IF NOT EXISTS "idempotency_keys" (
+    "key" text NOT NULL,
+    -- Username of the caller, empty for anonymous callers
+    "caller" text NOT NULL,
+    -- Hash of the request, so that a key can't be reused for another request
+    "fingerprint" text NOT NULL,
+    -- Null while the first request is still running
+    "status" integer,
+    "content_type" text,
+    "body" blob,
+    "created_at" timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY ("key", "caller")
+);
@@ -0,0 +1 @@
+ALTER TABLE "commands" ADD COLUMN "transforms" text NOT NULL DEFAULT '[]';
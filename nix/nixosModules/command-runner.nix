@@ -25,6 +25,16 @@ in {
         description = "The port the command-runner server should listen on.";
       };
 
+      listen = mkOption {
+        type = types.listOf types.str;
+        default = [];
+        example = ["127.0.0.1:5599;no-tls" "[fd7a:115c:a1e0::1]:5599;auth=required"];
+        description = ''
+          Addresses to listen on, overriding host and port. Each entry is an address optionally
+          followed by `;tls`, `;no-tls` or `;auth=open|required`.
+        '';
+      };
+
       user = mkOption {
         type = types.str;
         default = "command-runner";
@@ -59,6 +69,45 @@ in {
         '';
       };
 
+      commandTags = mkOption {
+        type = types.attrsOf (types.listOf types.str);
+        default = {};
+        example = {
+          "display_on" = ["display"];
+          "display_off" = ["display"];
+        };
+        description = ''
+          Tags to add to the commands in `commands`, keyed by command name.
+        '';
+      };
+
+      tls = {
+        certFile = mkOption {
+          type = types.nullOr types.str;
+          default = null;
+          example = "/var/lib/acme/example.com/fullchain.pem";
+          description = ''
+            PEM encoded certificate chain to serve over HTTPS. The file is reloaded when it changes on disk.
+          '';
+        };
+        keyFile = mkOption {
+          type = types.nullOr types.str;
+          default = null;
+          example = "/var/lib/acme/example.com/key.pem";
+          description = ''
+            PEM encoded private key for the certificate in tls.certFile.
+          '';
+        };
+        clientCaFile = mkOption {
+          type = types.nullOr types.str;
+          default = null;
+          description = ''
+            PEM encoded CA used to verify client certificates. The common name of a verified
+            certificate is mapped to the user with the same username.
+          '';
+        };
+      };
+
       database = {
         path = mkOption {
           type = types.nullOr types.str;
@@ -137,7 +186,11 @@ in {
           # CMD-RUNNER_PATH = cfg.path;
           # CMD-RUNNER_CONFIG_DIR = "/run/command-runner"; # required to start, but not used as configuration is via environment variables
         }
-        // lib.optionalAttrs (cfg.database.path != null) {CMD_RUNNER_DATABASE = cfg.database.path;};
+        // lib.optionalAttrs (cfg.database.path != null) {CMD_RUNNER_DATABASE = cfg.database.path;}
+        // lib.optionalAttrs (cfg.listen != []) {CMD_RUNNER_LISTEN = lib.concatStringsSep "," cfg.listen;}
+        // lib.optionalAttrs (cfg.tls.certFile != null) {CMD_RUNNER_TLS_CERT = cfg.tls.certFile;}
+        // lib.optionalAttrs (cfg.tls.keyFile != null) {CMD_RUNNER_TLS_KEY = cfg.tls.keyFile;}
+        // lib.optionalAttrs (cfg.tls.clientCaFile != null) {CMD_RUNNER_TLS_CLIENT_CA = cfg.tls.clientCaFile;};
     };
     systemd.services.command-runner-commands = {
       description = "command-runner commands";
@@ -155,7 +208,7 @@ in {
 
       script = let
         commands = lib.concatStringsSep "\n" (
-          lib.mapAttrsToList (name: value: "${lib.getExe cfg.package} add --replace ${name} -- ${lib.concatStringsSep " " value}") cfg.commands
+          lib.mapAttrsToList (name: value: "${lib.getExe cfg.package} add --replace ${lib.concatMapStrings (tag: "--tag ${lib.escapeShellArg tag} ") (cfg.commandTags.${name} or [])}${name} -- ${lib.concatStringsSep " " value}") cfg.commands
         );
       in ''
         ${lib.getExe cfg.package} delete --all
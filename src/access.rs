@@ -0,0 +1,160 @@
+//! Allow and deny lists of client networks, for the server as a whole and for
+//! single commands, and the client address of requests through trusted
+//! reverse proxies.
+
+use crate::*;
+use ipnet::IpNet;
+use std::{collections::BTreeMap, net::IpAddr};
+
+const FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Networks allowed or denied to connect. Denied networks win, and when
+/// `allow` is empty every network not denied is allowed.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Rules {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl Rules {
+    fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        !self.deny.iter().any(|net| net.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Access {
+    /// Rules for every request
+    #[serde(flatten)]
+    pub rules: Rules,
+    /// Rules for runs of specific commands, on top of the global ones
+    pub commands: BTreeMap<String, Rules>,
+    /// Reverse proxies whose `X-Forwarded-For` header is trusted
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl Access {
+    fn trusted(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The address of the client behind `peer`: the last address in
+    /// `X-Forwarded-For` that isn't a trusted proxy, when `peer` is one.
+    fn client(&self, peer: IpAddr, headers: &http::HeaderMap) -> IpAddr {
+        if !self.trusted(peer) {
+            return peer;
+        }
+        let forwarded: Vec<&str> = headers
+            .get_all(FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        let mut client = peer;
+        for address in forwarded.into_iter().rev() {
+            let Ok(ip) = address.trim().parse() else {
+                break;
+            };
+            client = ip;
+            if !self.trusted(ip) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// Fails unless the rules of `command` allow the client at `ip`.
+    pub fn check_command(&self, command: &str, ip: IpAddr) -> Result<()> {
+        match self.commands.get(command) {
+            Some(rules) if !rules.allows(ip) => Err(denied(
+                ip,
+                format!("Access from {ip} to command {command} is denied"),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn denied(ip: IpAddr, reason: String) -> Report<Error> {
+    Error::new()
+        .attach_printable(reason)
+        .attach(ErrorCode::Forbidden)
+        .attach(ErrorDetails(
+            serde_json::json!({ "address": ip.to_string() }),
+        ))
+}
+
+/// Middleware replacing the peer address with the client behind trusted
+/// proxies, and rejecting clients the global rules don't allow.
+pub async fn check_request(
+    axum::Extension(settings): axum::Extension<config::Settings>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+    let access = &settings.access;
+    let Some(axum::extract::ConnectInfo(peer)) = request
+        .extensions()
+        .get::<axum::extract::ConnectInfo<app::Peer>>()
+    else {
+        return next.run(request).await;
+    };
+    let address = peer.addr;
+    let client = access.client(address.ip(), request.headers());
+    if client != address.ip()
+        && let Some(axum::extract::ConnectInfo(peer)) = request
+            .extensions_mut()
+            .get_mut::<axum::extract::ConnectInfo<app::Peer>>(
+        )
+    {
+        peer.proxy = Some(address);
+        peer.addr = std::net::SocketAddr::new(client, 0);
+    }
+    if !access.rules.allows(client) {
+        return ErrorResponse::from(denied(client, format!("Access from {client} is denied")))
+            .into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules() {
+        let access: Access = toml::from_str(
+            r#"
+            allow = ["10.0.0.0/8", "::1/128"]
+            deny = ["10.0.5.0/24"]
+            trusted_proxies = ["127.0.0.1/32"]
+
+            [commands.backup]
+            allow = ["10.0.1.0/24"]
+            "#,
+        )
+        .unwrap();
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert!(access.rules.allows(ip("10.1.2.3")));
+        assert!(access.rules.allows(ip("::1")));
+        assert!(!access.rules.allows(ip("10.0.5.1")));
+        assert!(!access.rules.allows(ip("192.168.1.1")));
+        assert!(access.commands["backup"].allows(ip("10.0.1.7")));
+        assert!(!access.commands["backup"].allows(ip("10.1.2.3")));
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            FORWARDED_FOR,
+            "1.2.3.4, 10.1.1.1, 127.0.0.1".parse().unwrap(),
+        );
+        assert_eq!(access.client(ip("127.0.0.1"), &headers), ip("10.1.1.1"));
+        assert_eq!(access.client(ip("10.9.9.9"), &headers), ip("10.9.9.9"));
+        headers.insert(FORWARDED_FOR, "127.0.0.1".parse().unwrap());
+        assert_eq!(access.client(ip("127.0.0.1"), &headers), ip("127.0.0.1"));
+    }
+}
@@ -1,21 +1,68 @@
 use crate::*;
+use axum::extract::connect_info::Connected;
+use axum::serve::IncomingStream;
+use axum_login::{
+    AuthManagerLayerBuilder,
+    tower_sessions::{ExpiredDeletion, Expiry, SessionManagerLayer, cookie::time::Duration},
+};
 use sqlx::SqlitePool;
+use std::{net::SocketAddr, sync::Arc};
+use tower_sessions_sqlx_store::SqliteStore;
 
 pub struct App {
     database: SqlitePool,
-    host: core::net::IpAddr,
-    port: u16,
+    endpoints: Vec<Endpoint>,
+    settings: config::Settings,
+}
+
+/// A resolved listen address along with how it should be served.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub address: SocketAddr,
+    pub tls: Option<tls::TlsConfig>,
+    pub auth: auth::AuthPolicy,
+}
+
+/// Connection level information about the remote end of a request.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// Common name of the verified client certificate, if one was presented.
+    pub certificate: Option<String>,
+    /// The trusted reverse proxy the request came through, in which case
+    /// `addr` is the client address it forwarded, without a port.
+    pub proxy: Option<SocketAddr>,
+}
+
+impl Connected<IncomingStream<'_, tokio::net::TcpListener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, tokio::net::TcpListener>) -> Self {
+        Peer {
+            addr: *stream.remote_addr(),
+            certificate: None,
+            proxy: None,
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, tls::TlsListener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, tls::TlsListener>) -> Self {
+        let (_, connection) = stream.io().get_ref();
+        Peer {
+            addr: *stream.remote_addr(),
+            certificate: connection
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(tls::certificate_subject),
+            proxy: None,
+        }
+    }
 }
-// use axum_login::{
-//     AuthManagerLayerBuilder, login_required,
-//     tower_sessions::{ExpiredDeletion, Expiry, SessionManagerLayer},
-// };
 
 impl App {
     pub async fn new(
         database_url: impl AsRef<str>,
-        host: core::net::IpAddr,
-        port: u16,
+        endpoints: Vec<Endpoint>,
+        settings: config::Settings,
     ) -> Result<Self> {
         let database = crate::database::connect(database_url)
             .await
@@ -23,100 +70,101 @@ impl App {
             .attach_printable("Failed to connect to database")?;
         Ok(App {
             database,
-            host,
-            port,
+            endpoints,
+            settings,
         })
     }
 
     pub async fn serve(self) -> Result<()> {
-        use ::tap::*;
-        tracing::info!("Starting server at http://{}:{}", self.host, self.port);
-        let app = routes::routes()
-            .layer(axum::Extension(self.database.clone()))
-            .pipe(|app| {
-                {
-                    #[cfg(debug_assertions)]
-                    app.layer(axum::middleware::from_fn(routes::handler_405))
-                }
-                #[cfg(not(debug_assertions))]
-                app
-            })
-            .fallback(routes::handler_404);
-        let listener = tokio::net::TcpListener::bind((self.host, self.port))
+        let session_store = SqliteStore::new(self.database.clone());
+        session_store
+            .migrate()
             .await
             .change_context(Error)
-            .attach_printable("Failed to create listener")?;
-        axum::serve(listener, app.into_make_service())
-            .await
-            .change_context(Error)?;
+            .attach_printable("Failed to apply session store migrations")?;
+        tokio::spawn(
+            session_store
+                .clone()
+                .continuously_delete_expired(tokio::time::Duration::from_secs(60)),
+        );
+
+        telemetry::install()?;
+        tokio::spawn(schedule::run(self.database.clone(), self.settings.clone()));
+
+        let mut servers = tokio::task::JoinSet::new();
+        for endpoint in self.endpoints {
+            let app = router(
+                &self.database,
+                &self.settings,
+                session_store.clone(),
+                &endpoint,
+            );
+            let listener = tokio::net::TcpListener::bind(endpoint.address)
+                .await
+                .change_context(Error)
+                .attach_printable_lazy(|| {
+                    format!("Failed to create listener on {}", endpoint.address)
+                })?;
+            if let Some(tls) = endpoint.tls {
+                let resolver = Arc::new(tls::ReloadingResolver::new(tls, tls::provider())?);
+                let config = tls::server_config(resolver.clone())?;
+                tokio::spawn(resolver.watch());
+                let listener = tls::TlsListener::new(listener, config)?;
+                tracing::info!("Starting server at https://{}", endpoint.address);
+                servers.spawn(
+                    axum::serve(listener, app.into_make_service_with_connect_info::<Peer>())
+                        .into_future(),
+                );
+            } else {
+                tracing::info!("Starting server at http://{}", endpoint.address);
+                servers.spawn(
+                    axum::serve(listener, app.into_make_service_with_connect_info::<Peer>())
+                        .into_future(),
+                );
+            }
+        }
+        // The servers only return on failure, so the first one to finish
+        // takes the whole process down with it.
+        if let Some(result) = servers.join_next().await {
+            result.change_context(Error)?.change_context(Error)?;
+        }
         Ok(())
     }
-
-    // pub async fn serve(self) -> Result<()> {
-    //     use tower_sessions_sqlx_store::SqliteStore;
-    //     let session_store = SqliteStore::new(self.database.clone());
-    //     session_store.migrate().await.change_context(Error)?;
-    //
-    //     let deletion_task = tokio::task::spawn(
-    //         session_store
-    //             .clone()
-    //             .continuously_delete_expired(tokio::time::Duration::from_secs(60)),
-    //     );
-    //
-    //     // Generate a cryptographic key to sign the session cookie.
-    //     let key = Key::generate();
-    //
-    //     let session_layer = SessionManagerLayer::new(session_store)
-    //         .with_secure(false)
-    //         .with_expiry(Expiry::OnInactivity(Duration::days(1)))
-    //         .with_signed(key);
-    //
-    //     // Auth service.
-    //     //
-    //     // This combines the session layer with our backend to establish the auth
-    //     // service which will provide the auth session as a request extension.
-    //     let backend = Backend::new(self.db);
-    //     let auth_layer = AuthManagerLayerBuilder::new(backend, session_layer).build();
-    //
-    //     let app = protected::router()
-    //         .route_layer(login_required!(Backend, login_url = "/login"))
-    //         .merge(auth::router())
-    //         .layer(MessagesManagerLayer)
-    //         .layer(auth_layer);
-    //
-    //     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    //
-    //     // Ensure we use a shutdown signal to abort the deletion task.
-    //     axum::serve(listener, app.into_make_service())
-    //         .with_graceful_shutdown(shutdown_signal(deletion_task.abort_handle()))
-    //         .await?;
-    //
-    //     deletion_task.await??;
-    //
-    //     Ok(())
-    // }
 }
 
-// async fn shutdown_signal(deletion_task_abort_handle: AbortHandle) {
-//     let ctrl_c = async {
-//         signal::ctrl_c()
-//             .await
-//             .expect("failed to install Ctrl+C handler");
-//     };
-//
-//     #[cfg(unix)]
-//     let terminate = async {
-//         signal::unix::signal(signal::unix::SignalKind::terminate())
-//             .expect("failed to install signal handler")
-//             .recv()
-//             .await;
-//     };
-//
-//     #[cfg(not(unix))]
-//     let terminate = std::future::pending::<()>();
-//
-//     tokio::select! {
-//         _ = ctrl_c => { deletion_task_abort_handle.abort() },
-//         _ = terminate => { deletion_task_abort_handle.abort() },
-//     }
-// }
+fn router(
+    database: &SqlitePool,
+    settings: &config::Settings,
+    session_store: SqliteStore,
+    endpoint: &Endpoint,
+) -> axum::Router {
+    use ::tap::*;
+    let session_layer = SessionManagerLayer::new(session_store)
+        .with_secure(endpoint.tls.is_some())
+        .with_expiry(Expiry::OnInactivity(Duration::days(1)));
+    let backend = users::Backend::new(database.clone(), settings.limiter.clone());
+    let auth_layer = AuthManagerLayerBuilder::new(backend, session_layer).build();
+    routes::routes()
+        .pipe(|app| match endpoint.auth {
+            auth::AuthPolicy::Open => app,
+            auth::AuthPolicy::Required => {
+                app.layer(axum::middleware::from_fn(auth::require_authentication))
+            }
+        })
+        .layer(axum::middleware::from_fn(ratelimit::limit_requests))
+        .layer(axum::middleware::from_fn(access::check_request))
+        .layer(auth_layer)
+        .layer(axum::Extension(database.clone()))
+        .layer(axum::Extension(settings.clone()))
+        .pipe(|app| {
+            {
+                #[cfg(debug_assertions)]
+                app.layer(axum::middleware::from_fn(routes::handler_405))
+            }
+            #[cfg(not(debug_assertions))]
+            app
+        })
+        .layer(axum::middleware::from_fn(telemetry::track_request))
+        .fallback(routes::handler_404)
+        .layer(axum::middleware::from_fn(errors::request_id))
+}
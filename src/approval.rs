@@ -0,0 +1,412 @@
+//! Runs of commands that require approval wait as pending requests until a
+//! second user approves or rejects them, or they expire.
+
+use crate::{
+    command::{Command, Identifier, Origin, Output, UuidWrapper},
+    *,
+};
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+
+const APPROVAL_COLUMNS: &str = "a.id, a.command_id, c.name AS command, a.placeholders, \
+    a.requested_by, a.requested_from, a.status, a.decided_by, a.created_at, a.expires_at, \
+    a.decided_at";
+
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Approvals {
+    /// How long requests wait for a decision
+    pub expire_seconds: u64,
+}
+
+impl Default for Approvals {
+    fn default() -> Self {
+        Approvals {
+            expire_seconds: 3600,
+        }
+    }
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    sqlx::Type,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum Status {
+    Pending,
+    Approved,
+    Rejected,
+    Expired,
+}
+
+/// A run of a command waiting for, or given, a decision.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, sqlx::FromRow, utoipa::ToSchema)]
+pub struct Approval {
+    #[sqlx(try_from = "UuidWrapper")]
+    pub id: uuid::Uuid,
+    #[sqlx(try_from = "UuidWrapper")]
+    pub command_id: uuid::Uuid,
+    /// Name of the command
+    pub command: String,
+    /// Placeholder values of the run, sensitive ones redacted
+    #[sqlx(json)]
+    pub placeholders: BTreeMap<String, String>,
+    /// Who asked for the run. Requests without one can't be decided
+    pub requested_by: Option<String>,
+    pub requested_from: Option<String>,
+    pub status: Status,
+    /// Who approved or rejected the run
+    pub decided_by: Option<String>,
+    pub created_at: String,
+    pub expires_at: String,
+    pub decided_at: Option<String>,
+}
+
+/// Marks requests that are past their expiry, dropping the values they
+/// would have run with.
+async fn expire(database: &SqlitePool) -> Result<()> {
+    sqlx::query(
+        "UPDATE approvals SET status = 'expired', pending_placeholders = '{}'
+        WHERE status = 'pending' AND expires_at <= CURRENT_TIMESTAMP",
+    )
+    .execute(database)
+    .await
+    .change_context(Error)
+    .attach_printable("Failed to expire approval requests")
+    .attach(ErrorCode::Database)?;
+    Ok(())
+}
+
+impl Approval {
+    /// Asks for a run of `command` with `placeholders` on behalf of whoever
+    /// caused `event`, recording it in the audit log. Anonymous callers can't
+    /// ask, as nobody could tell whether they approve their own request.
+    pub async fn request(
+        database: &SqlitePool,
+        settings: &config::Settings,
+        command: &Command,
+        placeholders: &BTreeMap<String, String>,
+        event: audit::Event,
+    ) -> Result<Approval> {
+        let Some(requested_by) = event.requester() else {
+            return Err(Error::new()
+                .attach_printable(format!(
+                    "Authentication is required to request a run of: {}",
+                    command.name
+                ))
+                .attach(ErrorCode::AuthenticationRequired)
+                .attach(ErrorDetails(serde_json::json!({ "command": command.name }))));
+        };
+        let expire_seconds = settings.approvals.expire_seconds;
+        let id = uuid::Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO approvals (id, command_id, placeholders, pending_placeholders, requested_by, requested_from, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?, datetime('now', ?))",
+        )
+        .bind(id.as_simple())
+        .bind(command.id.as_simple())
+        .bind(sqlx::types::Json(audit::redact(placeholders, Some(command))))
+        .bind(sqlx::types::Json(placeholders))
+        .bind(&requested_by)
+        .bind(event.source_ip())
+        .bind(format!("+{expire_seconds} seconds"))
+        .execute(database)
+        .await
+        .change_context(Error)
+        .attach_printable(format!("Failed to request approval for: {}", command.name))
+        .attach(ErrorCode::Database)?;
+        event
+            .with_command(command)
+            .with_placeholders(placeholders)
+            .with_details(serde_json::json!({ "approval": id }))
+            .record(database)
+            .await?;
+        tracing::info!(
+            "Run of {} by {requested_by} is waiting for approval {id}",
+            command.name
+        );
+        Self::id(database, id).await
+    }
+
+    /// Requests, newest first, optionally only those with `status`.
+    pub async fn list(database: &SqlitePool, status: Option<Status>) -> Result<Vec<Approval>> {
+        expire(database).await?;
+        sqlx::query_as(&format!(
+            "SELECT {APPROVAL_COLUMNS} FROM approvals a JOIN commands c ON c.id = a.command_id
+            WHERE ? IS NULL OR a.status = ?
+            ORDER BY a.created_at DESC, a.rowid DESC"
+        ))
+        .bind(status)
+        .bind(status)
+        .fetch_all(database)
+        .await
+        .change_context(Error)
+        .attach_printable("Failed to list approval requests")
+        .attach(ErrorCode::Database)
+    }
+
+    pub async fn id(database: &SqlitePool, id: uuid::Uuid) -> Result<Approval> {
+        expire(database).await?;
+        sqlx::query_as(&format!(
+            "SELECT {APPROVAL_COLUMNS} FROM approvals a JOIN commands c ON c.id = a.command_id
+            WHERE a.id = ?"
+        ))
+        .bind(id.as_simple())
+        .fetch_optional(database)
+        .await
+        .change_context(Error)
+        .attach_printable(format!("Failed to query approval request: {id}"))
+        .attach(ErrorCode::Database)?
+        .ok_or_else(|| {
+            Error::new()
+                .attach_printable(format!("No approval request found with id: {id}"))
+                .attach(ErrorCode::ApprovalNotFound)
+                .attach(ErrorDetails(serde_json::json!({ "id": id })))
+        })
+    }
+
+    /// Decides a pending request, returning the values the command was asked
+    /// to run with. Nobody can decide their own request, nor one without a
+    /// known requester.
+    async fn decide(
+        &self,
+        database: &SqlitePool,
+        decided_by: &str,
+        status: Status,
+    ) -> Result<BTreeMap<String, String>> {
+        let not_pending = || {
+            Error::new()
+                .attach_printable(format!("Approval request {} is not pending", self.id))
+                .attach(ErrorCode::ApprovalNotPending)
+                .attach(ErrorDetails(serde_json::json!({ "id": self.id })))
+        };
+        if self.status != Status::Pending {
+            return Err(not_pending());
+        }
+        let forbidden = |reason: String| {
+            Error::new()
+                .attach_printable(reason)
+                .attach(ErrorCode::Forbidden)
+                .attach(ErrorDetails(serde_json::json!({ "id": self.id })))
+        };
+        match self.requested_by.as_deref() {
+            None => {
+                return Err(forbidden(format!(
+                    "Approval request {} has no known requester",
+                    self.id
+                )));
+            }
+            Some(requested_by) if requested_by == decided_by => {
+                return Err(forbidden(format!(
+                    "{decided_by} can't decide their own approval request {}",
+                    self.id
+                )));
+            }
+            Some(_) => {}
+        }
+        let mut transaction = database
+            .begin()
+            .await
+            .change_context(Error)
+            .attach(ErrorCode::Database)?;
+        let placeholders: Option<sqlx::types::Json<BTreeMap<String, String>>> = sqlx::query_scalar(
+            "SELECT pending_placeholders FROM approvals
+                WHERE id = ? AND status = 'pending' AND expires_at > CURRENT_TIMESTAMP",
+        )
+        .bind(self.id.as_simple())
+        .fetch_optional(&mut *transaction)
+        .await
+        .change_context(Error)
+        .attach_printable(format!("Failed to query approval request: {}", self.id))
+        .attach(ErrorCode::Database)?;
+        // Someone else decided first, or it expired since it was loaded
+        let Some(sqlx::types::Json(placeholders)) = placeholders else {
+            return Err(not_pending());
+        };
+        let decided = sqlx::query(
+            "UPDATE approvals SET status = ?, decided_by = ?, decided_at = CURRENT_TIMESTAMP,
+                pending_placeholders = '{}'
+            WHERE id = ? AND status = 'pending'",
+        )
+        .bind(status)
+        .bind(decided_by)
+        .bind(self.id.as_simple())
+        .execute(&mut *transaction)
+        .await
+        .change_context(Error)
+        .attach_printable(format!("Failed to decide approval request: {}", self.id))
+        .attach(ErrorCode::Database)?;
+        if decided.rows_affected() == 0 {
+            return Err(not_pending());
+        }
+        transaction
+            .commit()
+            .await
+            .change_context(Error)
+            .attach(ErrorCode::Database)?;
+        tracing::info!(
+            "Approval {} of {} was {:?} by {decided_by}",
+            self.id,
+            self.command,
+            status
+        );
+        Ok(placeholders)
+    }
+
+    /// Records the decision in the audit log as `event`.
+    async fn record(
+        &self,
+        database: &SqlitePool,
+        command: &Command,
+        event: audit::Event,
+    ) -> Result<()> {
+        event
+            .with_command(command)
+            .with_placeholders(&self.placeholders)
+            .with_details(serde_json::json!({
+                "approval": self.id,
+                "requested_by": self.requested_by,
+            }))
+            .record(database)
+            .await
+    }
+
+    /// Approves the request and runs the command, saving its output to the
+    /// history along with who asked for and approved the run.
+    pub async fn approve(
+        &self,
+        database: &SqlitePool,
+        settings: &config::Settings,
+        approved_by: &str,
+        event: audit::Event,
+    ) -> Result<Output> {
+        let command = Command::identifier(database, Identifier::Id(self.command_id)).await?;
+        settings.limiter.check_command(&command.name)?;
+        let placeholders = self.decide(database, approved_by, Status::Approved).await?;
+        self.record(database, &command, event).await?;
+        let output = command.run_with_placeholder(settings, placeholders).await?;
+        output
+            .save(
+                database,
+                settings,
+                command.id,
+                &Origin {
+                    trigger_metadata: Some(serde_json::json!({
+                        "approval": self.id,
+                        "requested_by": self.requested_by,
+                        "approved_by": approved_by,
+                    })),
+                    ..Origin::default()
+                },
+            )
+            .await?;
+        Ok(output)
+    }
+
+    pub async fn reject(
+        &self,
+        database: &SqlitePool,
+        rejected_by: &str,
+        event: audit::Event,
+    ) -> Result<Approval> {
+        let command = Command::identifier(database, Identifier::Id(self.command_id)).await?;
+        self.decide(database, rejected_by, Status::Rejected).await?;
+        self.record(database, &command, event).await?;
+        Self::id(database, self.id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_decide() {
+        let path = std::env::temp_dir().join(format!("{}.sqlite", uuid::Uuid::new_v4().simple()));
+        let database = database::connect(path.to_string_lossy()).await.unwrap();
+        let settings = config::Settings::default();
+        let code = |report: Report<Error>| report.downcast_ref::<ErrorCode>().copied();
+        let event = |actor, name: Option<&str>, action| {
+            audit::Event::new(actor, name.map(str::to_owned), action)
+        };
+
+        let id = Command::new("reboot".into(), "true".into(), Vec::new())
+            .with_metadata(command::Metadata {
+                requires_approval: true,
+                ..Default::default()
+            })
+            .add(&database, command::CommandAddMode::Error)
+            .await
+            .unwrap();
+        let command = Command::identifier(&database, Identifier::Id(id))
+            .await
+            .unwrap();
+        let placeholders = BTreeMap::new();
+        let request = |actor, name| {
+            Approval::request(
+                &database,
+                &settings,
+                &command,
+                &placeholders,
+                event(actor, name, audit::Action::Request),
+            )
+        };
+
+        // Anonymous callers could approve their own request once logged in
+        let anonymous = request(audit::Actor::Anonymous, None).await.unwrap_err();
+        assert_eq!(code(anonymous), Some(ErrorCode::AuthenticationRequired));
+
+        let approval = request(audit::Actor::User, Some("alice")).await.unwrap();
+        assert_eq!(approval.requested_by.as_deref(), Some("alice"));
+        let own = approval
+            .approve(
+                &database,
+                &settings,
+                "alice",
+                event(audit::Actor::User, Some("alice"), audit::Action::Approve),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(code(own), Some(ErrorCode::Forbidden));
+        let output = approval
+            .approve(
+                &database,
+                &settings,
+                "bob",
+                event(audit::Actor::User, Some("bob"), audit::Action::Approve),
+            )
+            .await
+            .unwrap();
+        assert!(output.status.success());
+
+        // Requests left from before anonymous ones were refused
+        let unknown = uuid::Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO approvals (id, command_id, placeholders, pending_placeholders, expires_at)
+            VALUES (?, ?, '{}', '{}', datetime('now', '+1 hour'))",
+        )
+        .bind(unknown.as_simple())
+        .bind(id.as_simple())
+        .execute(&database)
+        .await
+        .unwrap();
+        let unknown = Approval::id(&database, unknown).await.unwrap();
+        let rejected = unknown
+            .reject(
+                &database,
+                "bob",
+                event(audit::Actor::User, Some("bob"), audit::Action::Reject),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(code(rejected), Some(ErrorCode::Forbidden));
+    }
+}
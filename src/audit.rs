@@ -0,0 +1,295 @@
+//! An append-only log of who ran, added or deleted which command, and from
+//! where.
+
+use crate::{
+    command::{Command, OptionalUuidWrapper, UuidWrapper},
+    *,
+};
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+
+/// Placeholders whose name contains one of these have their value redacted,
+/// like those the command marks as secret.
+const SENSITIVE: [&str; 5] = ["password", "secret", "token", "key", "credential"];
+const REDACTED: &str = "[redacted]";
+
+/// Who did something.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    sqlx::Type,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum Actor {
+    /// An unauthenticated api request
+    Anonymous,
+    /// A user signed in with a session or client certificate
+    User,
+    /// A user authenticated with an api token
+    Token,
+    Scheduler,
+    Webhook,
+    /// The cli working on the database directly
+    Cli,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    sqlx::Type,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum Action {
+    Run,
+    Add,
+    Delete,
+    /// Asked for a run of a command that requires approval
+    Request,
+    Approve,
+    Reject,
+}
+
+/// An entry of the audit log.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, sqlx::FromRow, utoipa::ToSchema)]
+pub struct Entry {
+    #[sqlx(try_from = "UuidWrapper")]
+    pub id: uuid::Uuid,
+    pub actor: Actor,
+    /// Username, schedule or webhook id, or the local user of the cli
+    pub actor_name: Option<String>,
+    pub source_ip: Option<String>,
+    pub action: Action,
+    #[sqlx(try_from = "OptionalUuidWrapper")]
+    pub command_id: Option<uuid::Uuid>,
+    pub command_name: Option<String>,
+    /// Placeholder values used by a run, sensitive ones redacted
+    #[sqlx(json)]
+    pub placeholders: BTreeMap<String, String>,
+    /// What else was affected, like the pipeline that ran
+    #[sqlx(json(nullable))]
+    pub details: Option<serde_json::Value>,
+    pub created_at: String,
+}
+
+/// An entry about to be recorded.
+#[derive(Debug, Clone)]
+pub struct Event {
+    actor: Actor,
+    actor_name: Option<String>,
+    source_ip: Option<String>,
+    action: Action,
+    command: Option<Command>,
+    placeholders: BTreeMap<String, String>,
+    details: Option<serde_json::Value>,
+}
+
+impl Event {
+    pub fn new(actor: Actor, actor_name: Option<String>, action: Action) -> Self {
+        Event {
+            actor,
+            actor_name,
+            source_ip: None,
+            action,
+            command: None,
+            placeholders: BTreeMap::new(),
+            details: None,
+        }
+    }
+
+    /// An event caused by the caller of an api request.
+    pub fn caller(caller: &auth::Caller, peer: &app::Peer, action: Action) -> Self {
+        let (actor, name) = match caller {
+            auth::Caller::Anonymous => (Actor::Anonymous, None),
+            auth::Caller::User { user, method } => (
+                match method {
+                    auth::AuthMethod::Token => Actor::Token,
+                    auth::AuthMethod::Session | auth::AuthMethod::Certificate => Actor::User,
+                },
+                Some(user.username.clone()),
+            ),
+        };
+        Event::new(actor, name, action).with_source(peer)
+    }
+
+    /// An event caused by the cli, named after the local user.
+    pub fn cli(action: Action) -> Self {
+        let user = std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .ok();
+        Event::new(Actor::Cli, user, action)
+    }
+
+    pub fn with_source(mut self, peer: &app::Peer) -> Self {
+        self.source_ip = Some(peer.addr.ip().to_string());
+        self
+    }
+
+    pub fn with_command(mut self, command: &Command) -> Self {
+        self.command = Some(command.clone());
+        self
+    }
+
+    pub fn with_placeholders(mut self, placeholders: &BTreeMap<String, String>) -> Self {
+        self.placeholders = placeholders.clone();
+        self
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    /// Who caused the event as shown to others: a username, or the kind and
+    /// id of the schedule or webhook.
+    pub fn requester(&self) -> Option<String> {
+        match self.actor {
+            Actor::Anonymous => None,
+            Actor::User | Actor::Token | Actor::Cli => self.actor_name.clone(),
+            Actor::Scheduler => Some(format!("schedule:{}", self.actor_name.as_deref()?)),
+            Actor::Webhook => Some(format!("webhook:{}", self.actor_name.as_deref()?)),
+        }
+    }
+
+    pub fn source_ip(&self) -> Option<&str> {
+        self.source_ip.as_deref()
+    }
+
+    pub async fn record(self, database: &SqlitePool) -> Result<()> {
+        let placeholders = redact(&self.placeholders, self.command.as_ref());
+        sqlx::query(
+            "INSERT INTO audit_log (id, actor, actor_name, source_ip, action, command_id, command_name, placeholders, details)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(uuid::Uuid::new_v4().as_simple())
+        .bind(self.actor)
+        .bind(&self.actor_name)
+        .bind(&self.source_ip)
+        .bind(self.action)
+        .bind(self.command.as_ref().map(|command| command.id.simple()))
+        .bind(self.command.as_ref().map(|command| &command.name))
+        .bind(sqlx::types::Json(&placeholders))
+        .bind(self.details.as_ref().map(sqlx::types::Json))
+        .execute(database)
+        .await
+        .change_context(Error)
+        .attach_printable("Failed to write to the audit log")
+        .attach(ErrorCode::Database)?;
+        Ok(())
+    }
+}
+
+/// Filters for the audit log.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, utoipa::IntoParams)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+pub struct Filter {
+    /// Only entries about the command with this name
+    pub command: Option<String>,
+    /// Only entries by the user, schedule or webhook with this name
+    pub actor: Option<String>,
+    /// Maximum number of entries to return (default: 50)
+    pub limit: u32,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            command: None,
+            actor: None,
+            limit: 50,
+        }
+    }
+}
+
+/// The most recent entries matching `filter`, newest first. With a `user`,
+/// entries about commands they may not run are left out, see
+/// [`auth::authorize`].
+pub async fn list(
+    database: &SqlitePool,
+    filter: &Filter,
+    user: Option<&users::User>,
+) -> Result<Vec<Entry>> {
+    sqlx::query_as(
+        "SELECT id, actor, actor_name, source_ip, action, command_id, command_name, placeholders,
+            details, created_at
+        FROM audit_log a
+        WHERE (? IS NULL OR command_name = ?) AND (? IS NULL OR actor_name = ?)
+            AND (NOT ? OR a.command_name IS NULL
+                OR NOT EXISTS (SELECT 1 FROM command_permissions p
+                    WHERE p.command_name = a.command_name)
+                OR EXISTS (SELECT 1 FROM command_permissions p
+                    WHERE p.command_name = a.command_name AND p.user_id = ?))
+        ORDER BY created_at DESC, rowid DESC LIMIT ?",
+    )
+    .bind(&filter.command)
+    .bind(&filter.command)
+    .bind(&filter.actor)
+    .bind(&filter.actor)
+    .bind(user.is_some())
+    .bind(user.map(|user| user.id.as_simple()))
+    .bind(filter.limit)
+    .fetch_all(database)
+    .await
+    .change_context(Error)
+    .attach_printable("Failed to query the audit log")
+    .attach(ErrorCode::Database)
+}
+
+/// `placeholders` with the values of sensitive and secret placeholders
+/// replaced.
+pub fn redact(
+    placeholders: &BTreeMap<String, String>,
+    command: Option<&Command>,
+) -> BTreeMap<String, String> {
+    placeholders
+        .iter()
+        .map(|(name, value)| {
+            let lowercase = name.to_lowercase();
+            if SENSITIVE.iter().any(|word| lowercase.contains(word))
+                || command.is_some_and(|command| command.is_secret(name))
+            {
+                (name.clone(), REDACTED.to_owned())
+            } else {
+                (name.clone(), value.clone())
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let mut command = Command::new("login".into(), "login".into(), vec!["{pin}".into()]);
+        command.metadata.secret_placeholders = vec!["pin".into()];
+        let redacted = redact(
+            &BTreeMap::from([
+                ("{name}".to_owned(), "world".to_owned()),
+                ("{API_TOKEN}".to_owned(), "abc".to_owned()),
+                ("db_password".to_owned(), "hunter2".to_owned()),
+                ("{pin}".to_owned(), "1234".to_owned()),
+            ]),
+            Some(&command),
+        );
+        assert_eq!(redacted["{name}"], "world");
+        assert_eq!(redacted["{API_TOKEN}"], REDACTED);
+        assert_eq!(redacted["db_password"], REDACTED);
+        assert_eq!(redacted["{pin}"], REDACTED);
+    }
+}
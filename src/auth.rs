@@ -0,0 +1,315 @@
+use crate::{command::Command, users::User, *};
+use axum::extract::{ConnectInfo, FromRequestParts};
+use sqlx::SqlitePool;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    Session,
+    Certificate,
+    Token,
+}
+
+/// Whether an endpoint accepts requests from unauthenticated callers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthPolicy {
+    /// Anyone may run commands and read their history, per-command permissions
+    /// still apply. Changing commands always needs a user.
+    #[default]
+    Open,
+    /// Every request except logging in has to be authenticated.
+    Required,
+}
+
+impl core::str::FromStr for AuthPolicy {
+    type Err = Report<Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(AuthPolicy::Open),
+            "required" => Ok(AuthPolicy::Required),
+            _ => Err(Error::new().attach_printable(format!("Unknown auth policy: {s}"))),
+        }
+    }
+}
+
+/// Whoever is making the current request.
+#[derive(Debug, Clone)]
+pub enum Caller {
+    Anonymous,
+    User { user: User, method: AuthMethod },
+}
+
+impl Caller {
+    pub fn user(&self) -> Option<&User> {
+        match self {
+            Caller::Anonymous => None,
+            Caller::User { user, .. } => Some(user),
+        }
+    }
+
+    /// The user making the request, or an error saying that authentication is
+    /// required to `action`.
+    pub fn authenticated(&self, action: &str) -> Result<&User> {
+        self.user().ok_or_else(|| {
+            Error::new()
+                .attach_printable(format!("Authentication is required to {action}"))
+                .attach(ErrorCode::AuthenticationRequired)
+        })
+    }
+}
+
+impl core::fmt::Display for Caller {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Caller::Anonymous => write!(f, "anonymous"),
+            Caller::User { user, method } => write!(f, "{} ({:?})", user.username, method),
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Caller {
+    type Rejection = ErrorResponse;
+
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let subject = parts
+            .extensions
+            .get::<ConnectInfo<app::Peer>>()
+            .and_then(|ConnectInfo(peer)| peer.certificate.clone());
+        if let Some(subject) = subject {
+            let database = parts.extensions.get::<SqlitePool>().ok_or_else(|| {
+                Error::new().attach_printable("Database extension is missing from the router")
+            })?;
+            let user = User::username(database, &subject).await?.ok_or_else(|| {
+                Error::new()
+                    .attach_printable(format!(
+                        "Client certificate subject {subject} is not mapped to a user"
+                    ))
+                    .attach(ErrorCode::Forbidden)
+                    .attach(ErrorDetails(serde_json::json!({ "subject": subject })))
+            })?;
+            return Ok(Caller::User {
+                user,
+                method: AuthMethod::Certificate,
+            });
+        }
+        let bearer = parts
+            .headers
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if let Some(token) = bearer {
+            let database = parts.extensions.get::<SqlitePool>().ok_or_else(|| {
+                Error::new().attach_printable("Database extension is missing from the router")
+            })?;
+            let user = ApiToken::user(database, token.trim())
+                .await?
+                .ok_or_else(|| {
+                    Error::new()
+                        .attach_printable("Invalid api token")
+                        .attach(ErrorCode::InvalidCredentials)
+                })?;
+            return Ok(Caller::User {
+                user,
+                method: AuthMethod::Token,
+            });
+        }
+        Ok(parts
+            .extensions
+            .get::<users::AuthSession>()
+            .and_then(|session| session.user.clone())
+            .map_or(Caller::Anonymous, |user| Caller::User {
+                user,
+                method: AuthMethod::Session,
+            }))
+    }
+}
+
+pub async fn require_authentication(
+    caller: core::result::Result<Caller, ErrorResponse>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+    match caller {
+        Ok(Caller::User { .. }) => next.run(request).await,
+        _ if matches!(request.uri().path(), "/login" | "/ui/login") => next.run(request).await,
+        // Webhooks are authenticated by their signature
+        _ if request.uri().path().starts_with("/hooks/") => next.run(request).await,
+        Ok(Caller::Anonymous) => ErrorResponse::from(
+            Error::new()
+                .attach_printable("Authentication is required")
+                .attach(ErrorCode::AuthenticationRequired),
+        )
+        .into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Checks whether `caller` may run `command`.
+///
+/// Commands without any permissions are open to every caller. Once a user has
+/// been allowed to run a command, only the allowed users may run it.
+pub async fn authorize(database: &SqlitePool, command: &Command, caller: &Caller) -> Result<()> {
+    let allowed: Vec<(String,)> =
+        sqlx::query_as("SELECT user_id FROM command_permissions WHERE command_name = ?")
+            .bind(&command.name)
+            .fetch_all(database)
+            .await
+            .change_context(Error)
+            .attach_printable(format!(
+                "Failed to query permissions for command: {}",
+                command.name
+            ))
+            .attach(ErrorCode::Database)?;
+    if allowed.is_empty() {
+        return Ok(());
+    }
+    let Some(user) = caller.user() else {
+        return Err(Error::new()
+            .attach_printable(format!(
+                "Authentication is required to run command: {}",
+                command.name
+            ))
+            .attach(ErrorCode::AuthenticationRequired)
+            .attach(ErrorDetails(serde_json::json!({ "command": command.name }))));
+    };
+    let id = user.id.as_simple().to_string();
+    if allowed.iter().any(|(user_id,)| *user_id == id) {
+        Ok(())
+    } else {
+        Err(Error::new()
+            .attach_printable(format!(
+                "User {} is not allowed to run command: {}",
+                user.username, command.name
+            ))
+            .attach(ErrorCode::Forbidden)
+            .attach(ErrorDetails(serde_json::json!({ "command": command.name }))))
+    }
+}
+
+pub async fn allow(database: &SqlitePool, command: &Command, user: &User) -> Result<()> {
+    sqlx::query("INSERT OR IGNORE INTO command_permissions (command_name, user_id) VALUES (?, ?)")
+        .bind(&command.name)
+        .bind(user.id.as_simple())
+        .execute(database)
+        .await
+        .change_context(Error)
+        .attach_printable(format!(
+            "Failed to allow user {} to run command: {}",
+            user.username, command.name
+        ))?;
+    Ok(())
+}
+
+pub async fn revoke(database: &SqlitePool, command: &Command, user: &User) -> Result<()> {
+    sqlx::query("DELETE FROM command_permissions WHERE command_name = ? AND user_id = ?")
+        .bind(&command.name)
+        .bind(user.id.as_simple())
+        .execute(database)
+        .await
+        .change_context(Error)
+        .attach_printable(format!(
+            "Failed to revoke permission for user {} on command: {}",
+            user.username, command.name
+        ))?;
+    Ok(())
+}
+
+/// A long lived bearer token for non-interactive clients such as the CLI in
+/// remote mode. Only a hash of the token is stored.
+#[derive(Debug, Clone, serde::Serialize, sqlx::FromRow)]
+pub struct ApiToken {
+    #[sqlx(try_from = "command::UuidWrapper")]
+    pub id: uuid::Uuid,
+    pub username: String,
+    pub name: String,
+    pub last_used_at: Option<String>,
+    pub created_at: String,
+}
+
+fn hash_token(token: &str) -> String {
+    use sha2::Digest;
+    hex::encode(sha2::Sha256::digest(token.as_bytes()))
+}
+
+impl ApiToken {
+    /// Creates a token for `user`, returning the token itself. It can't be
+    /// recovered afterwards.
+    pub async fn create(database: &SqlitePool, user: &User, name: &str) -> Result<String> {
+        let token = format!(
+            "cr_{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        );
+        sqlx::query("INSERT INTO api_tokens (id, user_id, name, token_hash) VALUES (?, ?, ?, ?)")
+            .bind(uuid::Uuid::new_v4().as_simple())
+            .bind(user.id.as_simple())
+            .bind(name)
+            .bind(hash_token(&token))
+            .execute(database)
+            .await
+            .change_context(Error)
+            .attach_printable(format!(
+                "Failed to create token for user: {}",
+                user.username
+            ))
+            .attach(ErrorCode::Database)?;
+        Ok(token)
+    }
+
+    pub async fn list(database: &SqlitePool) -> Result<Vec<ApiToken>> {
+        sqlx::query_as(
+            "SELECT t.id, u.username, t.name, t.last_used_at, t.created_at
+            FROM api_tokens t JOIN users u ON u.id = t.user_id",
+        )
+        .fetch_all(database)
+        .await
+        .change_context(Error)
+        .attach_printable("Failed to list api tokens")
+        .attach(ErrorCode::Database)
+    }
+
+    pub async fn revoke(database: &SqlitePool, id: uuid::Uuid) -> Result<()> {
+        let result = sqlx::query("DELETE FROM api_tokens WHERE id = ?")
+            .bind(id.as_simple())
+            .execute(database)
+            .await
+            .change_context(Error)
+            .attach_printable(format!("Failed to revoke api token: {}", id))
+            .attach(ErrorCode::Database)?;
+        if result.rows_affected() == 0 {
+            return Err(Error::new().attach_printable(format!("No such api token: {}", id)));
+        }
+        Ok(())
+    }
+
+    async fn user(database: &SqlitePool, token: &str) -> Result<Option<User>> {
+        let hash = hash_token(token);
+        let user = sqlx::query_as(
+            "SELECT u.* FROM users u JOIN api_tokens t ON t.user_id = u.id WHERE t.token_hash = ?",
+        )
+        .bind(&hash)
+        .fetch_optional(database)
+        .await
+        .change_context(Error)
+        .attach_printable("Failed to query api token")
+        .attach(ErrorCode::Database)?;
+        if user.is_some() {
+            sqlx::query(
+                "UPDATE api_tokens SET last_used_at = CURRENT_TIMESTAMP WHERE token_hash = ?",
+            )
+            .bind(&hash)
+            .execute(database)
+            .await
+            .change_context(Error)
+            .attach_printable("Failed to update api token")
+            .attach(ErrorCode::Database)?;
+        }
+        Ok(user)
+    }
+}
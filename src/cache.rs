@@ -0,0 +1,97 @@
+//! Outputs of successful runs of commands with a `cache_seconds`, reused by
+//! runs with the same placeholder values until they expire. Kept in memory
+//! and lost on restart.
+
+use crate::command::{Command, Output};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Expired entries are pruned once there are this many, and nothing new is
+/// cached while none of them expired.
+const MAX_ENTRIES: usize = 10_000;
+
+type Key = (uuid::Uuid, BTreeMap<String, String>);
+
+static CACHE: LazyLock<Mutex<HashMap<Key, (Instant, Output)>>> = LazyLock::new(Default::default);
+
+fn ttl(command: &Command) -> Option<Duration> {
+    command
+        .metadata
+        .cache_seconds
+        .filter(|seconds| *seconds > 0)
+        .map(Duration::from_secs)
+}
+
+/// The cached output of `command` run with `values`, if it is still fresh.
+pub fn get(command: &Command, values: &BTreeMap<String, String>) -> Option<Output> {
+    ttl(command)?;
+    let cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    cache
+        .get(&(command.id, values.clone()))
+        .filter(|(expires, _)| *expires > Instant::now())
+        .map(|(_, output)| output.clone())
+}
+
+/// Caches `output` of `command` run with `values`, when it succeeded and the
+/// command has a ttl.
+pub fn put(command: &Command, values: &BTreeMap<String, String>, output: &Output) {
+    let Some(ttl) = ttl(command).filter(|_| output.status.success()) else {
+        return;
+    };
+    let now = Instant::now();
+    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    if cache.len() >= MAX_ENTRIES {
+        cache.retain(|_, (expires, _)| *expires > now);
+        if cache.len() >= MAX_ENTRIES {
+            tracing::warn!("Output cache is full, not caching {}", command.name);
+            return;
+        }
+    }
+    cache.insert((command.id, values.clone()), (now + ttl, output.clone()));
+}
+
+/// Drops every cached output of the command with `id`, e.g. after it changed.
+pub fn forget(id: uuid::Uuid) {
+    CACHE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|(command, _), _| *command != id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Metadata;
+
+    #[test]
+    fn test_cache() {
+        let mut command = Command::new("monitors".into(), "hyprctl".into(), vec![]);
+        command.id = uuid::Uuid::new_v4();
+        let output = |code| -> Output {
+            std::process::Output {
+                status: std::os::unix::process::ExitStatusExt::from_raw(code << 8),
+                stdout: b"DP-1".to_vec(),
+                stderr: Vec::new(),
+            }
+            .into()
+        };
+        let values = BTreeMap::from([("{id}".to_string(), "1".to_string())]);
+        put(&command, &values, &output(0));
+        assert!(get(&command, &values).is_none(), "cached without a ttl");
+
+        command = command.with_metadata(Metadata {
+            cache_seconds: Some(60),
+            ..Metadata::default()
+        });
+        put(&command, &values, &output(1));
+        assert!(get(&command, &values).is_none(), "cached a failure");
+        put(&command, &values, &output(0));
+        assert_eq!(get(&command, &values).unwrap().stdout, "DP-1");
+        assert!(get(&command, &BTreeMap::new()).is_none());
+        forget(command.id);
+        assert!(get(&command, &values).is_none());
+    }
+}
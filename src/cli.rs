@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
 
 use crate::command::Identifier;
 
@@ -20,6 +20,21 @@ pub struct Cli {
         global = true
     )]
     pub config: PathBuf,
+    #[clap(
+        long,
+        global = true,
+        env = "CMD_RUNNER_REMOTE",
+        help = "Url of a running server to manage instead of the local database"
+    )]
+    pub remote: Option<String>,
+    #[clap(
+        long,
+        global = true,
+        env = "CMD_RUNNER_TOKEN",
+        hide_env_values = true,
+        help = "Api token used to authenticate against the remote server"
+    )]
+    pub token: Option<String>,
 }
 
 #[derive(Debug, clap::Subcommand)]
@@ -27,11 +42,39 @@ pub enum SubCommand {
     #[clap(name = "run")]
     Run(Run),
     #[clap(name = "add")]
-    Add(Add),
+    Add(Box<Add>),
     #[clap(name = "list")]
     List(List),
     #[clap(name = "rm", alias = "delete")]
     Rm(Rm),
+    #[clap(name = "exec", about = "Run a registered command")]
+    Exec(Exec),
+    #[clap(name = "pipeline", subcommand, about = "Manage pipelines of commands")]
+    Pipeline(Pipeline),
+    #[clap(
+        name = "schedule",
+        subcommand,
+        about = "Run commands on a cron schedule"
+    )]
+    Schedule(Schedule),
+    #[clap(
+        name = "webhook",
+        subcommand,
+        about = "Run commands from signed webhook requests"
+    )]
+    Webhook(Webhook),
+    #[clap(
+        name = "approval",
+        subcommand,
+        about = "Approve or reject runs of commands that require approval"
+    )]
+    Approval(Approval),
+    #[clap(name = "history", about = "Show the output of previous runs")]
+    History(History),
+    #[clap(name = "audit", about = "Show who ran, added or deleted commands")]
+    Audit(Audit),
+    #[clap(name = "user", subcommand)]
+    User(User),
     #[clap(name = "completions")]
     Completions { shell: clap_complete::Shell },
 }
@@ -42,6 +85,18 @@ pub struct Run {
     pub host: Option<core::net::IpAddr>,
     #[clap(long, short)]
     pub port: Option<u16>,
+    #[clap(long, help = "PEM encoded certificate chain to serve over TLS")]
+    pub tls_cert: Option<PathBuf>,
+    #[clap(long, help = "PEM encoded private key for the TLS certificate")]
+    pub tls_key: Option<PathBuf>,
+    #[clap(long, help = "PEM encoded CA used to verify client certificates")]
+    pub tls_client_ca: Option<PathBuf>,
+    #[clap(
+        long,
+        short,
+        help = "Address to listen on as ADDRESS[;tls|no-tls][;auth=open|required], can be repeated"
+    )]
+    pub listen: Vec<crate::config::Listen>,
 }
 
 #[derive(Debug, clap::Args)]
@@ -53,6 +108,13 @@ pub struct Rm {
     pub command: Option<String>,
     #[clap(long, short = 'i', help = "Remove by ID", group = "like")]
     pub id: Option<uuid::Uuid>,
+    #[clap(
+        long,
+        short = 't',
+        help = "Remove all commands with this tag",
+        group = "like"
+    )]
+    pub tag: Option<String>,
     #[clap(
         long,
         short = 'a',
@@ -62,6 +124,30 @@ pub struct Rm {
     )]
     pub all: bool,
 }
+impl Add {
+    pub fn to_metadata(&self) -> crate::command::Metadata {
+        crate::command::Metadata {
+            description: self.description.clone(),
+            owner: self.owner.clone(),
+            icon: self.icon.clone(),
+            placeholder_help: self.placeholder_help.iter().cloned().collect(),
+            secret_placeholders: self.secret_placeholders.clone(),
+            requires_approval: self.requires_approval,
+            retry: self.max_attempts.map(|max_attempts| {
+                let default = crate::command::Retry::default();
+                crate::command::Retry {
+                    max_attempts,
+                    backoff_ms: self.backoff_ms.unwrap_or(default.backoff_ms),
+                    max_backoff_ms: self.max_backoff_ms.unwrap_or(default.max_backoff_ms),
+                    exit_codes: self.retry_on.clone(),
+                }
+            }),
+            cache_seconds: self.cache_seconds,
+            transforms: self.transforms.clone(),
+        }
+    }
+}
+
 impl Rm {
     pub fn to_identifier(&self) -> crate::Result<Identifier> {
         Ok(if let Some(id) = self.id {
@@ -94,17 +180,355 @@ pub struct Add {
         group = "add_mode"
     )]
     pub replace: bool,
+    #[clap(long = "tag", short, help = "Tag the command, can be repeated")]
+    pub tags: Vec<String>,
+    #[clap(long, help = "What the command does")]
+    pub description: Option<String>,
+    #[clap(long, help = "Who to ask about the command")]
+    pub owner: Option<String>,
+    #[clap(long, help = "Icon name or url, e.g. mdi:monitor")]
+    pub icon: Option<String>,
+    #[clap(
+        long = "placeholder-help",
+        value_parser = parse_placeholder,
+        help = "Help text for a placeholder as NAME=TEXT, can be repeated"
+    )]
+    pub placeholder_help: Vec<(String, String)>,
+    #[clap(
+        long = "secret",
+        help = "Mask the value of this placeholder in the history and logs, can be repeated"
+    )]
+    pub secret_placeholders: Vec<String>,
+    #[clap(long, help = "Make runs wait for a second user to approve them")]
+    pub requires_approval: bool,
+    #[clap(
+        long,
+        help = "Try failed runs again, up to this many attempts in total"
+    )]
+    pub max_attempts: Option<u32>,
+    #[clap(
+        long,
+        requires = "max_attempts",
+        help = "Milliseconds to wait before the first retry, doubled for each one after it [default: 1000]"
+    )]
+    pub backoff_ms: Option<u64>,
+    #[clap(
+        long,
+        requires = "max_attempts",
+        help = "Longest wait between attempts in milliseconds [default: 60000]"
+    )]
+    pub max_backoff_ms: Option<u64>,
+    #[clap(
+        long,
+        requires = "max_attempts",
+        help = "Only retry runs failing with this exit code, can be repeated"
+    )]
+    pub retry_on: Vec<i32>,
+    #[clap(
+        long,
+        help = "Reuse the output of a successful run for this many seconds when run through the api with the same values"
+    )]
+    pub cache_seconds: Option<u64>,
+    #[clap(
+        long = "transform",
+        value_parser = parse_transform,
+        help = "Transform stdout in run responses, e.g. trim, lines, json, path:.a[0] or regex:PATTERN, can be repeated"
+    )]
+    pub transforms: Vec<crate::transform::Transform>,
     pub name: String,
     pub command: String,
     pub args: Vec<String>,
 }
 
+#[derive(Debug, clap::Args)]
+#[clap(group = clap::ArgGroup::new("like"))]
+pub struct Exec {
+    #[clap(long, short = 'n', group = "like")]
+    pub name: Option<String>,
+    #[clap(long, short = 'C', group = "like")]
+    pub command: Option<String>,
+    #[clap(long, short = 'i', group = "like")]
+    pub id: Option<uuid::Uuid>,
+    #[clap(long, help = "Don't save the output to the command's history")]
+    pub no_history: bool,
+    #[clap(
+        long,
+        help = "Show what the command would run and why it would fail, without running it"
+    )]
+    pub dry_run: bool,
+    #[clap(
+        help = "Name of the command unless given with a flag, followed by KEY=VALUE values for its placeholders"
+    )]
+    pub args: Vec<String>,
+}
+
+impl Exec {
+    /// The command to run and the values for its placeholders.
+    pub fn to_invocation(&self) -> crate::Result<(Identifier, BTreeMap<String, String>)> {
+        let mut args = self.args.iter();
+        let identifier = if let Some(id) = self.id {
+            Identifier::Id(id)
+        } else if let Some(name) = &self.name {
+            Identifier::Name(name.clone())
+        } else if let Some(command) = &self.command {
+            Identifier::Like(command.clone())
+        } else {
+            Identifier::Name(args.next().cloned().ok_or_else(|| {
+                crate::errors::Error::new().attach_printable("No command provided to exec")
+            })?)
+        };
+        let placeholders = args
+            .map(|arg| {
+                parse_placeholder(arg)
+                    .map_err(|message| crate::errors::Error::new().attach_printable(message))
+            })
+            .collect::<crate::Result<_>>()?;
+        Ok((identifier, placeholders))
+    }
+}
+
+fn parse_transform(arg: &str) -> Result<crate::transform::Transform, String> {
+    crate::transform::Transform::try_from(arg.to_string())
+}
+
+fn parse_placeholder(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got {arg:?}"))
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Pipeline {
+    #[clap(name = "add")]
+    Add(PipelineAdd),
+    #[clap(name = "list")]
+    List,
+    #[clap(name = "rm", alias = "delete")]
+    Rm { name: String },
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Schedule {
+    #[clap(name = "add")]
+    Add {
+        command: String,
+        #[clap(
+            long,
+            help = "Cron expression, e.g. \"0 8 * * 1-5\", with an optional leading seconds field"
+        )]
+        cron: String,
+        #[clap(
+            long,
+            default_value = "UTC",
+            help = "Timezone the cron expression is in"
+        )]
+        timezone: String,
+        #[clap(value_parser = parse_placeholder, help = "Placeholder values as KEY=VALUE")]
+        placeholders: Vec<(String, String)>,
+    },
+    #[clap(name = "list", about = "List schedules with their next run")]
+    List,
+    #[clap(name = "pause")]
+    Pause { id: uuid::Uuid },
+    #[clap(name = "resume")]
+    Resume { id: uuid::Uuid },
+    #[clap(name = "rm", alias = "delete")]
+    Rm { id: uuid::Uuid },
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Webhook {
+    #[clap(name = "add")]
+    Add {
+        command: String,
+        #[clap(
+            long,
+            help = "Secret the payloads are signed with, generated when not given"
+        )]
+        secret: Option<String>,
+        #[clap(
+            value_parser = parse_placeholder,
+            help = "Placeholders picked from the json payload as KEY=PATH, e.g. branch=$.ref"
+        )]
+        placeholders: Vec<(String, String)>,
+    },
+    #[clap(name = "list")]
+    List,
+    #[clap(name = "rm", alias = "delete")]
+    Rm { id: uuid::Uuid },
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Approval {
+    #[clap(name = "list", about = "List pending requests")]
+    List {
+        #[clap(long, help = "List decided and expired requests too")]
+        all: bool,
+    },
+    #[clap(name = "approve", about = "Approve a request and run the command")]
+    Approve { id: uuid::Uuid },
+    #[clap(name = "reject")]
+    Reject { id: uuid::Uuid },
+}
+
+#[derive(Debug, clap::Args)]
+pub struct PipelineAdd {
+    #[clap(long, help = "Run the remaining steps after a step fails")]
+    pub continue_on_failure: bool,
+    #[clap(
+        long,
+        conflicts_with_all = ["name", "steps", "continue_on_failure"],
+        help = "Read the pipeline from a json file, which can also give conditions for the steps"
+    )]
+    pub file: Option<PathBuf>,
+    #[clap(required_unless_present = "file")]
+    pub name: Option<String>,
+    #[clap(
+        required_unless_present = "file",
+        value_parser = parse_step,
+        help = "Steps as \"COMMAND KEY=VALUE...\", prefix the command with | to pass it the previous step's output on stdin. {stdout} in a value is the previous step's output, {NAME} an argument of the pipeline"
+    )]
+    pub steps: Vec<crate::pipeline::NewStep>,
+}
+
+impl PipelineAdd {
+    pub fn to_new_pipeline(&self) -> crate::Result<crate::pipeline::NewPipeline> {
+        use crate::pipeline::{NewPipeline, OnFailure};
+        use error_stack::ResultExt;
+        if let Some(file) = &self.file {
+            let contents = std::fs::read_to_string(file)
+                .change_context(crate::Error)
+                .attach_printable_lazy(|| format!("Failed to read {}", file.display()))?;
+            return serde_json::from_str(&contents)
+                .change_context(crate::Error)
+                .attach_printable_lazy(|| format!("Invalid pipeline in {}", file.display()));
+        }
+        Ok(NewPipeline {
+            name: self.name.clone().unwrap_or_default(),
+            on_failure: if self.continue_on_failure {
+                OnFailure::Continue
+            } else {
+                OnFailure::Stop
+            },
+            steps: self.steps.clone(),
+        })
+    }
+}
+
+fn parse_step(spec: &str) -> Result<crate::pipeline::NewStep, String> {
+    let mut words = spec.split_whitespace();
+    let command = words.next().ok_or("expected a command")?;
+    let (command, stdin) = match command.strip_prefix('|') {
+        Some(command) => (command, true),
+        None => (command, false),
+    };
+    if command.is_empty() {
+        return Err("expected a command after |".into());
+    }
+    Ok(crate::pipeline::NewStep {
+        command_id: None,
+        command: Some(command.to_string()),
+        placeholders: words.map(parse_placeholder).collect::<Result<_, _>>()?,
+        stdin,
+        when: None,
+    })
+}
+
+#[derive(Debug, clap::Args)]
+#[clap(group = clap::ArgGroup::new("like"))]
+pub struct History {
+    #[clap(long, short = 'n', group = "like")]
+    pub name: Option<String>,
+    #[clap(long, short = 'C', group = "like")]
+    pub command: Option<String>,
+    #[clap(long, short = 'i', group = "like")]
+    pub id: Option<uuid::Uuid>,
+    #[clap(long, short = 'l', default_value_t = 20)]
+    pub limit: u32,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Audit {
+    #[clap(long, short = 'n', help = "Only entries about this command")]
+    pub command: Option<String>,
+    #[clap(
+        long,
+        short = 'a',
+        help = "Only entries by this user, schedule or webhook"
+    )]
+    pub actor: Option<String>,
+    #[clap(long, short = 'l', default_value_t = 50)]
+    pub limit: u32,
+}
+
+impl Audit {
+    pub fn to_filter(&self) -> crate::audit::Filter {
+        crate::audit::Filter {
+            command: self.command.clone(),
+            actor: self.actor.clone(),
+            limit: self.limit,
+        }
+    }
+}
+
+impl History {
+    pub fn to_identifier(&self) -> Option<Identifier> {
+        if let Some(id) = self.id {
+            Some(Identifier::Id(id))
+        } else if let Some(name) = &self.name {
+            Some(Identifier::Name(name.clone()))
+        } else {
+            self.command.clone().map(Identifier::Like)
+        }
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum User {
+    #[clap(name = "add")]
+    Add {
+        username: String,
+        #[clap(
+            long,
+            short,
+            help = "Password for the user, read from stdin when not provided"
+        )]
+        password: Option<String>,
+    },
+    #[clap(name = "list")]
+    List,
+    #[clap(name = "rm", alias = "delete")]
+    Rm { username: String },
+    #[clap(name = "allow", about = "Allow a user to run a command")]
+    Allow { username: String, command: String },
+    #[clap(name = "revoke", about = "Revoke a user's permission to run a command")]
+    Revoke { username: String, command: String },
+    #[clap(name = "token", subcommand, about = "Manage api tokens")]
+    Token(Token),
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Token {
+    #[clap(name = "create", about = "Create a token and print it")]
+    Create {
+        username: String,
+        #[clap(default_value = "default")]
+        name: String,
+    },
+    #[clap(name = "list")]
+    List,
+    #[clap(name = "revoke", alias = "rm")]
+    Revoke { id: uuid::Uuid },
+}
+
 #[derive(Debug, clap::Args)]
 pub struct List {
     #[clap(long, short = 'n', group = "like")]
     pub name: Option<String>,
     #[clap(long, short = 'C', group = "like")]
     pub command: Option<String>,
+    #[clap(long, short = 't', help = "Only list commands with this tag")]
+    pub tag: Option<String>,
     #[clap(long, short = 'v', help = "Enable verbose output")]
     pub verbose: bool,
 }
@@ -138,4 +562,38 @@ mod tests {
     fn test_cli() {
         Cli::verify();
     }
+
+    #[test]
+    fn test_exec_invocation() {
+        let exec = |args: &[&str]| {
+            let cli =
+                <Cli as clap::Parser>::parse_from(["command-runner", "exec"].iter().chain(args));
+            let SubCommand::Exec(exec) = cli.cmd else {
+                unreachable!()
+            };
+            exec.to_invocation()
+        };
+        let (identifier, placeholders) = exec(&["greet", "name=world", "x=a=b"]).unwrap();
+        assert!(matches!(identifier, Identifier::Name(name) if name == "greet"));
+        assert_eq!(placeholders["name"], "world");
+        assert_eq!(placeholders["x"], "a=b");
+        let (identifier, placeholders) = exec(&["--name", "greet", "--", "name=world"]).unwrap();
+        assert!(matches!(identifier, Identifier::Name(name) if name == "greet"));
+        assert_eq!(placeholders.len(), 1);
+        assert!(exec(&["greet", "name"]).is_err());
+        assert!(exec(&[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_step() {
+        let step = parse_step("greet name={who}").unwrap();
+        assert_eq!(step.command.as_deref(), Some("greet"));
+        assert_eq!(step.placeholders["name"], "{who}");
+        assert!(!step.stdin);
+        let step = parse_step("|upper").unwrap();
+        assert_eq!(step.command.as_deref(), Some("upper"));
+        assert!(step.stdin);
+        assert!(parse_step("| upper").is_err());
+        assert!(parse_step("greet name").is_err());
+    }
 }
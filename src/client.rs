@@ -0,0 +1,677 @@
+use crate::{
+    approval::{Approval, Status},
+    command::{Command, CommandAddMode, DryRun, History, Identifier, Origin, Output},
+    pipeline::{NewPipeline, Pipeline, PipelineOutput, Runnable},
+    schedule::{NewSchedule, Schedule},
+    webhook::{NewWebhook, Webhook},
+    *,
+};
+use std::collections::BTreeMap;
+
+/// Talks to a running server over its http api instead of opening the
+/// database directly.
+pub struct Remote {
+    base: reqwest::Url,
+    http: reqwest::Client,
+    token: Option<String>,
+}
+
+impl Remote {
+    pub fn new(url: &str, token: Option<String>) -> Result<Self> {
+        let base = reqwest::Url::parse(url)
+            .change_context(Error)
+            .attach_printable_lazy(|| format!("Invalid remote url: {url}"))?;
+        let http = reqwest::Client::builder()
+            .user_agent(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .build()
+            .change_context(Error)?;
+        Ok(Self { base, http, token })
+    }
+
+    /// Builds a request to the api path made of `segments`, relative to the
+    /// remote url.
+    fn request(
+        &self,
+        method: reqwest::Method,
+        segments: &[&str],
+    ) -> Result<reqwest::RequestBuilder> {
+        let mut url = self.base.clone();
+        url.path_segments_mut()
+            .map_err(|_| {
+                Error::new().attach_printable(format!("Invalid remote url: {}", self.base))
+            })?
+            .pop_if_empty()
+            .extend(segments);
+        let request = self.http.request(method, url);
+        Ok(match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        })
+    }
+
+    /// Sends the request, turning error envelopes returned by the server into
+    /// reports carrying the same [`ErrorCode`].
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let response = request
+            .send()
+            .await
+            .change_context(Error)
+            .attach_printable_lazy(|| format!("Failed to reach server at {}", self.base))?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        let status = response.status();
+        let body = response.text().await.change_context(Error)?;
+        Err(match serde_json::from_str::<ErrorBody>(&body) {
+            Ok(error) => {
+                let report = Error::new()
+                    .attach_printable(format!("{} ({:?})", error.message, error.code))
+                    .attach(error.code);
+                let report = match error.details {
+                    Some(details) => report.attach_printable(format!("Details: {details}")),
+                    None => report,
+                };
+                match error.request_id {
+                    Some(request_id) => {
+                        report.attach_printable(format!("Request id: {request_id}"))
+                    }
+                    None => report,
+                }
+            }
+            Err(_) => Error::new()
+                .attach_printable(format!("Server responded with {status}: {body}"))
+                .attach(status),
+        })
+    }
+
+    async fn json<T: serde::de::DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<T> {
+        self.send(request)
+            .await?
+            .json()
+            .await
+            .change_context(Error)
+            .attach_printable("Failed to parse response from server")
+    }
+
+    pub async fn list(&self, like: Option<String>, tag: Option<String>) -> Result<Vec<Command>> {
+        let query = [("like", like), ("tag", tag)]
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect::<Vec<_>>();
+        self.json(
+            self.request(reqwest::Method::GET, &["commands"])?
+                .query(&query),
+        )
+        .await
+    }
+
+    pub async fn add(&self, command: Command, mode: CommandAddMode) -> Result<Command> {
+        self.json(
+            self.request(reqwest::Method::POST, &["commands"])?
+                .json(&routes::NewCommand {
+                    name: command.name,
+                    command: command.command,
+                    args: command.args,
+                    mode,
+                    tags: command.tags,
+                    metadata: command.metadata,
+                }),
+        )
+        .await
+    }
+
+    pub async fn delete(&self, identifier: &Identifier) -> Result<()> {
+        self.send(
+            self.request(reqwest::Method::DELETE, &["commands"])?
+                .query(&[identifier.query_pair()]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn delete_all(&self) -> Result<()> {
+        self.send(self.request(reqwest::Method::DELETE, &["commands", "all"])?)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_tagged(&self, tag: &str) -> Result<u64> {
+        self.json::<routes::Deleted>(
+            self.request(reqwest::Method::DELETE, &["commands", "tagged", tag])?,
+        )
+        .await
+        .map(|deleted| deleted.deleted)
+    }
+
+    pub async fn run(
+        &self,
+        identifier: &Identifier,
+        placeholders: &BTreeMap<String, String>,
+        history: bool,
+    ) -> Result<RunOutput> {
+        self.json(
+            self.request(reqwest::Method::POST, &["commands", "run"])?
+                .query(&[identifier.query_pair()])
+                .query(&[("full", "true"), ("history", &history.to_string())])
+                .json(placeholders),
+        )
+        .await
+    }
+
+    pub async fn dry_run(
+        &self,
+        identifier: &Identifier,
+        placeholders: &BTreeMap<String, String>,
+    ) -> Result<DryRun> {
+        self.json(
+            self.request(reqwest::Method::POST, &["commands", "run"])?
+                .query(&[identifier.query_pair()])
+                .query(&[("dry_run", "true")])
+                .json(placeholders),
+        )
+        .await
+    }
+
+    pub async fn pipelines(&self) -> Result<Vec<Pipeline>> {
+        self.json(self.request(reqwest::Method::GET, &["pipelines"])?)
+            .await
+    }
+
+    pub async fn add_pipeline(&self, new: &NewPipeline) -> Result<Pipeline> {
+        self.json(
+            self.request(reqwest::Method::POST, &["pipelines"])?
+                .json(new),
+        )
+        .await
+    }
+
+    pub async fn delete_pipeline(&self, identifier: &Identifier) -> Result<()> {
+        self.send(
+            self.request(reqwest::Method::DELETE, &["pipelines"])?
+                .query(&[identifier.query_pair()]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn schedules(&self) -> Result<Vec<Schedule>> {
+        self.json(self.request(reqwest::Method::GET, &["schedules"])?)
+            .await
+    }
+
+    pub async fn add_schedule(&self, new: &NewSchedule) -> Result<Schedule> {
+        self.json(
+            self.request(reqwest::Method::POST, &["schedules"])?
+                .json(new),
+        )
+        .await
+    }
+
+    pub async fn set_schedule_paused(&self, id: uuid::Uuid, paused: bool) -> Result<Schedule> {
+        let action = if paused { "pause" } else { "resume" };
+        self.json(self.request(
+            reqwest::Method::POST,
+            &["schedules", &id.to_string(), action],
+        )?)
+        .await
+    }
+
+    pub async fn delete_schedule(&self, id: uuid::Uuid) -> Result<()> {
+        self.send(self.request(reqwest::Method::DELETE, &["schedules", &id.to_string()])?)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn webhooks(&self) -> Result<Vec<Webhook>> {
+        self.json(self.request(reqwest::Method::GET, &["webhooks"])?)
+            .await
+    }
+
+    pub async fn add_webhook(&self, new: &NewWebhook) -> Result<Webhook> {
+        self.json(
+            self.request(reqwest::Method::POST, &["webhooks"])?
+                .json(new),
+        )
+        .await
+    }
+
+    pub async fn delete_webhook(&self, id: uuid::Uuid) -> Result<()> {
+        self.send(self.request(reqwest::Method::DELETE, &["webhooks", &id.to_string()])?)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn history(
+        &self,
+        identifier: Option<&Identifier>,
+        limit: u32,
+    ) -> Result<Vec<History>> {
+        let request = self
+            .request(reqwest::Method::GET, &["history"])?
+            .query(&[("limit", limit)]);
+        self.json(match identifier {
+            Some(identifier) => request.query(&[identifier.query_pair()]),
+            None => request,
+        })
+        .await
+    }
+
+    pub async fn audit(&self, filter: &audit::Filter) -> Result<Vec<audit::Entry>> {
+        self.json(
+            self.request(reqwest::Method::GET, &["audit"])?
+                .query(filter),
+        )
+        .await
+    }
+
+    pub async fn approvals(&self, status: Option<Status>) -> Result<Vec<Approval>> {
+        self.json(
+            self.request(reqwest::Method::GET, &["approvals"])?
+                .query(&routes::ApprovalArgs { status }),
+        )
+        .await
+    }
+
+    pub async fn approve(&self, id: uuid::Uuid) -> Result<Output> {
+        self.json(self.request(
+            reqwest::Method::POST,
+            &["approvals", &id.to_string(), "approve"],
+        )?)
+        .await
+    }
+
+    pub async fn reject(&self, id: uuid::Uuid) -> Result<Approval> {
+        self.json(self.request(
+            reqwest::Method::POST,
+            &["approvals", &id.to_string(), "reject"],
+        )?)
+        .await
+    }
+}
+
+/// Output of running either a command or a pipeline.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum RunOutput {
+    Pipeline(PipelineOutput),
+    Command(Output),
+    /// The command requires approval and waits for it
+    Pending(Approval),
+}
+
+impl RunOutput {
+    /// Writes the output to our own stdout and stderr, with a header before
+    /// each step of a pipeline.
+    pub fn print(&self) -> Result<()> {
+        use std::io::Write;
+        let write = |output: &Output| {
+            std::io::stdout()
+                .write_all(output.stdout.as_bytes())
+                .and_then(|_| std::io::stderr().write_all(output.stderr.as_bytes()))
+                .change_context(Error)
+                .attach_printable("Failed to write output")
+        };
+        match self {
+            RunOutput::Command(output) => {
+                write(output)?;
+                output.print_attempts();
+                Ok(())
+            }
+            RunOutput::Pending(approval) => {
+                eprintln!(
+                    "==> Waiting for approval, request {} expires at {}",
+                    approval.id, approval.expires_at
+                );
+                Ok(())
+            }
+            RunOutput::Pipeline(output) => {
+                for step in &output.steps {
+                    eprintln!("==> {}", step.command);
+                    write(&step.output)?;
+                }
+                for decision in output.decisions.iter().filter(|decision| !decision.result) {
+                    eprintln!(
+                        "==> Skipped step {}, its condition didn't hold",
+                        decision.step
+                    );
+                }
+                if output.skipped > 0 {
+                    eprintln!("==> Skipped {} remaining steps", output.skipped);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Exit code for the CLI, that of the first failed command.
+    pub fn exit_code(&self) -> i32 {
+        let code = |output: &Output| match output.status.success() {
+            true => 0,
+            false => output.status.code().unwrap_or(1),
+        };
+        match self {
+            RunOutput::Command(output) => code(output),
+            RunOutput::Pending(_) => 0,
+            RunOutput::Pipeline(output) => output
+                .steps
+                .iter()
+                .map(|step| code(&step.output))
+                .find(|code| *code != 0)
+                .unwrap_or(if output.success { 0 } else { 1 }),
+        }
+    }
+}
+
+/// Where the CLI reads and changes commands: the local database, along with
+/// the settings of local runs, or a remote server.
+pub enum Target {
+    Local(sqlx::SqlitePool, config::Settings),
+    Remote(Remote),
+}
+
+impl Target {
+    pub async fn new(cli: &cli::Cli) -> Result<Self> {
+        if let Some(remote) = &cli.remote {
+            return Ok(Target::Remote(Remote::new(remote, cli.token.clone())?));
+        }
+        let config = config::Config::try_new(cli)?;
+        let database_path = dunce::simplified(&config.database);
+        Ok(Target::Local(
+            database::connect(database_path.display().to_string()).await?,
+            config.settings(),
+        ))
+    }
+
+    pub async fn list(&self, like: Option<String>, tag: Option<String>) -> Result<Vec<Command>> {
+        match self {
+            Target::Local(database, _) => Command::filter(database, like, tag).await,
+            Target::Remote(remote) => remote.list(like, tag).await,
+        }
+    }
+
+    pub async fn add(&self, command: Command, mode: CommandAddMode) -> Result<()> {
+        match self {
+            Target::Local(database, _) => {
+                let id = command.add(database, mode).await?;
+                let command = Command::identifier(database, Identifier::Id(id)).await?;
+                audit::Event::cli(audit::Action::Add)
+                    .with_command(&command)
+                    .record(database)
+                    .await
+            }
+            Target::Remote(remote) => remote.add(command, mode).await.map(|_| ()),
+        }
+    }
+
+    pub async fn delete(&self, identifier: Identifier) -> Result<()> {
+        match self {
+            Target::Local(database, _) => {
+                let command = Command::identifier(database, identifier).await?;
+                command.delete(database).await?;
+                audit::Event::cli(audit::Action::Delete)
+                    .with_command(&command)
+                    .record(database)
+                    .await
+            }
+            Target::Remote(remote) => remote.delete(&identifier).await,
+        }
+    }
+
+    pub async fn delete_all(&self) -> Result<()> {
+        match self {
+            Target::Local(database, _) => {
+                Command::delete_all(database).await?;
+                audit::Event::cli(audit::Action::Delete)
+                    .with_details(serde_json::json!({ "all": true }))
+                    .record(database)
+                    .await
+            }
+            Target::Remote(remote) => remote.delete_all().await,
+        }
+    }
+
+    pub async fn delete_tagged(&self, tag: &str) -> Result<u64> {
+        match self {
+            Target::Local(database, _) => {
+                let deleted = Command::delete_tagged(database, tag).await?;
+                audit::Event::cli(audit::Action::Delete)
+                    .with_details(serde_json::json!({ "tag": tag, "deleted": deleted }))
+                    .record(database)
+                    .await?;
+                Ok(deleted)
+            }
+            Target::Remote(remote) => remote.delete_tagged(tag).await,
+        }
+    }
+
+    /// Runs the command or pipeline. The output of local commands is streamed
+    /// to the terminal while they run, everything else is only returned once
+    /// it finished.
+    pub async fn run(
+        &self,
+        identifier: Identifier,
+        placeholders: BTreeMap<String, String>,
+        history: bool,
+    ) -> Result<RunOutput> {
+        match self {
+            Target::Local(database, settings) => {
+                match Runnable::identifier(database, identifier).await? {
+                    Runnable::Command(command) if command.metadata.requires_approval => {
+                        Ok(RunOutput::Pending(
+                            Approval::request(
+                                database,
+                                settings,
+                                &command,
+                                &placeholders,
+                                audit::Event::cli(audit::Action::Request),
+                            )
+                            .await?,
+                        ))
+                    }
+                    Runnable::Command(command) => {
+                        audit::Event::cli(audit::Action::Run)
+                            .with_command(&command)
+                            .with_placeholders(&placeholders)
+                            .record(database)
+                            .await?;
+                        let output = command.run_streaming(settings, placeholders).await?;
+                        if history {
+                            output
+                                .save(database, settings, command.id, &Origin::default())
+                                .await?;
+                        }
+                        Ok(RunOutput::Command(output))
+                    }
+                    Runnable::Pipeline(pipeline) => {
+                        audit::Event::cli(audit::Action::Run)
+                            .with_placeholders(&placeholders)
+                            .with_details(serde_json::json!({ "pipeline": pipeline.name }))
+                            .record(database)
+                            .await?;
+                        Ok(RunOutput::Pipeline(
+                            pipeline
+                                .run(database, settings, &placeholders, history)
+                                .await?,
+                        ))
+                    }
+                }
+            }
+            Target::Remote(remote) => remote.run(&identifier, &placeholders, history).await,
+        }
+    }
+
+    /// What running the command would execute, without running it.
+    pub async fn dry_run(
+        &self,
+        identifier: Identifier,
+        placeholders: BTreeMap<String, String>,
+    ) -> Result<DryRun> {
+        match self {
+            Target::Local(database, settings) => {
+                match Runnable::identifier(database, identifier).await? {
+                    Runnable::Command(command) => Ok(command.dry_run(settings, placeholders)),
+                    Runnable::Pipeline(pipeline) => Err(Error::new().attach_printable(format!(
+                        "Dry runs of pipelines like {} aren't supported",
+                        pipeline.name
+                    ))),
+                }
+            }
+            Target::Remote(remote) => remote.dry_run(&identifier, &placeholders).await,
+        }
+    }
+
+    pub async fn history(
+        &self,
+        identifier: Option<Identifier>,
+        limit: u32,
+    ) -> Result<Vec<History>> {
+        match self {
+            Target::Local(database, _) => {
+                let command = match identifier {
+                    Some(identifier) => Some(Command::identifier(database, identifier).await?),
+                    None => None,
+                };
+                History::list(database, command.as_ref(), None, limit).await
+            }
+            Target::Remote(remote) => remote.history(identifier.as_ref(), limit).await,
+        }
+    }
+
+    pub async fn audit(&self, filter: &audit::Filter) -> Result<Vec<audit::Entry>> {
+        match self {
+            Target::Local(database, _) => audit::list(database, filter, None).await,
+            Target::Remote(remote) => remote.audit(filter).await,
+        }
+    }
+
+    pub async fn approvals(&self, status: Option<Status>) -> Result<Vec<Approval>> {
+        match self {
+            Target::Local(database, _) => Approval::list(database, status).await,
+            Target::Remote(remote) => remote.approvals(status).await,
+        }
+    }
+
+    /// Approves a pending run and runs the command, as the local user when
+    /// working on the database directly.
+    pub async fn approve(&self, id: uuid::Uuid) -> Result<Output> {
+        match self {
+            Target::Local(database, settings) => {
+                let event = audit::Event::cli(audit::Action::Approve);
+                let approver = local_user(&event)?;
+                Approval::id(database, id)
+                    .await?
+                    .approve(database, settings, &approver, event)
+                    .await
+            }
+            Target::Remote(remote) => remote.approve(id).await,
+        }
+    }
+
+    pub async fn reject(&self, id: uuid::Uuid) -> Result<Approval> {
+        match self {
+            Target::Local(database, _) => {
+                let event = audit::Event::cli(audit::Action::Reject);
+                let approver = local_user(&event)?;
+                Approval::id(database, id)
+                    .await?
+                    .reject(database, &approver, event)
+                    .await
+            }
+            Target::Remote(remote) => remote.reject(id).await,
+        }
+    }
+
+    pub async fn pipelines(&self) -> Result<Vec<Pipeline>> {
+        match self {
+            Target::Local(database, _) => Pipeline::list(database).await,
+            Target::Remote(remote) => remote.pipelines().await,
+        }
+    }
+
+    pub async fn add_pipeline(&self, new: NewPipeline) -> Result<Pipeline> {
+        match self {
+            Target::Local(database, _) => Pipeline::add(database, new).await,
+            Target::Remote(remote) => remote.add_pipeline(&new).await,
+        }
+    }
+
+    pub async fn delete_pipeline(&self, identifier: Identifier) -> Result<()> {
+        match self {
+            Target::Local(database, _) => {
+                Pipeline::identifier(database, &identifier)
+                    .await?
+                    .delete(database)
+                    .await
+            }
+            Target::Remote(remote) => remote.delete_pipeline(&identifier).await,
+        }
+    }
+
+    pub async fn schedules(&self) -> Result<Vec<Schedule>> {
+        match self {
+            Target::Local(database, _) => Schedule::list(database).await,
+            Target::Remote(remote) => remote.schedules().await,
+        }
+    }
+
+    pub async fn add_schedule(&self, new: NewSchedule) -> Result<Schedule> {
+        match self {
+            Target::Local(database, _) => Schedule::add(database, new).await,
+            Target::Remote(remote) => remote.add_schedule(&new).await,
+        }
+    }
+
+    pub async fn set_schedule_paused(&self, id: uuid::Uuid, paused: bool) -> Result<Schedule> {
+        match self {
+            Target::Local(database, _) => {
+                Schedule::id(database, id)
+                    .await?
+                    .set_paused(database, paused)
+                    .await
+            }
+            Target::Remote(remote) => remote.set_schedule_paused(id, paused).await,
+        }
+    }
+
+    pub async fn delete_schedule(&self, id: uuid::Uuid) -> Result<()> {
+        match self {
+            Target::Local(database, _) => Schedule::id(database, id).await?.delete(database).await,
+            Target::Remote(remote) => remote.delete_schedule(id).await,
+        }
+    }
+
+    pub async fn webhooks(&self) -> Result<Vec<Webhook>> {
+        match self {
+            Target::Local(database, _) => Webhook::list(database).await,
+            Target::Remote(remote) => remote.webhooks().await,
+        }
+    }
+
+    pub async fn add_webhook(&self, new: NewWebhook) -> Result<Webhook> {
+        match self {
+            Target::Local(database, _) => Webhook::add(database, new).await,
+            Target::Remote(remote) => remote.add_webhook(&new).await,
+        }
+    }
+
+    pub async fn delete_webhook(&self, id: uuid::Uuid) -> Result<()> {
+        match self {
+            Target::Local(database, _) => Webhook::id(database, id).await?.delete(database).await,
+            Target::Remote(remote) => remote.delete_webhook(id).await,
+        }
+    }
+}
+
+/// Name of the local user deciding an approval request in the cli.
+fn local_user(event: &audit::Event) -> Result<String> {
+    event
+        .requester()
+        .ok_or_else(|| Error::new().attach_printable("Can't tell who is deciding, USER is not set"))
+}
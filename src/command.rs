@@ -1,12 +1,11 @@
 use crate::*;
-use ::tap::*;
 use regex::Regex;
 
-use std::{collections::BTreeMap, sync::LazyLock};
+use std::{collections::BTreeMap, sync::LazyLock, time::Duration};
 static REPLACE_WITH: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r"\{.*\}").expect("Failed to compile regex"));
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, sqlx::FromRow)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, sqlx::FromRow, utoipa::ToSchema)]
 pub struct Command {
     #[sqlx(try_from = "UuidWrapper")]
     pub id: uuid::Uuid,
@@ -14,8 +13,104 @@ pub struct Command {
     pub command: String,
     #[sqlx(json)]
     pub args: Vec<String>,
+    /// Tags used to group commands, e.g. `display` or `maintenance`
+    #[sqlx(json)]
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub metadata: Metadata,
+}
+
+/// Optional settings of a command besides what it runs: descriptions for
+/// listings and generated dashboards, and how runs are handled, like secret
+/// placeholders, approvals, retries, caching and output transforms.
+#[derive(
+    Debug, Clone, Default, serde::Serialize, serde::Deserialize, sqlx::FromRow, utoipa::ToSchema,
+)]
+#[serde(default)]
+pub struct Metadata {
+    /// What the command does
+    pub description: Option<String>,
+    /// Who to ask about the command
+    pub owner: Option<String>,
+    /// Icon name or url for buttons running the command, e.g. `mdi:monitor`
+    pub icon: Option<String>,
+    /// Help text for each placeholder, keyed by the name inside the braces
+    #[sqlx(json)]
+    pub placeholder_help: BTreeMap<String, String>,
+    /// Placeholders whose values are masked in the history, the audit log and
+    /// error messages
+    #[sqlx(json)]
+    pub secret_placeholders: Vec<String>,
+    /// Runs wait for a second user to approve them
+    pub requires_approval: bool,
+    /// Tries failed runs again
+    #[sqlx(json(nullable))]
+    pub retry: Option<Retry>,
+    /// Seconds the output of a successful run is reused by runs through the
+    /// api and dashboard with the same placeholder values
+    pub cache_seconds: Option<u64>,
+    /// Transforms of stdout in run responses, like `regex:(?<level>\d+)`
+    #[sqlx(json)]
+    #[schema(value_type = Vec<String>)]
+    pub transforms: Vec<transform::Transform>,
+}
+
+/// How often a failed run is tried again. The first retry waits `backoff_ms`
+/// and each one after it twice as long as the one before, up to
+/// `max_backoff_ms`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+#[serde(default)]
+pub struct Retry {
+    /// Attempts in total, including the first
+    pub max_attempts: u32,
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Exit codes worth another attempt, any failure when empty
+    pub exit_codes: Vec<i32>,
 }
 
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            max_attempts: 3,
+            backoff_ms: 1000,
+            max_backoff_ms: 60_000,
+            exit_codes: Vec::new(),
+        }
+    }
+}
+
+impl Retry {
+    fn retryable(&self, status: &ExitStatus) -> bool {
+        !status.success
+            && (self.exit_codes.is_empty()
+                || status
+                    .code
+                    .is_some_and(|code| self.exit_codes.contains(&code)))
+    }
+
+    /// How long to wait after `attempt` failed.
+    fn backoff(&self, attempt: u32) -> Duration {
+        Duration::from_millis(
+            self.backoff_ms
+                .saturating_mul(2u64.saturating_pow(attempt.saturating_sub(1)))
+                .min(self.max_backoff_ms),
+        )
+    }
+}
+
+/// Columns selected for a [`Command`], with its tags gathered from
+/// `command_tags`.
+const COMMAND_COLUMNS: &str =
+    "id, name, command, args, description, owner, icon, placeholder_help, secret_placeholders,
+    requires_approval, retry, cache_seconds, transforms, (
+    SELECT json_group_array(tag) FROM (
+        SELECT tag FROM command_tags WHERE command_id = commands.id ORDER BY tag
+    )
+) AS tags";
+
 #[derive(Debug, Clone, serde::Serialize)]
 pub enum Identifier {
     Id(uuid::Uuid),
@@ -36,18 +131,36 @@ impl From<Identifier> for IdentifierWrapper {
     }
 }
 
+/// Query parameters accepted wherever an [`Identifier`] is expected. The first
+/// one present out of `id`, `name` and `like` is used.
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct IdentifierQuery {
+    /// Substring of the command's name or program
+    pub like: Option<String>,
+    /// Id of the command
+    pub id: Option<uuid::Uuid>,
+    /// Exact name of the command
+    pub name: Option<String>,
+}
+
+impl Identifier {
+    /// The query parameter this identifier is sent as.
+    pub fn query_pair(&self) -> (&'static str, String) {
+        match self {
+            Identifier::Id(id) => ("id", id.to_string()),
+            Identifier::Name(name) => ("name", name.clone()),
+            Identifier::Like(like) => ("like", like.clone()),
+        }
+    }
+}
+
 impl<'de> serde::Deserialize<'de> for Identifier {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        #[derive(serde::Deserialize)]
-        pub struct IdentifierStruct {
-            pub like: Option<String>,
-            pub id: Option<uuid::Uuid>,
-            pub name: Option<String>,
-        }
-        let identifier = IdentifierStruct::deserialize(deserializer)?;
+        let identifier = IdentifierQuery::deserialize(deserializer)?;
         if let Some(id) = identifier.id {
             Ok(Identifier::Id(id))
         } else if let Some(name) = identifier.name {
@@ -69,14 +182,37 @@ impl From<UuidWrapper> for uuid::Uuid {
     }
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, sqlx::Type)]
+#[sqlx(transparent)]
+pub struct OptionalUuidWrapper(Option<uuid::fmt::Simple>);
+impl From<OptionalUuidWrapper> for Option<uuid::Uuid> {
+    fn from(input: OptionalUuidWrapper) -> Self {
+        input.0.map(uuid::fmt::Simple::into_uuid)
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
 pub struct Output {
     pub stdout: String,
     pub stderr: String,
     pub status: ExitStatus,
+    /// Values of secret placeholders, masked when the output is saved
+    #[serde(skip)]
+    pub secrets: Vec<String>,
+    /// Which attempt of the run this is, counting from 1
+    #[serde(default = "first_attempt")]
+    pub attempt: u32,
+    /// Earlier attempts that failed, saved to the history along with this one
+    #[serde(skip)]
+    pub retried: Vec<Output>,
+}
+
+fn first_attempt() -> u32 {
+    1
 }
 
-#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize, Default)]
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize, Default, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
 pub enum CommandAddMode {
     Ignore,
     Replace,
@@ -84,34 +220,267 @@ pub enum CommandAddMode {
     Error,
 }
 
+/// What started a run.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    sqlx::Type,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum Trigger {
+    /// Run through the api, dashboard or cli
+    #[default]
+    Manual,
+    /// Run by a schedule
+    Scheduled,
+    /// Run by a signed request to a webhook
+    Webhook,
+}
+
+/// Where a run came from, saved with its output in the history.
+#[derive(Debug, Clone, Default)]
+pub struct Origin {
+    pub trigger: Trigger,
+    /// The schedule that started the run
+    pub schedule: Option<uuid::Uuid>,
+    /// The webhook that started the run
+    pub webhook: Option<uuid::Uuid>,
+    /// Details of the trigger, like the event of a webhook
+    pub trigger_metadata: Option<serde_json::Value>,
+    /// The pipeline the run is a step of, and the id shared by every step of
+    /// that pipeline run
+    pub pipeline: Option<(uuid::Uuid, uuid::Uuid)>,
+    /// Why a pipeline step with a condition ran or was skipped
+    pub decision: Option<pipeline::Decision>,
+}
+
 impl Output {
-    pub async fn save(&self, database: &sqlx::SqlitePool, command_id: uuid::Uuid) -> Result<()> {
+    pub async fn save(
+        &self,
+        database: &sqlx::SqlitePool,
+        settings: &config::Settings,
+        command_id: uuid::Uuid,
+        origin: &Origin,
+    ) -> Result<()> {
+        let previous =
+            notify::previous_success(database, &settings.notifications, command_id).await;
+        let run_id = uuid::Uuid::new_v4();
+        for attempt in &self.retried {
+            attempt
+                .masked()
+                .insert(database, command_id, run_id, origin)
+                .await?;
+        }
+        let output = self.masked();
+        output.insert(database, command_id, run_id, origin).await?;
+        notify::completed(
+            database,
+            &settings.notifications,
+            command_id,
+            &output,
+            origin,
+            previous,
+        );
+        Ok(())
+    }
+
+    /// Adds a single attempt of the run `run_id` to the history.
+    async fn insert(
+        &self,
+        database: &sqlx::SqlitePool,
+        command_id: uuid::Uuid,
+        run_id: uuid::Uuid,
+        origin: &Origin,
+    ) -> Result<()> {
+        let output = self;
         sqlx::query(
-            "INSERT INTO history (id, command_id, stdout, stderr, success, exit_code) VALUES (?, ?, ?, ?, ?, ?)",
+            "INSERT INTO history (id, command_id, stdout, stderr, success, exit_code, pipeline_id, pipeline_run_id, decision, triggered_by, schedule_id, webhook_id, trigger_metadata, run_id, attempt)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
         )
         .bind(uuid::Uuid::new_v4().as_simple())
-        .bind(command_id)
-        .bind(&self.stdout)
-        .bind(&self.stderr)
-        .bind(self.status.success)
-        .bind(self.status.code)
+        .bind(command_id.as_simple())
+        .bind(&output.stdout)
+        .bind(&output.stderr)
+        .bind(output.status.success)
+        .bind(output.status.code)
+        .bind(origin.pipeline.map(|(pipeline, _)| pipeline.simple()))
+        .bind(origin.pipeline.map(|(_, run)| run.simple()))
+        .bind(origin.decision.as_ref().map(sqlx::types::Json))
+        .bind(origin.trigger)
+        .bind(origin.schedule.map(|schedule| schedule.simple()))
+        .bind(origin.webhook.map(|webhook| webhook.simple()))
+        .bind(origin.trigger_metadata.as_ref().map(sqlx::types::Json))
+        .bind(run_id.as_simple())
+        .bind(output.attempt)
         .execute(database)
         .await
         .change_context(Error)
         .attach_printable(format!(
             "Failed to save output for command id: {}",
             command_id
-        ))?;
+        ))
+        .attach(ErrorCode::Database)?;
         Ok(())
     }
+
+    /// Notes on stderr how many attempts the run took, when it was retried.
+    pub fn print_attempts(&self) {
+        if self.attempt > 1 {
+            eprintln!(
+                "==> {} after {} attempts",
+                if self.status.success {
+                    "Succeeded"
+                } else {
+                    "Failed"
+                },
+                self.attempt
+            );
+        }
+    }
+
+    /// The output with the values of secret placeholders masked.
+    pub fn masked(&self) -> Output {
+        Output {
+            stdout: secret::mask(&self.stdout, &self.secrets),
+            stderr: secret::mask(&self.stderr, &self.secrets),
+            status: self.status.clone(),
+            secrets: Vec::new(),
+            attempt: self.attempt,
+            retried: Vec::new(),
+        }
+    }
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
 pub struct ExitStatus {
     success: bool,
     code: Option<i32>,
 }
 
+impl ExitStatus {
+    pub fn success(&self) -> bool {
+        self.success
+    }
+
+    /// The exit code, `None` if the process was killed by a signal.
+    pub fn code(&self) -> Option<i32> {
+        self.code
+    }
+}
+
+/// A saved run of a command.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, sqlx::FromRow, utoipa::ToSchema)]
+pub struct History {
+    #[sqlx(try_from = "UuidWrapper")]
+    pub id: uuid::Uuid,
+    #[sqlx(try_from = "UuidWrapper")]
+    pub command_id: uuid::Uuid,
+    pub name: String,
+    pub stdout: String,
+    pub stderr: String,
+    pub success: bool,
+    pub exit_code: Option<i32>,
+    pub created_at: String,
+    /// Pipeline this run was a step of
+    #[sqlx(try_from = "OptionalUuidWrapper")]
+    #[serde(default)]
+    pub pipeline_id: Option<uuid::Uuid>,
+    /// Shared by the steps of one run of the pipeline
+    #[sqlx(try_from = "OptionalUuidWrapper")]
+    #[serde(default)]
+    pub pipeline_run_id: Option<uuid::Uuid>,
+    /// Why the pipeline step ran or was skipped, when it has a condition
+    #[sqlx(json(nullable))]
+    #[serde(default)]
+    pub decision: Option<pipeline::Decision>,
+    #[serde(default)]
+    pub triggered_by: Trigger,
+    #[sqlx(try_from = "OptionalUuidWrapper")]
+    #[serde(default)]
+    pub schedule_id: Option<uuid::Uuid>,
+    #[sqlx(try_from = "OptionalUuidWrapper")]
+    #[serde(default)]
+    pub webhook_id: Option<uuid::Uuid>,
+    #[sqlx(json(nullable))]
+    #[serde(default)]
+    pub trigger_metadata: Option<serde_json::Value>,
+    /// Shared by the attempts of one run, when it was retried
+    #[sqlx(try_from = "OptionalUuidWrapper")]
+    #[serde(default)]
+    pub run_id: Option<uuid::Uuid>,
+    #[serde(default = "first_attempt")]
+    pub attempt: u32,
+}
+
+impl History {
+    /// Records a pipeline step skipped because its condition didn't hold.
+    pub async fn skipped(
+        database: &sqlx::SqlitePool,
+        settings: &config::Settings,
+        command_id: uuid::Uuid,
+        origin: &Origin,
+    ) -> Result<()> {
+        Output {
+            stdout: String::new(),
+            stderr: String::new(),
+            status: ExitStatus {
+                success: true,
+                code: None,
+            },
+            secrets: Vec::new(),
+            attempt: first_attempt(),
+            retried: Vec::new(),
+        }
+        .save(database, settings, command_id, origin)
+        .await
+    }
+
+    /// The most recent runs, newest first, optionally only of `command`. With
+    /// a `caller`, only runs of commands they may run are listed, see
+    /// [`auth::authorize`].
+    pub async fn list(
+        database: &sqlx::SqlitePool,
+        command: Option<&Command>,
+        caller: Option<&auth::Caller>,
+        limit: u32,
+    ) -> Result<Vec<History>> {
+        sqlx::query_as(
+            "SELECT h.id, h.command_id, c.name, h.stdout, h.stderr, h.success, h.exit_code, h.created_at,
+                h.pipeline_id, h.pipeline_run_id, h.decision, h.triggered_by, h.schedule_id,
+                h.webhook_id, h.trigger_metadata, h.run_id, h.attempt
+            FROM history h JOIN commands c ON c.id = h.command_id
+            WHERE (? IS NULL OR h.command_id = ?)
+                AND (NOT ?
+                    OR NOT EXISTS (SELECT 1 FROM command_permissions p WHERE p.command_name = c.name)
+                    OR EXISTS (SELECT 1 FROM command_permissions p
+                        WHERE p.command_name = c.name AND p.user_id = ?))
+            ORDER BY h.created_at DESC, h.rowid DESC LIMIT ?",
+        )
+        .bind(command.map(|command| command.id.as_simple()))
+        .bind(command.map(|command| command.id.as_simple()))
+        .bind(caller.is_some())
+        .bind(
+            caller
+                .and_then(auth::Caller::user)
+                .map(|user| user.id.as_simple()),
+        )
+        .bind(limit)
+        .fetch_all(database)
+        .await
+        .change_context(Error)
+        .attach_printable("Failed to query history")
+        .attach(ErrorCode::Database)
+    }
+}
+
 impl From<std::process::ExitStatus> for ExitStatus {
     fn from(status: std::process::ExitStatus) -> Self {
         ExitStatus {
@@ -141,10 +510,82 @@ impl From<std::process::Output> for Output {
                 // })
                 .to_string(),
             status: ExitStatus::from(output.status),
+            secrets: Vec::new(),
+            attempt: first_attempt(),
+            retried: Vec::new(),
         }
     }
 }
 
+/// What a run would execute, see [`Command::dry_run`].
+#[derive(Debug, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct DryRun {
+    /// The program as found on the `PATH`, or as given when it wasn't found
+    pub program: String,
+    /// Arguments with the placeholders replaced and secret values masked
+    pub args: Vec<String>,
+    /// Directory the command runs in, that of the server
+    pub cwd: Option<String>,
+    /// Why the run would fail, empty when it would start
+    pub errors: Vec<ErrorBody>,
+}
+
+/// Where `program` is, looked up on the `PATH` unless it is a path.
+fn find_program(program: &str) -> Option<std::path::PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+    let executable = |path: &std::path::Path| {
+        path.metadata()
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    };
+    if program.contains('/') {
+        let path = std::path::PathBuf::from(program);
+        return executable(&path).then_some(path);
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|directory| directory.join(program))
+        .find(|path| executable(path))
+}
+
+/// Copies everything from `from` to `to` while collecting it.
+async fn tee(
+    from: Option<impl tokio::io::AsyncRead + Unpin>,
+    mut to: impl tokio::io::AsyncWrite + Unpin,
+) -> std::io::Result<Vec<u8>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut collected = Vec::new();
+    let Some(mut from) = from else {
+        return Ok(collected);
+    };
+    let mut buffer = [0; 8192];
+    loop {
+        let read = from.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(collected);
+        }
+        to.write_all(&buffer[..read]).await?;
+        to.flush().await?;
+        collected.extend_from_slice(&buffer[..read]);
+    }
+}
+
+/// The name between the braces of a placeholder, or `placeholder` itself
+/// when it has none.
+pub fn placeholder_name(placeholder: &str) -> &str {
+    REPLACE_WITH.find(placeholder).map_or(placeholder, |found| {
+        let found = found.as_str();
+        &found[1..found.len() - 1]
+    })
+}
+
+/// Looks up the value for the placeholder in `arg`, either by the whole
+/// argument (`--level={level}`) or by the name between the braces (`level`).
+fn placeholder_value<'a>(arg: &str, args: &'a BTreeMap<String, String>) -> Option<&'a String> {
+    args.get(arg).or_else(|| {
+        let placeholder = REPLACE_WITH.find(arg)?.as_str();
+        args.get(&placeholder[1..placeholder.len() - 1])
+    })
+}
+
 impl Command {
     pub const fn new(name: String, command: String, args: Vec<String>) -> Self {
         Command {
@@ -152,70 +593,327 @@ impl Command {
             name,
             command,
             args,
+            tags: Vec::new(),
+            metadata: Metadata {
+                description: None,
+                owner: None,
+                icon: None,
+                placeholder_help: BTreeMap::new(),
+                secret_placeholders: Vec::new(),
+                requires_approval: false,
+                retry: None,
+                cache_seconds: None,
+                transforms: Vec::new(),
+            },
         }
     }
 
-    pub async fn run(&self) -> Result<Output> {
-        use tokio::process::Command;
-        Command::new(&self.command)
-            .args(&self.args)
-            .output()
-            .await
-            .change_context(Error)
-            .attach_printable(format!(
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Help text for `placeholder`, given either as the whole argument or the
+    /// name inside the braces.
+    pub fn placeholder_help(&self, placeholder: &str) -> Option<&str> {
+        placeholder_value(placeholder, &self.metadata.placeholder_help).map(String::as_str)
+    }
+
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Arguments that have to be replaced with a value when running the command.
+    pub fn placeholders(&self) -> impl Iterator<Item = &str> {
+        self.args
+            .iter()
+            .filter(|arg| REPLACE_WITH.is_match(arg))
+            .map(String::as_str)
+    }
+
+    pub async fn run_with_placeholder(
+        &self,
+        settings: &config::Settings,
+        args: BTreeMap<String, String>,
+    ) -> Result<Output> {
+        self.run_with_input(settings, args, None).await
+    }
+
+    /// Same as [`Command::run_with_placeholder`], with `stdin` written to the
+    /// command's standard input.
+    pub async fn run_with_input(
+        &self,
+        settings: &config::Settings,
+        args: BTreeMap<String, String>,
+        stdin: Option<&[u8]>,
+    ) -> Result<Output> {
+        let (args, secrets) = self.prepare(settings, args)?;
+        let (args, secrets) = (&args, &secrets);
+        self.retrying(move || async move {
+            let mut output = self.execute(args, secrets, stdin).await?;
+            output.secrets = secrets.clone();
+            Ok(output)
+        })
+        .await
+    }
+
+    /// Attempts the run until it succeeds, fails in a way the retry policy
+    /// doesn't cover, or is out of attempts.
+    async fn retrying<F, R>(&self, mut run: F) -> Result<Output>
+    where
+        F: FnMut() -> R,
+        R: Future<Output = Result<Output>>,
+    {
+        let mut retried = Vec::new();
+        loop {
+            let mut output = run().await?;
+            output.attempt = first_attempt() + retried.len() as u32;
+            match &self.metadata.retry {
+                Some(retry)
+                    if output.attempt < retry.max_attempts && retry.retryable(&output.status) =>
+                {
+                    let backoff = retry.backoff(output.attempt);
+                    tracing::warn!(
+                        "Attempt {} of {} failed with exit code {:?}, retrying in {backoff:?}",
+                        output.attempt,
+                        self.name,
+                        output.status.code
+                    );
+                    tokio::time::sleep(backoff).await;
+                    retried.push(output);
+                }
+                _ => {
+                    output.retried = retried;
+                    return Ok(output);
+                }
+            }
+        }
+    }
+
+    /// The arguments to run the command with, and the values of its secret
+    /// placeholders.
+    fn prepare(
+        &self,
+        settings: &config::Settings,
+        mut values: BTreeMap<String, String>,
+    ) -> Result<(Vec<String>, Vec<String>)> {
+        secret::fill(&settings.secrets, self, &mut values)?;
+        let secrets = values
+            .iter()
+            .filter(|(key, _)| self.is_secret(key))
+            .map(|(_, value)| value.clone())
+            .collect();
+        let args = self.arguments(&values).into_iter().collect::<Result<_>>()?;
+        Ok((args, secrets))
+    }
+
+    /// Whether the value of `placeholder` is secret, given either as the
+    /// whole argument or the name inside the braces.
+    pub fn is_secret(&self, placeholder: &str) -> bool {
+        let name = placeholder_name(placeholder);
+        self.metadata
+            .secret_placeholders
+            .iter()
+            .any(|secret| placeholder_name(secret) == name)
+    }
+
+    async fn execute(
+        &self,
+        args: &[String],
+        secrets: &[String],
+        stdin: Option<&[u8]>,
+    ) -> Result<Output> {
+        let failed = || {
+            format!(
                 "Failed to run command: {} with args: {}",
                 self.command,
-                self.args.join(" ")
-            ))
-            .map(From::from)
+                secret::mask(&args.join(" "), secrets)
+            )
+        };
+        let run = async {
+            let Some(stdin) = stdin else {
+                return tokio::process::Command::new(&self.command)
+                    .args(args)
+                    .output()
+                    .await
+                    .change_context(Error)
+                    .attach_printable_lazy(failed)
+                    .attach(ErrorCode::SpawnFailed)
+                    .map(From::from);
+            };
+
+            use std::process::Stdio;
+            use tokio::io::AsyncWriteExt;
+            let mut child = tokio::process::Command::new(&self.command)
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .change_context(Error)
+                .attach_printable_lazy(failed)
+                .attach(ErrorCode::SpawnFailed)?;
+            let mut input = child.stdin.take();
+            let write = async {
+                if let Some(input) = &mut input {
+                    // The command may exit without reading all of its input
+                    if let Err(error) = input.write_all(stdin).await
+                        && error.kind() != std::io::ErrorKind::BrokenPipe
+                    {
+                        return Err(error);
+                    }
+                }
+                drop(input);
+                Ok(())
+            };
+            let (_, output) = tokio::try_join!(write, child.wait_with_output())
+                .change_context(Error)
+                .attach_printable_lazy(failed)?;
+            Ok(output.into())
+        };
+        telemetry::track_run(&self.name, run).await
+    }
+
+    /// Same as [`Command::run_with_placeholder`], but the output is also copied
+    /// to our own stdout and stderr as the command produces it.
+    pub async fn run_streaming(
+        &self,
+        settings: &config::Settings,
+        args: BTreeMap<String, String>,
+    ) -> Result<Output> {
+        let (args, secrets) = self.prepare(settings, args)?;
+        let (args, secrets) = (&args, &secrets);
+        self.retrying(move || self.stream(args, secrets)).await
     }
 
-    pub async fn run_with_placeholder(&self, args: BTreeMap<String, String>) -> Result<Output> {
-        if args.is_empty() {
-            return self.run().await;
+    async fn stream(&self, args: &[String], secrets: &[String]) -> Result<Output> {
+        let failed = || {
+            format!(
+                "Failed to run command: {} with args: {}",
+                self.command,
+                secret::mask(&args.join(" "), secrets)
+            )
+        };
+
+        use std::process::Stdio;
+        let mut child = tokio::process::Command::new(&self.command)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .change_context(Error)
+            .attach_printable_lazy(failed)
+            .attach(ErrorCode::SpawnFailed)?;
+        let stdout = tee(child.stdout.take(), tokio::io::stdout());
+        let stderr = tee(child.stderr.take(), tokio::io::stderr());
+        let (stdout, stderr, status) = tokio::try_join!(stdout, stderr, child.wait())
+            .change_context(Error)
+            .attach_printable_lazy(failed)?;
+        let mut output: Output = std::process::Output {
+            status,
+            stdout,
+            stderr,
         }
+        .into();
+        output.secrets = secrets.to_vec();
+        Ok(output)
+    }
 
-        let args = self
-            .args
+    /// The command's arguments with every placeholder replaced by its value.
+    /// Without any values the arguments are used as they are.
+    fn arguments(&self, args: &BTreeMap<String, String>) -> Vec<Result<String>> {
+        self.args
             .iter()
             .map(|arg| {
-                if REPLACE_WITH.is_match(arg) {
-                    args.get(arg)
-                        .ok_or_else(|| {
-                            Error::new().attach_printable(format!(
-                                "Not enough arguments provided for command: {}",
-                                self.command
-                            ))
-                        })
-                        .and_then(|value| {
-                            let replaced_arg = REPLACE_WITH.replace_all(arg, value).to_string();
-                            if replaced_arg.is_empty() {
-                                Err(Error::new().attach_printable(format!(
-                                    "Replacement resulted in an empty argument for command: {}",
-                                    self.command
-                                )))
-                            } else {
-                                Ok(replaced_arg)
-                            }
-                        })
+                if args.is_empty() {
+                    Ok(arg.clone())
                 } else {
-                    Ok(arg.to_string())
+                    self.argument(arg, args)
                 }
             })
-            .collect::<Result<Vec<_>>>()?;
+            .collect()
+    }
 
-        use tokio::process::Command;
-        Command::new(&self.command)
-            .args(&args)
-            .output()
-            .await
-            .change_context(Error)
-            .attach_printable(format!(
-                "Failed to run command: {} with args: {}",
-                self.command,
-                args.join(" ")
-            ))
-            .map(From::from)
+    /// `arg` with its placeholder replaced by its value.
+    fn argument(&self, arg: &str, args: &BTreeMap<String, String>) -> Result<String> {
+        if !REPLACE_WITH.is_match(arg) {
+            return Ok(arg.to_string());
+        }
+        let value = placeholder_value(arg, args).ok_or_else(|| {
+            Error::new()
+                .attach_printable(format!(
+                    "Not enough arguments provided for command: {}",
+                    self.command
+                ))
+                .attach(ErrorCode::PlaceholderMissing)
+                .attach(ErrorDetails(serde_json::json!({ "placeholder": arg })))
+        })?;
+        let replaced_arg = REPLACE_WITH.replace_all(arg, value).to_string();
+        if replaced_arg.is_empty() {
+            Err(Error::new()
+                .attach_printable(format!(
+                    "Replacement resulted in an empty argument for command: {}",
+                    self.command
+                ))
+                .attach(ErrorCode::PlaceholderEmpty)
+                .attach(ErrorDetails(serde_json::json!({ "placeholder": arg }))))
+        } else {
+            Ok(replaced_arg)
+        }
+    }
+
+    /// What running the command with `values` would execute, along with
+    /// every reason it would fail, without spawning anything.
+    pub fn dry_run(
+        &self,
+        settings: &config::Settings,
+        mut values: BTreeMap<String, String>,
+    ) -> DryRun {
+        let mut errors = Vec::new();
+        if let Err(e) = secret::fill(&settings.secrets, self, &mut values) {
+            errors.push(ErrorResponse::from(e).body());
+        }
+        let secrets: Vec<String> = values
+            .iter()
+            .filter(|(key, _)| self.is_secret(key))
+            .map(|(_, value)| value.clone())
+            .collect();
+        let args = self
+            .args
+            .iter()
+            .zip(self.arguments(&values))
+            .map(|(arg, value)| match value {
+                Ok(value) => secret::mask(&value, &secrets),
+                Err(e) => {
+                    errors.push(ErrorResponse::from(e).body());
+                    arg.clone()
+                }
+            })
+            .collect();
+        let program = match find_program(&self.command) {
+            Some(program) => program.display().to_string(),
+            None => {
+                errors.push(
+                    ErrorResponse::from(
+                        Error::new()
+                            .attach_printable(format!("Program {} was not found", self.command))
+                            .attach(ErrorCode::SpawnFailed)
+                            .attach(ErrorDetails(serde_json::json!({ "program": self.command }))),
+                    )
+                    .body(),
+                );
+                self.command.clone()
+            }
+        };
+        DryRun {
+            program,
+            args,
+            cwd: std::env::current_dir()
+                .ok()
+                .map(|cwd| cwd.display().to_string()),
+            errors,
+        }
     }
 
     pub async fn add(
@@ -223,7 +921,9 @@ impl Command {
         database: &sqlx::SqlitePool,
         mode: CommandAddMode,
     ) -> Result<uuid::Uuid> {
-        query_add(database, &self, mode).await
+        let id = query_add(database, &self, mode).await?;
+        cache::forget(id);
+        Ok(id)
     }
 
     // pub async fn query(id: uuid::Uuid, database: &sqlx::SqlitePool) -> Result<Command> {
@@ -240,6 +940,31 @@ impl Command {
     pub async fn list(database: &sqlx::SqlitePool) -> Result<Vec<Command>> {
         query_list(database).await
     }
+
+    /// Lists commands, narrowed down to those matching `like` and tagged with
+    /// `tag` when given.
+    pub async fn filter(
+        database: &sqlx::SqlitePool,
+        like: Option<String>,
+        tag: Option<String>,
+    ) -> Result<Vec<Command>> {
+        match (like, tag) {
+            (Some(like), tag) => {
+                let mut commands = Command::like(database, like).await?;
+                if let Some(tag) = tag {
+                    commands.retain(|command| command.tags.contains(&tag));
+                }
+                Ok(commands)
+            }
+            (None, Some(tag)) => query_tagged(database, &tag).await,
+            (None, None) => query_list(database).await,
+        }
+    }
+
+    /// Deletes every command tagged with `tag`, returning how many were deleted.
+    pub async fn delete_tagged(database: &sqlx::SqlitePool, tag: &str) -> Result<u64> {
+        query_delete_tagged(database, tag).await
+    }
     pub async fn like(
         database: &sqlx::SqlitePool,
         pattern: impl AsRef<str>,
@@ -255,21 +980,30 @@ impl Command {
 }
 
 async fn query_get(database: &sqlx::SqlitePool, id: uuid::Uuid) -> Result<Command> {
-    sqlx::query_as("SELECT id,name, command, args FROM commands WHERE id = ?")
-        .bind(id)
-        .fetch_one(database)
-        .await
-        .change_context(Error)
-        .attach_printable(format!("Failed to query command with id: {}", id))
-        .attach(http::StatusCode::NOT_FOUND)
+    sqlx::query_as(&format!(
+        "SELECT {COMMAND_COLUMNS} FROM commands WHERE id = ?"
+    ))
+    .bind(id.as_simple())
+    .fetch_optional(database)
+    .await
+    .change_context(Error)
+    .attach_printable(format!("Failed to query command with id: {}", id))
+    .attach(ErrorCode::Database)?
+    .ok_or_else(|| {
+        Error::new()
+            .attach_printable(format!("No command found with id: {}", id))
+            .attach(ErrorCode::CommandNotFound)
+            .attach(ErrorDetails(serde_json::json!({ "id": id })))
+    })
 }
 
 async fn query_list(database: &sqlx::SqlitePool) -> Result<Vec<Command>> {
-    sqlx::query_as("SELECT id,name, command, args FROM commands")
+    sqlx::query_as(&format!("SELECT {COMMAND_COLUMNS} FROM commands"))
         .fetch_all(database)
         .await
         .change_context(Error)
         .attach_printable("Failed to list commands")
+        .attach(ErrorCode::Database)
 }
 
 async fn query_add(
@@ -277,66 +1011,204 @@ async fn query_add(
     command: &Command,
     mode: CommandAddMode,
 ) -> Result<uuid::Uuid> {
-    let id = uuid::Uuid::new_v4();
-    match mode {
-        CommandAddMode::Ignore => sqlx::query(
-            "INSERT OR IGNORE INTO commands (id, name, command, args) VALUES (?, ?, ?, ?)",
-        ),
-        CommandAddMode::Replace => sqlx::query(
-            "INSERT OR REPLACE INTO commands (id, name, command, args) VALUES (?, ?, ?, ?)",
-        ),
+    let mut transaction = database
+        .begin()
+        .await
+        .change_context(Error)
+        .attach(ErrorCode::Database)?;
+    // Pipelines are run by name like commands, so the names must not clash
+    let pipeline: Option<(String,)> = sqlx::query_as("SELECT id FROM pipelines WHERE name = ?")
+        .bind(&command.name)
+        .fetch_optional(&mut *transaction)
+        .await
+        .change_context(Error)
+        .attach_printable(format!("Failed to query pipeline: {}", command.name))
+        .attach(ErrorCode::Database)?;
+    if pipeline.is_some() {
+        return Err(Error::new()
+            .attach_printable(format!("A pipeline named {} already exists", command.name))
+            .attach(ErrorCode::PipelineExists)
+            .attach(ErrorDetails(serde_json::json!({ "name": command.name }))));
+    }
+    // Replacing updates the existing row in place, so that its id, history and
+    // the pipelines using it are kept
+    let inserted: Option<UuidWrapper> = sqlx::query_scalar(match mode {
+        CommandAddMode::Ignore => {
+            "INSERT INTO commands (id, name, command, args, description, owner, icon, placeholder_help, secret_placeholders, requires_approval, retry, cache_seconds, transforms)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (name) DO NOTHING
+            RETURNING id"
+        }
+        CommandAddMode::Replace => {
+            "INSERT INTO commands (id, name, command, args, description, owner, icon, placeholder_help, secret_placeholders, requires_approval, retry, cache_seconds, transforms)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (name) DO UPDATE SET
+                command = excluded.command,
+                args = excluded.args,
+                description = excluded.description,
+                owner = excluded.owner,
+                icon = excluded.icon,
+                placeholder_help = excluded.placeholder_help,
+                secret_placeholders = excluded.secret_placeholders,
+                requires_approval = excluded.requires_approval,
+                retry = excluded.retry,
+                cache_seconds = excluded.cache_seconds,
+                transforms = excluded.transforms
+            RETURNING id"
+        }
         CommandAddMode::Error => {
-            sqlx::query("INSERT INTO commands (id, name, command, args) VALUES (?, ?, ?, ?)")
+            "INSERT INTO commands (id, name, command, args, description, owner, icon, placeholder_help, secret_placeholders, requires_approval, retry, cache_seconds, transforms)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            RETURNING id"
         }
-    }
-    .bind(id.as_simple())
+    })
+    .bind(uuid::Uuid::new_v4().as_simple())
     .bind(&command.name)
     .bind(&command.command)
     .bind(sqlx::types::Json(&command.args))
-    .execute(database)
+    .bind(&command.metadata.description)
+    .bind(&command.metadata.owner)
+    .bind(&command.metadata.icon)
+    .bind(sqlx::types::Json(&command.metadata.placeholder_help))
+    .bind(sqlx::types::Json(&command.metadata.secret_placeholders))
+    .bind(command.metadata.requires_approval)
+    .bind(command.metadata.retry.as_ref().map(sqlx::types::Json))
+    .bind(command.metadata.cache_seconds.map(|seconds| seconds as i64))
+    .bind(sqlx::types::Json(&command.metadata.transforms))
+    .fetch_optional(&mut *transaction)
     .await
-    .change_context(Error)
-    .attach_printable(format!("Failed to add command: {}", command.command))
-    .attach(http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    .map_err(|e| {
+        let code = match &e {
+            sqlx::Error::Database(e) if e.is_unique_violation() => ErrorCode::CommandExists,
+            _ => ErrorCode::Database,
+        };
+        Report::new(e)
+            .change_context(Error)
+            .attach_printable(format!("Failed to add command: {}", command.command))
+            .attach(code)
+            .attach(ErrorDetails(serde_json::json!({ "name": command.name })))
+    })?;
+    // An ignored insert leaves the existing command and its tags untouched
+    let Some(id) = inserted.map(uuid::Uuid::from) else {
+        drop(transaction);
+        return Ok(query_name(database, &command.name).await?.id);
+    };
+    sqlx::query("DELETE FROM command_tags WHERE command_id = ?")
+        .bind(id.as_simple())
+        .execute(&mut *transaction)
+        .await
+        .change_context(Error)
+        .attach_printable(format!("Failed to clear tags of command: {}", command.name))
+        .attach(ErrorCode::Database)?;
+    for tag in &command.tags {
+        sqlx::query("INSERT OR IGNORE INTO command_tags (command_id, tag) VALUES (?, ?)")
+            .bind(id.as_simple())
+            .bind(tag)
+            .execute(&mut *transaction)
+            .await
+            .change_context(Error)
+            .attach_printable(format!(
+                "Failed to tag command: {} with: {}",
+                command.name, tag
+            ))
+            .attach(ErrorCode::Database)?;
+    }
+    transaction
+        .commit()
+        .await
+        .change_context(Error)
+        .attach_printable(format!("Failed to add command: {}", command.command))
+        .attach(ErrorCode::Database)?;
     Ok(id)
 }
 
+async fn query_tagged(database: &sqlx::SqlitePool, tag: &str) -> Result<Vec<Command>> {
+    sqlx::query_as(&format!(
+        "SELECT {COMMAND_COLUMNS} FROM commands
+        WHERE id IN (SELECT command_id FROM command_tags WHERE tag = ?)"
+    ))
+    .bind(tag)
+    .fetch_all(database)
+    .await
+    .change_context(Error)
+    .attach_printable(format!("Failed to query commands tagged: {}", tag))
+    .attach(ErrorCode::Database)
+}
+
+async fn query_delete_tagged(database: &sqlx::SqlitePool, tag: &str) -> Result<u64> {
+    sqlx::query(
+        "DELETE FROM commands WHERE id IN (SELECT command_id FROM command_tags WHERE tag = ?)",
+    )
+    .bind(tag)
+    .execute(database)
+    .await
+    .map_err(delete_error)
+    .attach_printable(format!("Failed to delete commands tagged: {}", tag))
+    .map(|result| result.rows_affected())
+}
+
 async fn query_like(database: &sqlx::SqlitePool, pattern: &str) -> Result<Vec<Command>> {
     let pattern_bind = format!("%{}%", pattern);
-    let out = sqlx::query_as(
-        "SELECT id,name, command, args FROM commands WHERE command LIKE ? OR name LIKE ?",
-    )
+    let out = sqlx::query_as(&format!(
+        "SELECT {COMMAND_COLUMNS} FROM commands WHERE command LIKE ? OR name LIKE ?"
+    ))
     .bind(&pattern_bind)
     .bind(&pattern_bind)
     .fetch_all(database)
     .await
     .change_context(Error)
-    .attach_printable(format!("Failed to query commands like: {}", pattern))?;
+    .attach_printable(format!("Failed to query commands like: {}", pattern))
+    .attach(ErrorCode::Database)?;
     if out.is_empty() {
         return Err(Error)
             .attach_printable(format!("No commands found matching pattern: {}", pattern))
-            .attach(http::StatusCode::NOT_FOUND);
+            .attach(ErrorCode::CommandNotFound)
+            .attach(ErrorDetails(serde_json::json!({ "like": pattern })));
     }
     Ok(out)
 }
 
 async fn query_name(database: &sqlx::SqlitePool, name: &str) -> Result<Command> {
-    sqlx::query_as("SELECT id,name, command, args FROM commands WHERE name = ?")
-        .bind(name)
-        .fetch_one(database)
-        .await
-        .change_context(Error)
-        .attach_printable(format!("Failed to query command with name: {}", name))
-        .attach(http::StatusCode::NOT_FOUND)
+    sqlx::query_as(&format!(
+        "SELECT {COMMAND_COLUMNS} FROM commands WHERE name = ?"
+    ))
+    .bind(name)
+    .fetch_optional(database)
+    .await
+    .change_context(Error)
+    .attach_printable(format!("Failed to query command with name: {}", name))
+    .attach(ErrorCode::Database)?
+    .ok_or_else(|| {
+        Error::new()
+            .attach_printable(format!("No command found with name: {}", name))
+            .attach(ErrorCode::CommandNotFound)
+            .attach(ErrorDetails(serde_json::json!({ "name": name })))
+    })
+}
+
+/// Reports a failed delete, which is refused while a pipeline uses the command.
+fn delete_error(error: sqlx::Error) -> Report<Error> {
+    let code = match &error {
+        // `ON DELETE RESTRICT` is reported as a trigger constraint by sqlite
+        sqlx::Error::Database(e)
+            if e.is_foreign_key_violation()
+                || e.message().contains("FOREIGN KEY constraint failed") =>
+        {
+            ErrorCode::CommandInUse
+        }
+        _ => ErrorCode::Database,
+    };
+    Report::new(error).change_context(Error).attach(code)
 }
 
 async fn query_delete(database: &sqlx::SqlitePool, id: uuid::Uuid) -> Result<()> {
     sqlx::query("DELETE FROM commands WHERE id = ?")
-        .bind(id)
+        .bind(id.as_simple())
         .execute(database)
         .await
-        .change_context(Error)
-        .attach_printable(format!("Failed to delete command with id: {}", id))?;
+        .map_err(delete_error)
+        .attach_printable(format!("Failed to delete command with id: {}", id))
+        .attach(ErrorDetails(serde_json::json!({ "id": id })))?;
     Ok(())
 }
 
@@ -344,7 +1216,7 @@ async fn delete_all(database: &sqlx::SqlitePool) -> Result<()> {
     sqlx::query("DELETE FROM commands")
         .execute(database)
         .await
-        .change_context(Error)
+        .map_err(delete_error)
         .attach_printable("Failed to delete all commands")?;
     Ok(())
 }
@@ -359,6 +1231,108 @@ async fn query_identifier(database: &sqlx::SqlitePool, identifier: Identifier) -
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry() {
+        let retry = Retry {
+            max_attempts: 5,
+            backoff_ms: 100,
+            max_backoff_ms: 300,
+            exit_codes: vec![75],
+        };
+        assert_eq!(retry.backoff(1), Duration::from_millis(100));
+        assert_eq!(retry.backoff(2), Duration::from_millis(200));
+        assert_eq!(retry.backoff(3), Duration::from_millis(300));
+        assert_eq!(retry.backoff(u32::MAX), Duration::from_millis(300));
+        let status = |success, code| ExitStatus { success, code };
+        assert!(retry.retryable(&status(false, Some(75))));
+        assert!(!retry.retryable(&status(false, Some(1))));
+        assert!(!retry.retryable(&status(false, None)));
+        assert!(!retry.retryable(&status(true, Some(0))));
+        let any = Retry::default();
+        assert!(any.retryable(&status(false, Some(1))));
+        assert!(any.retryable(&status(false, None)));
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let settings = config::Settings::default();
+        let command = Command::new("greet".into(), "echo".into(), vec!["{name}".into()]);
+        let dry_run = command.dry_run(&settings, BTreeMap::new());
+        assert_eq!(dry_run.args, vec!["{name}".to_string()]);
+        assert!(dry_run.errors.is_empty());
+        let values = BTreeMap::from([("other".to_string(), "x".to_string())]);
+        let dry_run = command.dry_run(&settings, values);
+        assert_eq!(dry_run.args, vec!["{name}".to_string()]);
+        assert_eq!(dry_run.errors.len(), 1);
+        let values = BTreeMap::from([("name".to_string(), "world".to_string())]);
+        let dry_run = command.dry_run(&settings, values);
+        assert_eq!(dry_run.args, vec!["world".to_string()]);
+        assert!(dry_run.errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_add_and_delete() {
+        let path = std::env::temp_dir().join(format!("{}.sqlite", uuid::Uuid::new_v4().simple()));
+        let database = database::connect(path.to_string_lossy()).await.unwrap();
+        let code = |report: Report<Error>| report.downcast_ref::<ErrorCode>().copied();
+
+        let id = Command::new("greet".into(), "echo".into(), vec!["hi".into()])
+            .with_tags(vec!["old".into()])
+            .add(&database, CommandAddMode::Error)
+            .await
+            .unwrap();
+        pipeline::Pipeline::add(
+            &database,
+            pipeline::NewPipeline {
+                name: "morning".into(),
+                on_failure: Default::default(),
+                steps: vec![pipeline::NewStep {
+                    command_id: Some(id),
+                    command: None,
+                    placeholders: BTreeMap::new(),
+                    stdin: false,
+                    when: None,
+                }],
+            },
+        )
+        .await
+        .unwrap();
+
+        // Replacing keeps the id the pipeline refers to
+        let replaced = Command::new("greet".into(), "echo".into(), vec!["hello".into()])
+            .with_tags(vec!["new".into()])
+            .add(&database, CommandAddMode::Replace)
+            .await
+            .unwrap();
+        assert_eq!(replaced, id);
+        let greet = Command::identifier(&database, Identifier::Id(id))
+            .await
+            .unwrap();
+        assert_eq!(greet.args, ["hello"]);
+        assert_eq!(greet.tags, ["new"]);
+        let ignored = Command::new("greet".into(), "false".into(), vec![])
+            .add(&database, CommandAddMode::Ignore)
+            .await
+            .unwrap();
+        assert_eq!(ignored, id);
+        let added = Command::new("greet".into(), "false".into(), vec![])
+            .add(&database, CommandAddMode::Error)
+            .await;
+        assert_eq!(code(added.unwrap_err()), Some(ErrorCode::CommandExists));
+
+        let deleted = greet.delete(&database).await;
+        assert_eq!(code(deleted.unwrap_err()), Some(ErrorCode::CommandInUse));
+        let clash = Command::new("morning".into(), "true".into(), vec![])
+            .add(&database, CommandAddMode::Replace)
+            .await;
+        assert_eq!(code(clash.unwrap_err()), Some(ErrorCode::PipelineExists));
+
+        database.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+
     // use ::tap::*;
     //
     // #[tokio::test]
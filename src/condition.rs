@@ -0,0 +1,158 @@
+//! Conditions on the output of an earlier pipeline step, deciding whether a
+//! step runs.
+
+use crate::{command::Output, *};
+use regex::Regex;
+use serde_json::Value;
+
+/// A test on the output of a step.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[schema(no_recursion)]
+pub enum Condition {
+    /// The step exited with this code
+    ExitCode(i32),
+    /// The step succeeded, or failed when `false`
+    Success(bool),
+    /// The regex matches the step's stdout
+    Stdout(String),
+    /// Tests values in the step's stdout parsed as json
+    Json(JsonCondition),
+    /// Every condition holds
+    All(Vec<Condition>),
+    /// At least one condition holds
+    Any(Vec<Condition>),
+    /// The condition doesn't hold
+    Not(Box<Condition>),
+}
+
+/// Holds when any value selected by `path` equals `equals`, matches the
+/// regex `matches`, or without either is neither null nor false.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct JsonCondition {
+    /// Path like `$.monitors[0].name`, `[*]` selects every element, see
+    /// [`json_path`]
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub equals: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matches: Option<String>,
+}
+
+/// Runs a step only when `condition` holds for the output of an earlier step.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct When {
+    /// Position of the step whose output is tested, counting from 0. Defaults
+    /// to the last step that ran
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub step: Option<usize>,
+    pub condition: Condition,
+}
+
+impl Condition {
+    /// Checks regexes and paths, so that broken conditions are rejected when
+    /// the pipeline is added rather than when it runs.
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Condition::ExitCode(_) | Condition::Success(_) => Ok(()),
+            Condition::Stdout(pattern) => regex(pattern).map(|_| ()),
+            Condition::Json(json) => {
+                json_path::check(&json.path)?;
+                match &json.matches {
+                    Some(pattern) => regex(pattern).map(|_| ()),
+                    None => Ok(()),
+                }
+            }
+            Condition::All(conditions) | Condition::Any(conditions) => {
+                conditions.iter().try_for_each(Condition::validate)
+            }
+            Condition::Not(condition) => condition.validate(),
+        }
+    }
+
+    pub fn evaluate(&self, output: &Output) -> Result<bool> {
+        Ok(match self {
+            Condition::ExitCode(code) => output.status.code() == Some(*code),
+            Condition::Success(success) => output.status.success() == *success,
+            Condition::Stdout(pattern) => regex(pattern)?.is_match(&output.stdout),
+            Condition::Json(json) => {
+                let Ok(value) = serde_json::from_str::<Value>(&output.stdout) else {
+                    return Ok(false);
+                };
+                let matches = match &json.matches {
+                    Some(pattern) => Some(regex(pattern)?),
+                    None => None,
+                };
+                json_path::select(&value, &json.path)?
+                    .into_iter()
+                    .any(|value| match (&json.equals, &matches) {
+                        (Some(expected), _) => value == expected,
+                        (None, Some(regex)) => match value {
+                            Value::String(string) => regex.is_match(string),
+                            value => regex.is_match(&value.to_string()),
+                        },
+                        (None, None) => !matches!(value, Value::Null | Value::Bool(false)),
+                    })
+            }
+            Condition::All(conditions) => {
+                for condition in conditions {
+                    if !condition.evaluate(output)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            Condition::Any(conditions) => {
+                for condition in conditions {
+                    if condition.evaluate(output)? {
+                        return Ok(true);
+                    }
+                }
+                false
+            }
+            Condition::Not(condition) => !condition.evaluate(output)?,
+        })
+    }
+}
+
+fn regex(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern)
+        .change_context(Error)
+        .attach_printable(format!("Invalid regex in condition: {pattern}"))
+        .attach(ErrorCode::BadRequest)
+        .attach(ErrorDetails(serde_json::json!({ "regex": pattern })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_condition() {
+        let output: Output = serde_json::from_value(serde_json::json!({
+            "stdout": r#"[{ "name": "DP-1", "dpmsStatus": false }]"#,
+            "stderr": "",
+            "status": { "success": true, "code": 0 }
+        }))
+        .unwrap();
+        let active = Condition::Json(JsonCondition {
+            path: "$[*].dpmsStatus".into(),
+            equals: Some(true.into()),
+            matches: None,
+        });
+        assert!(!active.evaluate(&output).unwrap());
+        assert!(Condition::Not(Box::new(active)).evaluate(&output).unwrap());
+        assert!(Condition::ExitCode(0).evaluate(&output).unwrap());
+        assert!(
+            Condition::Stdout("DP-\\d".into())
+                .evaluate(&output)
+                .unwrap()
+        );
+        assert!(
+            !Condition::All(vec![Condition::Success(true), Condition::Success(false)])
+                .evaluate(&output)
+                .unwrap()
+        );
+        assert!(Condition::Stdout("(".into()).validate().is_err());
+    }
+}
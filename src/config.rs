@@ -1,18 +1,133 @@
 use crate::*;
-use core::net::IpAddr;
-use std::path::PathBuf;
+use core::net::{IpAddr, SocketAddr};
+use std::{path::PathBuf, sync::Arc};
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct PartialConfig {
     database: Option<PathBuf>,
     host: Option<IpAddr>,
     port: Option<u16>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    tls_client_ca: Option<PathBuf>,
+    listen: Option<Vec<Listen>>,
+    notifications: Option<Vec<notify::Notification>>,
+    secrets: Option<secret::Sources>,
+    rate_limits: Option<ratelimit::RateLimits>,
+    access: Option<access::Access>,
+    approvals: Option<approval::Approvals>,
 }
 
 pub struct Config {
     pub database: PathBuf,
     pub host: IpAddr,
     pub port: u16,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    /// Endpoints to serve on. When empty, the server listens on `host:port`.
+    pub listen: Vec<Listen>,
+    /// Rules for notifying about completed runs
+    pub notifications: Vec<notify::Notification>,
+    /// Sources of secret placeholder values, by `command.placeholder` or
+    /// `placeholder`
+    pub secrets: secret::Sources,
+    pub rate_limits: ratelimit::RateLimits,
+    /// Networks allowed to connect, and the reverse proxies in front
+    pub access: access::Access,
+    /// How long runs of commands that require approval wait for it
+    pub approvals: approval::Approvals,
+}
+
+/// The parts of the config used while serving requests and running commands,
+/// shared with handlers through an [`axum::Extension`] like the database.
+#[derive(Clone, Default)]
+pub struct Settings {
+    pub notifications: Arc<[notify::Notification]>,
+    pub secrets: Arc<secret::Sources>,
+    /// The limits along with the requests and failed logins counted so far
+    pub limiter: Arc<ratelimit::Limiter>,
+    pub access: Arc<access::Access>,
+    pub approvals: approval::Approvals,
+}
+
+impl Config {
+    pub fn settings(&self) -> Settings {
+        Settings {
+            notifications: self.notifications.clone().into(),
+            secrets: Arc::new(self.secrets.clone()),
+            limiter: Arc::new(ratelimit::Limiter::new(self.rate_limits.clone())),
+            access: Arc::new(self.access.clone()),
+            approvals: self.approvals,
+        }
+    }
+}
+
+/// A single address to accept connections on.
+///
+/// Endpoints use the top level `tls_*` settings unless they provide their own
+/// certificate or explicitly opt out with `tls = false`.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct Listen {
+    pub address: SocketAddr,
+    #[serde(default)]
+    pub tls: Option<bool>,
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
+    #[serde(default)]
+    pub tls_client_ca: Option<PathBuf>,
+    #[serde(default)]
+    pub auth: auth::AuthPolicy,
+}
+
+impl From<SocketAddr> for Listen {
+    fn from(address: SocketAddr) -> Self {
+        Self {
+            address,
+            tls: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            auth: auth::AuthPolicy::default(),
+        }
+    }
+}
+
+/// Parses `ADDRESS[;OPTION...]`, e.g. `[::1]:5599;tls;auth=required`.
+///
+/// Supported options are `tls`, `no-tls`, `auth=<policy>`, `cert=<path>`,
+/// `key=<path>` and `client-ca=<path>`.
+impl core::str::FromStr for Listen {
+    type Err = Report<Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(';').map(str::trim);
+        let address = parts.next().unwrap_or_default();
+        let mut listen = Listen::from(
+            address
+                .parse::<SocketAddr>()
+                .change_context(Error)
+                .attach_printable_lazy(|| format!("Invalid listen address: {address}"))?,
+        );
+        for option in parts.filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                None if option == "tls" => listen.tls = Some(true),
+                None if option == "no-tls" => listen.tls = Some(false),
+                Some(("auth", policy)) => listen.auth = policy.parse()?,
+                Some(("cert", path)) => listen.tls_cert = Some(PathBuf::from(path)),
+                Some(("key", path)) => listen.tls_key = Some(PathBuf::from(path)),
+                Some(("client-ca", path)) => listen.tls_client_ca = Some(PathBuf::from(path)),
+                _ => {
+                    return Err(
+                        Error::new().attach_printable(format!("Unknown listen option: {option}"))
+                    );
+                }
+            }
+        }
+        Ok(listen)
+    }
 }
 
 impl TryFrom<PartialConfig> for Config {
@@ -28,19 +143,108 @@ impl TryFrom<PartialConfig> for Config {
         let port = value.port.ok_or_else(|| {
             Error::new().attach_printable("Port is required in the configuration")
         })?;
-        Ok(Self {
+        if value.tls_cert.is_some() != value.tls_key.is_some() {
+            return Err(Error::new()
+                .attach_printable("Both tls_cert and tls_key are required to enable TLS"));
+        }
+        let config = Self {
             database,
             host,
             port,
-        })
+            tls_cert: value.tls_cert,
+            tls_key: value.tls_key,
+            tls_client_ca: value.tls_client_ca,
+            listen: value.listen.unwrap_or_default(),
+            notifications: value.notifications.unwrap_or_default(),
+            secrets: value.secrets.unwrap_or_default(),
+            rate_limits: value.rate_limits.unwrap_or_default(),
+            access: value.access.unwrap_or_default(),
+            approvals: value.approvals.unwrap_or_default(),
+        };
+        config.check_client_ca()?;
+        Ok(config)
     }
 }
 
 impl Config {
+    /// Fails when a client CA is set for endpoints that don't serve TLS, as
+    /// clients would then connect without certificates.
+    fn check_client_ca(&self) -> Result<()> {
+        let endpoints = self.endpoints()?;
+        for (listen, endpoint) in self.listen.iter().zip(&endpoints) {
+            if listen.tls_client_ca.is_some() && endpoint.tls.is_none() {
+                return Err(Error::new().attach_printable(format!(
+                    "Listen address {} has a client CA but no TLS certificate",
+                    listen.address
+                )));
+            }
+        }
+        if self.tls_client_ca.is_some() && endpoints.iter().all(|endpoint| endpoint.tls.is_none()) {
+            return Err(Error::new().attach_printable(
+                "tls_client_ca requires a TLS certificate, set globally or on a listen address",
+            ));
+        }
+        Ok(())
+    }
+
     pub fn try_new(cli: &cli::Cli) -> Result<Self> {
         let partial_config = PartialConfig::try_from_cli_or_env_or_file(cli)?;
         Self::try_from(partial_config).change_context(Error)
     }
+
+    pub fn tls(&self) -> Option<tls::TlsConfig> {
+        Some(tls::TlsConfig {
+            cert: self.tls_cert.clone()?,
+            key: self.tls_key.clone()?,
+            client_ca: self.tls_client_ca.clone(),
+        })
+    }
+
+    pub fn endpoints(&self) -> Result<Vec<app::Endpoint>> {
+        if self.listen.is_empty() {
+            return Ok(vec![app::Endpoint {
+                address: SocketAddr::new(self.host, self.port),
+                tls: self.tls(),
+                auth: auth::AuthPolicy::default(),
+            }]);
+        }
+        self.listen
+            .iter()
+            .map(|listen| {
+                let tls = match (&listen.tls_cert, &listen.tls_key) {
+                    (Some(cert), Some(key)) => Some(tls::TlsConfig {
+                        cert: cert.clone(),
+                        key: key.clone(),
+                        client_ca: listen
+                            .tls_client_ca
+                            .clone()
+                            .or_else(|| self.tls_client_ca.clone()),
+                    }),
+                    (None, None) => match listen.tls {
+                        Some(false) => None,
+                        Some(true) => Some(self.tls().ok_or_else(|| {
+                            Error::new().attach_printable(format!(
+                                "Listen address {} requires TLS but no certificate is configured",
+                                listen.address
+                            ))
+                        })?),
+                        None => self.tls(),
+                    },
+                    _ => {
+                        return Err(Error::new().attach_printable(format!(
+                            "Listen address {} needs both a certificate and a key",
+                            listen.address
+                        )));
+                    }
+                };
+                Ok(app::Endpoint {
+                    address: listen.address,
+                    tls,
+                    auth: listen.auth,
+                })
+            })
+            .collect()
+    }
 }
 
 impl Default for PartialConfig {
@@ -49,6 +253,15 @@ impl Default for PartialConfig {
             database: None,
             host: Some(IpAddr::V4(core::net::Ipv4Addr::LOCALHOST)),
             port: Some(5599),
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            listen: None,
+            notifications: None,
+            secrets: None,
+            rate_limits: None,
+            access: None,
+            approvals: None,
         }
     }
 }
@@ -67,10 +280,35 @@ impl PartialConfig {
             .transpose()
             .change_context(Error)
             .attach_printable("Failed to parse port")?;
+        let tls_cert = std::env::var("CMD_RUNNER_TLS_CERT").ok().map(PathBuf::from);
+        let tls_key = std::env::var("CMD_RUNNER_TLS_KEY").ok().map(PathBuf::from);
+        let tls_client_ca = std::env::var("CMD_RUNNER_TLS_CLIENT_CA")
+            .ok()
+            .map(PathBuf::from);
+        let listen = std::env::var("CMD_RUNNER_LISTEN")
+            .ok()
+            .map(|listen| {
+                listen
+                    .split(',')
+                    .filter(|listen| !listen.trim().is_empty())
+                    .map(str::parse::<Listen>)
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()
+            .attach_printable("Failed to parse listen addresses")?;
         Ok(Self {
             database,
             host,
             port,
+            tls_cert,
+            tls_key,
+            tls_client_ca,
+            listen,
+            notifications: None,
+            secrets: None,
+            rate_limits: None,
+            access: None,
+            approvals: None,
         })
     }
 
@@ -107,6 +345,17 @@ impl PartialConfig {
             database: cli.database.clone(),
             host: run.and_then(|r| r.host),
             port: run.and_then(|r| r.port),
+            tls_cert: run.and_then(|r| r.tls_cert.clone()),
+            tls_key: run.and_then(|r| r.tls_key.clone()),
+            tls_client_ca: run.and_then(|r| r.tls_client_ca.clone()),
+            listen: run
+                .map(|r| r.listen.clone())
+                .filter(|listen| !listen.is_empty()),
+            notifications: None,
+            secrets: None,
+            rate_limits: None,
+            access: None,
+            approvals: None,
         })
     }
 
@@ -115,6 +364,15 @@ impl PartialConfig {
             database: self.database.or(other.database),
             host: self.host.or(other.host),
             port: self.port.or(other.port),
+            tls_cert: self.tls_cert.or(other.tls_cert),
+            tls_key: self.tls_key.or(other.tls_key),
+            tls_client_ca: self.tls_client_ca.or(other.tls_client_ca),
+            listen: self.listen.or(other.listen),
+            notifications: self.notifications.or(other.notifications),
+            secrets: self.secrets.or(other.secrets),
+            rate_limits: self.rate_limits.or(other.rate_limits),
+            access: self.access.or(other.access),
+            approvals: self.approvals.or(other.approvals),
         }
     }
 
@@ -131,3 +389,54 @@ impl PartialConfig {
             .or(Self::default()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_from_str() {
+        let listen: Listen = "[::1]:5599;tls;auth=required".parse().unwrap();
+        assert_eq!(listen.address, "[::1]:5599".parse().unwrap());
+        assert_eq!(listen.tls, Some(true));
+        assert_eq!(listen.auth, auth::AuthPolicy::Required);
+
+        let listen: Listen = "127.0.0.1:5599".parse().unwrap();
+        assert_eq!(listen.tls, None);
+        assert_eq!(listen.auth, auth::AuthPolicy::Open);
+
+        assert!("127.0.0.1:5599;bogus".parse::<Listen>().is_err());
+        assert!("localhost".parse::<Listen>().is_err());
+    }
+
+    #[test]
+    fn test_client_ca() {
+        let config = |listen: &[&str], client_ca: Option<&str>| {
+            Config::try_from(PartialConfig {
+                database: Some("db.sqlite".into()),
+                host: Some("127.0.0.1".parse().unwrap()),
+                port: Some(5599),
+                tls_client_ca: client_ca.map(PathBuf::from),
+                listen: Some(
+                    listen
+                        .iter()
+                        .map(|listen| listen.parse().unwrap())
+                        .collect(),
+                ),
+                ..PartialConfig::default()
+            })
+        };
+        // Certificates of listen addresses count, not only the global one
+        assert!(config(&["127.0.0.1:5599;cert=c.pem;key=k.pem"], Some("ca.pem")).is_ok());
+        assert!(
+            config(
+                &["127.0.0.1:5599;cert=c.pem;key=k.pem;client-ca=ca.pem"],
+                None
+            )
+            .is_ok()
+        );
+        assert!(config(&["127.0.0.1:5599;client-ca=ca.pem"], None).is_err());
+        assert!(config(&["127.0.0.1:5599"], Some("ca.pem")).is_err());
+        assert!(config(&[], Some("ca.pem")).is_err());
+    }
+}
@@ -0,0 +1,491 @@
+//! Server rendered pages to browse and run commands from a browser.
+
+use crate::{
+    command::{Command, History, Identifier},
+    routes::{Executed, Form, Query},
+    *,
+};
+use axum::{
+    Extension,
+    extract::{ConnectInfo, FromRequestParts},
+    response::{IntoResponse, Redirect, Response},
+};
+use maud::{DOCTYPE, Markup, PreEscaped, html};
+use std::collections::BTreeMap;
+
+pub fn routes() -> axum::Router {
+    axum::Router::new()
+        .route("/", axum::routing::get(index))
+        .route("/login", axum::routing::get(login_page).post(login))
+        .route("/logout", axum::routing::post(logout))
+        .route("/command", axum::routing::get(command).post(run_command))
+        .route("/history", axum::routing::get(history))
+}
+
+type Result<T> = std::result::Result<T, HtmlError>;
+
+const STYLE: &str = r#"
+body { font-family: system-ui, sans-serif; margin: 0; color: #222; background: #fafafa; }
+header { display: flex; justify-content: space-between; align-items: center; padding: .5rem 1rem; background: #263238; color: #fff; }
+header a { color: #fff; margin-right: 1rem; text-decoration: none; }
+header form { display: inline; margin-left: 1rem; }
+main { max-width: 60rem; margin: 0 auto; padding: 1rem; }
+table { border-collapse: collapse; width: 100%; }
+th, td { text-align: left; padding: .4rem; border-bottom: 1px solid #ddd; vertical-align: top; }
+pre { background: #eceff1; padding: .5rem; overflow-x: auto; white-space: pre-wrap; }
+label { display: block; margin: .5rem 0 .2rem; font-weight: bold; }
+input[type=text], input[type=password] { width: 100%; max-width: 30rem; padding: .3rem; }
+small, .muted { color: #666; }
+button { margin-top: .8rem; padding: .3rem 1rem; }
+.tag { background: #e0e0e0; border-radius: .8rem; padding: 0 .5rem; margin-right: .3rem; color: inherit; text-decoration: none; }
+.success { color: #2e7d32; }
+.failure { color: #c62828; }
+.error { color: #c62828; font-weight: bold; }
+"#;
+
+/// Renders errors as a page instead of the json envelope used by the api.
+pub struct HtmlError(ErrorResponse);
+
+impl From<Report<Error>> for HtmlError {
+    fn from(report: Report<Error>) -> Self {
+        HtmlError(ErrorResponse::from(report))
+    }
+}
+
+impl IntoResponse for HtmlError {
+    fn into_response(self) -> Response {
+        let body = self.0.body();
+        // Keeps the logging and status of the api error response
+        let status = self.0.into_response().status();
+        let content = html! {
+            p.error { (body.message) }
+            @if let Some(details) = &body.details {
+                pre { (details) }
+            }
+            @if let Some(request_id) = &body.request_id {
+                p.muted { "Request id: " (request_id) }
+            }
+            p { a href="/ui" { "Back to the commands" } }
+        };
+        (status, page("Error", None, content)).into_response()
+    }
+}
+
+/// A caller that is signed in one way or another. Anonymous callers are sent
+/// to the login page.
+pub struct SignedIn(auth::Caller);
+
+impl<S: Send + Sync> FromRequestParts<S> for SignedIn {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        match auth::Caller::from_request_parts(parts, state).await {
+            Ok(auth::Caller::Anonymous) => {
+                // The nested router only sees the part of the path after `/ui`
+                let next = parts
+                    .extensions
+                    .get::<axum::extract::OriginalUri>()
+                    .and_then(|uri| uri.path_and_query())
+                    .map_or("/ui", |path| path.as_str());
+                Err(Redirect::to(&url("/ui/login", [("next", next)])).into_response())
+            }
+            Ok(caller) => Ok(SignedIn(caller)),
+            Err(error) => Err(HtmlError(error).into_response()),
+        }
+    }
+}
+
+fn url<'a>(path: &str, query: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(query)
+        .finish();
+    if query.is_empty() {
+        path.to_owned()
+    } else {
+        format!("{path}?{query}")
+    }
+}
+
+fn command_url(name: &str) -> String {
+    url("/ui/command", [("name", name)])
+}
+
+/// Only follows redirects within the dashboard.
+fn local_redirect(next: Option<&str>) -> &str {
+    next.filter(|next| next.starts_with("/ui") && !next.contains('\\'))
+        .unwrap_or("/ui")
+}
+
+fn page(title: &str, caller: Option<&auth::Caller>, content: Markup) -> Markup {
+    html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                title { (title) " · Command runner" }
+                style { (PreEscaped(STYLE)) }
+            }
+            body {
+                header {
+                    nav {
+                        a href="/ui" { "Commands" }
+                        a href="/ui/history" { "History" }
+                    }
+                    @if let Some(auth::Caller::User { user, method }) = caller {
+                        div {
+                            (user.username)
+                            @if *method == auth::AuthMethod::Session {
+                                form method="post" action="/ui/logout" {
+                                    button type="submit" { "Log out" }
+                                }
+                            }
+                        }
+                    }
+                }
+                main {
+                    h1 { (title) }
+                    (content)
+                }
+            }
+        }
+    }
+}
+
+fn tags(tags: &[String]) -> Markup {
+    html! {
+        @for tag in tags {
+            a.tag href=(url("/ui", [("tag", tag.as_str())])) { (tag) }
+        }
+    }
+}
+
+fn status(success: bool, code: Option<i32>) -> Markup {
+    html! {
+        span class=(if success { "success" } else { "failure" }) {
+            @match code {
+                Some(code) => { "exit code " (code) }
+                None => "killed by a signal",
+            }
+        }
+    }
+}
+
+fn streams(stdout: &str, stderr: &str) -> Markup {
+    html! {
+        @if !stdout.is_empty() {
+            h3 { "stdout" }
+            pre { (stdout) }
+        }
+        @if !stderr.is_empty() {
+            h3 { "stderr" }
+            pre { (stderr) }
+        }
+        @if stdout.is_empty() && stderr.is_empty() {
+            p.muted { "No output" }
+        }
+    }
+}
+
+fn history_table(history: &[History]) -> Markup {
+    html! {
+        @if history.is_empty() {
+            p.muted { "No runs yet." }
+        } @else {
+            table {
+                thead { tr { th { "When" } th { "Command" } th { "Result" } } }
+                tbody {
+                    @for entry in history {
+                        tr {
+                            td { (entry.created_at) " UTC" }
+                            td { a href=(command_url(&entry.name)) { (entry.name) } }
+                            td {
+                                details {
+                                    summary {
+                                        (status(entry.success, entry.exit_code))
+                                        @if entry.attempt > 1 {
+                                            " (attempt " (entry.attempt) ")"
+                                        }
+                                    }
+                                    (streams(&entry.stdout, &entry.stderr))
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct IndexArgs {
+    tag: Option<String>,
+}
+
+async fn index(
+    SignedIn(caller): SignedIn,
+    Query(args): Query<IndexArgs>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+) -> Result<Markup> {
+    let commands = Command::filter(&db, None, args.tag.clone()).await?;
+    let content = html! {
+        @if let Some(tag) = &args.tag {
+            p { "Tagged " strong { (tag) } " · " a href="/ui" { "show all" } }
+        }
+        @if commands.is_empty() {
+            p.muted { "No commands registered yet." }
+        } @else {
+            table {
+                thead { tr { th { "Name" } th { "Description" } th { "Tags" } } }
+                tbody {
+                    @for command in &commands {
+                        tr {
+                            td { a href=(command_url(&command.name)) { (command.name) } }
+                            td { (command.metadata.description.as_deref().unwrap_or_default()) }
+                            td { (tags(&command.tags)) }
+                        }
+                    }
+                }
+            }
+        }
+    };
+    Ok(page("Commands", Some(&caller), content))
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct LoginArgs {
+    next: Option<String>,
+}
+
+fn login_form(next: &str, error: Option<&str>) -> Markup {
+    let content = html! {
+        @if let Some(error) = error {
+            p.error { (error) }
+        }
+        form method="post" action="/ui/login" {
+            input type="hidden" name="next" value=(next);
+            label for="username" { "Username" }
+            input #username type="text" name="username" autocomplete="username" required autofocus;
+            label for="password" { "Password" }
+            input #password type="password" name="password" autocomplete="current-password" required;
+            button type="submit" { "Log in" }
+        }
+    };
+    page("Log in", None, content)
+}
+
+async fn login_page(Query(args): Query<LoginArgs>) -> Markup {
+    login_form(local_redirect(args.next.as_deref()), None)
+}
+
+async fn login(
+    Extension(settings): Extension<config::Settings>,
+    mut auth_session: users::AuthSession,
+    Form(credentials): Form<users::Credentials>,
+) -> Result<Response> {
+    let next = local_redirect(credentials.next.as_deref()).to_owned();
+    if let Err(e) = settings.limiter.check_login(&credentials.username) {
+        let retry_after = e.downcast_ref::<RetryAfter>().map_or(0, |retry| retry.0);
+        return Ok((
+            http::StatusCode::TOO_MANY_REQUESTS,
+            [(http::header::RETRY_AFTER, retry_after.to_string())],
+            login_form(&next, Some(ErrorCode::TooManyRequests.message())),
+        )
+            .into_response());
+    }
+    let Some(user) = auth_session
+        .authenticate(credentials)
+        .await
+        .change_context(Error)
+        .attach_printable("Failed to authenticate user")?
+    else {
+        return Ok((
+            http::StatusCode::UNAUTHORIZED,
+            login_form(&next, Some(ErrorCode::InvalidCredentials.message())),
+        )
+            .into_response());
+    };
+    auth_session
+        .login(&user)
+        .await
+        .change_context(Error)
+        .attach_printable("Failed to log in user")?;
+    Ok(Redirect::to(&next).into_response())
+}
+
+async fn logout(mut auth_session: users::AuthSession) -> Result<Redirect> {
+    auth_session
+        .logout()
+        .await
+        .change_context(Error)
+        .attach_printable("Failed to log out user")?;
+    Ok(Redirect::to("/ui/login"))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CommandArgs {
+    name: String,
+}
+
+async fn render_command(
+    db: &sqlx::SqlitePool,
+    caller: &auth::Caller,
+    command: &Command,
+    values: &BTreeMap<String, String>,
+    executed: Option<&Executed>,
+) -> Result<Markup> {
+    let history = History::list(db, Some(command), Some(caller), 10).await?;
+    let metadata = &command.metadata;
+    let content = html! {
+        @if let Some(description) = &metadata.description {
+            p { (description) }
+        }
+        table {
+            tr { th { "Runs" } td { code { (command.command) @for arg in &command.args { " " (arg) } } } }
+            @if let Some(owner) = &metadata.owner {
+                tr { th { "Owner" } td { (owner) } }
+            }
+            @if !command.tags.is_empty() {
+                tr { th { "Tags" } td { (tags(&command.tags)) } }
+            }
+            @if metadata.requires_approval {
+                tr { th { "Approval" } td { "Runs wait for a second user to approve them" } }
+            }
+            @if !metadata.transforms.is_empty() {
+                tr { th { "Transforms" } td { @for (i, transform) in metadata.transforms.iter().enumerate() {
+                    @if i > 0 { " | " }
+                    code { (transform) }
+                } } }
+            }
+            @if let Some(seconds) = metadata.cache_seconds {
+                tr { th { "Cache" } td { "Output is reused for " (seconds) " seconds" } }
+            }
+            @if let Some(retry) = &metadata.retry {
+                tr { th { "Retries" } td { "Up to " (retry.max_attempts) " attempts" } }
+            }
+        }
+        form method="post" action=(command_url(&command.name)) {
+            @for placeholder in command.placeholders() {
+                label { (placeholder) }
+                @if command.is_secret(placeholder) {
+                    // Secrets may come from the server's config instead
+                    input type="password" name=(placeholder);
+                } @else {
+                    input type="text" name=(placeholder)
+                        value=(values.get(placeholder).map_or("", String::as_str)) required;
+                }
+                @if let Some(help) = command.placeholder_help(placeholder) {
+                    br;
+                    small { (help) }
+                }
+            }
+            button type="submit" { "Run" }
+        }
+        @if let Some(Executed::Pending(approval)) = executed {
+            p { "Waiting for approval, request " code { (approval.id) } " expires at " (approval.expires_at) }
+        }
+        @if let Some(Executed::Output(output) | Executed::Cached(output)) = executed {
+            h2 { "Output " (status(output.status.success(), output.status.code())) }
+            @if matches!(executed, Some(Executed::Cached(_))) {
+                p.muted { "Cached output of an earlier run" }
+            }
+            (streams(&output.stdout, &output.stderr))
+        }
+        h2 { "Recent runs" }
+        (history_table(&history))
+        p { a href=(url("/ui/history", [("name", command.name.as_str())])) { "All runs" } }
+    };
+    Ok(page(&command.name, Some(caller), content))
+}
+
+async fn command(
+    SignedIn(caller): SignedIn,
+    Query(args): Query<CommandArgs>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+) -> Result<Markup> {
+    let command = Command::identifier(&db, Identifier::Name(args.name)).await?;
+    render_command(&db, &caller, &command, &BTreeMap::new(), None).await
+}
+
+async fn run_command(
+    SignedIn(caller): SignedIn,
+    Query(args): Query<CommandArgs>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    ConnectInfo(peer): ConnectInfo<app::Peer>,
+    Form(values): Form<BTreeMap<String, String>>,
+) -> Result<Markup> {
+    let command = Command::identifier(&db, Identifier::Name(args.name)).await?;
+    let executed = routes::execute(
+        &db,
+        &settings,
+        &command,
+        &caller,
+        &peer,
+        values.clone(),
+        true,
+    )
+    .await?;
+    render_command(&db, &caller, &command, &values, Some(&executed)).await
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct HistoryArgs {
+    name: Option<String>,
+    limit: u32,
+}
+
+impl Default for HistoryArgs {
+    fn default() -> Self {
+        HistoryArgs {
+            name: None,
+            limit: 50,
+        }
+    }
+}
+
+async fn history(
+    SignedIn(caller): SignedIn,
+    Query(args): Query<HistoryArgs>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+) -> Result<Markup> {
+    let command = match args.name {
+        Some(name) => {
+            let command = Command::identifier(&db, Identifier::Name(name)).await?;
+            auth::authorize(&db, &command, &caller).await?;
+            Some(command)
+        }
+        None => None,
+    };
+    let title = match &command {
+        Some(command) => format!("History of {}", command.name),
+        None => "History".to_owned(),
+    };
+    let history = History::list(&db, command.as_ref(), Some(&caller), args.limit).await?;
+    Ok(page(&title, Some(&caller), history_table(&history)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_redirect() {
+        assert_eq!(
+            local_redirect(Some("/ui/command?name=a")),
+            "/ui/command?name=a"
+        );
+        assert_eq!(local_redirect(Some("https://example.com")), "/ui");
+        assert_eq!(local_redirect(Some("//example.com")), "/ui");
+        assert_eq!(local_redirect(Some("/ui\\@example.com")), "/ui");
+        assert_eq!(local_redirect(None), "/ui");
+        assert_eq!(command_url("a b&c"), "/ui/command?name=a+b%26c");
+    }
+}
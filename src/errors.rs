@@ -11,10 +11,216 @@ impl Error {
 
 pub type Result<T, E = error_stack::Report<Error>> = core::result::Result<T, E>;
 
-#[derive(Debug, serde::Serialize)]
-#[serde(transparent)]
+/// Stable, machine readable error codes returned to API clients.
+///
+/// Attach one of these to a report to pick the code and status of the
+/// response. The codes are part of the API, so existing ones must not be
+/// renamed.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Internal,
+    Database,
+    BadRequest,
+    RouteNotFound,
+    MethodNotAllowed,
+    AuthenticationRequired,
+    InvalidCredentials,
+    Forbidden,
+    CommandNotFound,
+    CommandExists,
+    CommandInUse,
+    PipelineNotFound,
+    PipelineExists,
+    ScheduleNotFound,
+    WebhookNotFound,
+    ApprovalNotFound,
+    ApprovalNotPending,
+    IdempotencyKeyInUse,
+    IdempotencyKeyReused,
+    InvalidSignature,
+    TooManyRequests,
+    PlaceholderMissing,
+    PlaceholderEmpty,
+    SpawnFailed,
+    InvalidJsonOutput,
+    TransformFailed,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> http::StatusCode {
+        use http::StatusCode;
+        match self {
+            ErrorCode::Internal | ErrorCode::Database | ErrorCode::SpawnFailed => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            ErrorCode::BadRequest | ErrorCode::PlaceholderMissing | ErrorCode::PlaceholderEmpty => {
+                StatusCode::BAD_REQUEST
+            }
+            ErrorCode::RouteNotFound
+            | ErrorCode::CommandNotFound
+            | ErrorCode::PipelineNotFound
+            | ErrorCode::ScheduleNotFound
+            | ErrorCode::WebhookNotFound
+            | ErrorCode::ApprovalNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::AuthenticationRequired
+            | ErrorCode::InvalidCredentials
+            | ErrorCode::InvalidSignature => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::CommandExists
+            | ErrorCode::CommandInUse
+            | ErrorCode::PipelineExists
+            | ErrorCode::ApprovalNotPending
+            | ErrorCode::IdempotencyKeyInUse => StatusCode::CONFLICT,
+            ErrorCode::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::InvalidJsonOutput | ErrorCode::TransformFailed => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            ErrorCode::Internal => "An internal error occurred",
+            ErrorCode::Database => "A database error occurred",
+            ErrorCode::BadRequest => "The request is malformed",
+            ErrorCode::RouteNotFound => "The requested route doesn't exist",
+            ErrorCode::MethodNotAllowed => "The requested route doesn't support this method",
+            ErrorCode::AuthenticationRequired => "Authentication is required",
+            ErrorCode::InvalidCredentials => "Invalid username or password",
+            ErrorCode::Forbidden => "The caller is not allowed to perform this action",
+            ErrorCode::CommandNotFound => "No matching command was found",
+            ErrorCode::CommandExists => "A command with the same name already exists",
+            ErrorCode::CommandInUse => "The command is used by a pipeline",
+            ErrorCode::PipelineNotFound => "No matching pipeline was found",
+            ErrorCode::PipelineExists => "A command or pipeline with the same name already exists",
+            ErrorCode::ScheduleNotFound => "No matching schedule was found",
+            ErrorCode::WebhookNotFound => "No matching webhook was found",
+            ErrorCode::ApprovalNotFound => "No matching approval request was found",
+            ErrorCode::ApprovalNotPending => "The approval request was already decided or expired",
+            ErrorCode::IdempotencyKeyInUse => "A request with the same idempotency key is running",
+            ErrorCode::IdempotencyKeyReused => {
+                "The idempotency key was already used for another request"
+            }
+            ErrorCode::InvalidSignature => "The request signature is missing or invalid",
+            ErrorCode::TooManyRequests => "Too many requests, retry later",
+            ErrorCode::PlaceholderMissing => "A value for a placeholder is missing",
+            ErrorCode::PlaceholderEmpty => "A placeholder was replaced with an empty argument",
+            ErrorCode::SpawnFailed => "The command could not be started",
+            ErrorCode::InvalidJsonOutput => "The command's output is not valid json",
+            ErrorCode::TransformFailed => "The command's output couldn't be transformed",
+        }
+    }
+
+    fn from_status(status: http::StatusCode) -> Self {
+        use http::StatusCode;
+        match status {
+            StatusCode::NOT_FOUND => ErrorCode::RouteNotFound,
+            StatusCode::METHOD_NOT_ALLOWED => ErrorCode::MethodNotAllowed,
+            StatusCode::UNAUTHORIZED => ErrorCode::AuthenticationRequired,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            StatusCode::TOO_MANY_REQUESTS => ErrorCode::TooManyRequests,
+            status if status.is_client_error() => ErrorCode::BadRequest,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+/// Structured context for an error that is safe to show to API clients,
+/// e.g. the name of the missing placeholder.
+#[derive(Debug, Clone)]
+pub struct ErrorDetails(pub serde_json::Value);
+
+/// Seconds after which a rejected request may be retried, sent as the
+/// `Retry-After` header.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryAfter(pub u64);
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Tags every request with an id, taken from a well formed `x-request-id`
+/// header or generated, so that error responses can be matched up with the
+/// server logs.
+pub async fn request_id(
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= 64
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        })
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(request)).await;
+    if let Ok(value) = http::HeaderValue::from_str(&id) {
+        response.headers_mut().insert("x-request-id", value);
+    }
+    response
+}
+
+/// The json body of every error response.
+#[derive(Debug, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Wraps a report so it can be returned from handlers. Only the code, message
+/// and details are sent to the client, the full report is logged.
+#[derive(Debug)]
 pub struct ErrorResponse(Report<Error>);
 
+impl ErrorResponse {
+    pub fn code(&self) -> ErrorCode {
+        self.0
+            .downcast_ref::<ErrorCode>()
+            .copied()
+            .or_else(|| {
+                self.0
+                    .downcast_ref::<http::StatusCode>()
+                    .copied()
+                    .map(ErrorCode::from_status)
+            })
+            .unwrap_or(ErrorCode::Internal)
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        let code = self.code();
+        ErrorBody {
+            code,
+            message: code.message().to_owned(),
+            details: self
+                .0
+                .downcast_ref::<ErrorDetails>()
+                .map(|details| details.0.clone()),
+            request_id: REQUEST_ID.try_with(Clone::clone).ok(),
+        }
+    }
+}
+
+impl utoipa::PartialSchema for ErrorResponse {
+    fn schema() -> utoipa::openapi::RefOr<utoipa::openapi::schema::Schema> {
+        <ErrorBody as utoipa::PartialSchema>::schema()
+    }
+}
+
+impl utoipa::ToSchema for ErrorResponse {}
+
 impl From<Report<Error>> for ErrorResponse {
     fn from(report: Report<Error>) -> Self {
         ErrorResponse(report)
@@ -27,16 +233,79 @@ impl From<Error> for ErrorResponse {
     }
 }
 
+impl From<axum::extract::rejection::QueryRejection> for ErrorResponse {
+    fn from(rejection: axum::extract::rejection::QueryRejection) -> Self {
+        rejection_response(rejection.body_text())
+    }
+}
+
+impl From<axum::extract::rejection::JsonRejection> for ErrorResponse {
+    fn from(rejection: axum::extract::rejection::JsonRejection) -> Self {
+        rejection_response(rejection.body_text())
+    }
+}
+
+impl From<axum::extract::rejection::FormRejection> for ErrorResponse {
+    fn from(rejection: axum::extract::rejection::FormRejection) -> Self {
+        rejection_response(rejection.body_text())
+    }
+}
+
+fn rejection_response(reason: String) -> ErrorResponse {
+    ErrorResponse(
+        Error::new()
+            .attach_printable(reason.clone())
+            .attach(ErrorCode::BadRequest)
+            .attach(ErrorDetails(serde_json::json!({ "reason": reason }))),
+    )
+}
+
 impl IntoResponse for ErrorResponse {
     fn into_response(self) -> axum::response::Response {
-        let status = self
-            .0
-            .downcast_ref::<http::StatusCode>()
-            .cloned()
-            .unwrap_or(http::StatusCode::INTERNAL_SERVER_ERROR);
-        // let status = axum::http::StatusCode::INTERNAL_SERVER_ERROR;
-        let response_json =
-            serde_json::to_string_pretty(&self).expect("Failed to serialize error response");
-        (status, response_json).into_response()
+        let body = self.body();
+        let status = body.code.status();
+        let request_id = body.request_id.as_deref().unwrap_or_default();
+        if status.is_server_error() {
+            tracing::error!(request_id, code = ?body.code, "{:?}", self.0);
+        } else {
+            tracing::debug!(request_id, code = ?body.code, "{:?}", self.0);
+        }
+        let mut response = (status, axum::Json(body)).into_response();
+        if let Some(RetryAfter(seconds)) = self.0.downcast_ref::<RetryAfter>() {
+            response
+                .headers_mut()
+                .insert(http::header::RETRY_AFTER, http::HeaderValue::from(*seconds));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_body() {
+        let response = ErrorResponse::from(
+            Error::new()
+                .attach_printable("/secret/path/to/database.sqlite")
+                .attach(ErrorCode::Database)
+                .change_context(Error)
+                .attach(ErrorCode::PlaceholderMissing)
+                .attach(ErrorDetails(serde_json::json!({ "placeholder": "{x}" }))),
+        );
+        let body = serde_json::to_value(response.body()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "code": "placeholder_missing",
+                "message": "A value for a placeholder is missing",
+                "details": { "placeholder": "{x}" },
+            })
+        );
+        assert_eq!(
+            ErrorResponse::from(Error::new().attach(http::StatusCode::FORBIDDEN)).code(),
+            ErrorCode::Forbidden
+        );
     }
 }
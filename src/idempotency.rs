@@ -0,0 +1,280 @@
+//! `Idempotency-Key` handling for runs: the response to the first request with
+//! a key is stored, and later requests of the same caller with that key get it
+//! back instead of running the command again.
+
+use crate::*;
+use axum::response::IntoResponse;
+use sqlx::SqlitePool;
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Set on responses replayed for a key seen before.
+const REPLAYED: &str = "idempotent-replayed";
+/// How long keys are remembered.
+const EXPIRE: &str = "-1 day";
+const MAX_KEY_LENGTH: usize = 255;
+/// Same as the limit of axum's `Json` extractor.
+const MAX_BODY: usize = 2 * 1024 * 1024;
+
+/// Secret values in a response, masked before it is stored for a key.
+#[derive(Debug, Clone, Default)]
+pub struct Secrets(pub Vec<String>);
+
+/// A response stored for a key.
+#[derive(sqlx::FromRow)]
+struct Stored {
+    fingerprint: String,
+    status: Option<u16>,
+    content_type: Option<String>,
+    body: Option<Vec<u8>>,
+}
+
+fn bad_request(reason: &str) -> Report<Error> {
+    Error::new()
+        .attach_printable(reason.to_string())
+        .attach(ErrorCode::BadRequest)
+        .attach(ErrorDetails(serde_json::json!({ "reason": reason })))
+}
+
+/// Hash of what a request asks for, so that a key isn't reused for another
+/// request.
+fn fingerprint(parts: &http::request::Parts, body: &[u8]) -> String {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(parts.method.as_str());
+    hasher.update([0]);
+    hasher.update(parts.uri.to_string());
+    hasher.update([0]);
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+/// Claims `key` for the request, returning the stored response when it was
+/// claimed before.
+async fn claim(
+    database: &SqlitePool,
+    key: &str,
+    caller: &str,
+    fingerprint: &str,
+) -> Result<Option<axum::response::Response>> {
+    sqlx::query("DELETE FROM idempotency_keys WHERE created_at <= datetime('now', ?)")
+        .bind(EXPIRE)
+        .execute(database)
+        .await
+        .change_context(Error)
+        .attach_printable("Failed to expire idempotency keys")
+        .attach(ErrorCode::Database)?;
+    let claimed = sqlx::query(
+        "INSERT INTO idempotency_keys (key, caller, fingerprint) VALUES (?, ?, ?)
+        ON CONFLICT (key, caller) DO NOTHING",
+    )
+    .bind(key)
+    .bind(caller)
+    .bind(fingerprint)
+    .execute(database)
+    .await
+    .change_context(Error)
+    .attach_printable(format!("Failed to claim idempotency key: {key}"))
+    .attach(ErrorCode::Database)?;
+    if claimed.rows_affected() > 0 {
+        return Ok(None);
+    }
+    let stored: Stored = sqlx::query_as(
+        "SELECT fingerprint, status, content_type, body FROM idempotency_keys
+        WHERE key = ? AND caller = ?",
+    )
+    .bind(key)
+    .bind(caller)
+    .fetch_one(database)
+    .await
+    .change_context(Error)
+    .attach_printable(format!("Failed to query idempotency key: {key}"))
+    .attach(ErrorCode::Database)?;
+    let details = ErrorDetails(serde_json::json!({ "key": key }));
+    if stored.fingerprint != fingerprint {
+        return Err(Error::new()
+            .attach_printable(format!(
+                "Idempotency key {key} was already used for another request"
+            ))
+            .attach(ErrorCode::IdempotencyKeyReused)
+            .attach(details));
+    }
+    let (Some(status), Some(body)) = (stored.status, stored.body) else {
+        return Err(Error::new()
+            .attach_printable(format!(
+                "The request with idempotency key {key} is still running"
+            ))
+            .attach(ErrorCode::IdempotencyKeyInUse)
+            .attach(details));
+    };
+    let mut response = (
+        http::StatusCode::from_u16(status).unwrap_or(http::StatusCode::OK),
+        [(REPLAYED, "true")],
+        body,
+    )
+        .into_response();
+    if let Some(content_type) = stored
+        .content_type
+        .and_then(|content_type| content_type.parse().ok())
+    {
+        response
+            .headers_mut()
+            .insert(http::header::CONTENT_TYPE, content_type);
+    }
+    Ok(Some(response))
+}
+
+/// Stores the response to the request that claimed `key`.
+async fn store(
+    database: &SqlitePool,
+    key: &str,
+    caller: &str,
+    status: http::StatusCode,
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<()> {
+    sqlx::query(
+        "UPDATE idempotency_keys SET status = ?, content_type = ?, body = ?
+        WHERE key = ? AND caller = ?",
+    )
+    .bind(status.as_u16())
+    .bind(content_type)
+    .bind(body)
+    .bind(key)
+    .bind(caller)
+    .execute(database)
+    .await
+    .change_context(Error)
+    .attach_printable(format!(
+        "Failed to store response for idempotency key: {key}"
+    ))
+    .attach(ErrorCode::Database)?;
+    Ok(())
+}
+
+/// Gives up `key` after a failed request, so that it can be retried.
+async fn release(database: &SqlitePool, key: &str, caller: &str) -> Result<()> {
+    sqlx::query("DELETE FROM idempotency_keys WHERE key = ? AND caller = ?")
+        .bind(key)
+        .bind(caller)
+        .execute(database)
+        .await
+        .change_context(Error)
+        .attach_printable(format!("Failed to release idempotency key: {key}"))
+        .attach(ErrorCode::Database)?;
+    Ok(())
+}
+
+/// A claimed key, released when dropped before a response was stored for it,
+/// like when the client goes away halfway through the request.
+struct Claim {
+    database: SqlitePool,
+    key: String,
+    caller: String,
+    stored: bool,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if self.stored {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            tracing::error!("Failed to release idempotency key {}", self.key);
+            return;
+        };
+        let (database, key, caller) = (
+            self.database.clone(),
+            std::mem::take(&mut self.key),
+            std::mem::take(&mut self.caller),
+        );
+        runtime.spawn(async move {
+            if let Err(e) = release(&database, &key, &caller).await {
+                tracing::error!("{e:?}");
+            }
+        });
+    }
+}
+
+/// Middleware replaying the stored response for requests with an
+/// `Idempotency-Key` seen before. Only successful responses are stored, with
+/// their [`Secrets`] masked, and the key of a failed or abandoned request can
+/// be used again.
+pub async fn replay(
+    axum::Extension(database): axum::Extension<SqlitePool>,
+    caller: auth::Caller,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY) else {
+        return next.run(request).await;
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => key.to_string(),
+        _ => {
+            return ErrorResponse::from(bad_request(&format!(
+                "The Idempotency-Key header must be 1 to {MAX_KEY_LENGTH} visible characters"
+            )))
+            .into_response();
+        }
+    };
+    let caller = caller
+        .user()
+        .map(|user| user.username.clone())
+        .unwrap_or_default();
+    let (parts, body) = request.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, MAX_BODY).await else {
+        return ErrorResponse::from(bad_request("The request body is too large")).into_response();
+    };
+    match claim(&database, &key, &caller, &fingerprint(&parts, &body)).await {
+        Ok(Some(stored)) => return stored,
+        Ok(None) => {}
+        Err(e) => return ErrorResponse::from(e).into_response(),
+    }
+    let mut claim = Claim {
+        database: database.clone(),
+        key: key.clone(),
+        caller: caller.clone(),
+        stored: false,
+    };
+    let response = next
+        .run(axum::extract::Request::from_parts(parts, body.into()))
+        .await;
+    if !response.status().is_success() {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("Failed to read response for idempotency key {key}: {e}");
+            return ErrorResponse::from(Error::new().attach(ErrorCode::Internal)).into_response();
+        }
+    };
+    let content_type = parts
+        .headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok());
+    let secrets = parts
+        .extensions
+        .get::<Secrets>()
+        .cloned()
+        .unwrap_or_default();
+    let masked = match std::str::from_utf8(&body) {
+        Ok(text) => secret::mask(text, &secrets.0).into_bytes(),
+        Err(_) => body.to_vec(),
+    };
+    match store(
+        &database,
+        &key,
+        &caller,
+        parts.status,
+        content_type,
+        &masked,
+    )
+    .await
+    {
+        Ok(()) => claim.stored = true,
+        Err(e) => tracing::error!("{e:?}"),
+    }
+    axum::response::Response::from_parts(parts, body.into())
+}
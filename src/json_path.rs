@@ -0,0 +1,156 @@
+//! Paths picking values out of json, shared by pipeline conditions, webhook
+//! filters and transforms. A path like `$.monitors[0].name` starts at an
+//! optional `$` and is followed by `.key`, `[0]`, `["a key"]` or `*` and
+//! `[*]` for every element. The first key may leave out the dot, and keys that
+//! are numbers also index arrays, so `0.name` works as well.
+
+use crate::*;
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+    /// Every element of an array, or value of an object
+    All,
+}
+
+impl Segment {
+    /// The index this segment picks out of an array, if any.
+    pub fn index(&self) -> Option<usize> {
+        match self {
+            Segment::Key(key) => key.parse().ok(),
+            Segment::Index(index) => Some(*index),
+            Segment::All => None,
+        }
+    }
+}
+
+/// Parses `path`, or returns why it isn't one.
+pub fn parse(path: &str) -> Result<Vec<Segment>, String> {
+    let mut rest = path.strip_prefix('$').unwrap_or(path);
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        if let Some(bracketed) = rest.strip_prefix('[') {
+            let end = if let Some(quoted) = bracketed.strip_prefix('"') {
+                quoted
+                    .find("\"]")
+                    .map(|end| end + 2)
+                    .ok_or("unterminated key")?
+            } else {
+                bracketed.find(']').ok_or("unterminated index")?
+            };
+            let inner = &bracketed[..end];
+            segments.push(match inner {
+                "*" => Segment::All,
+                key if key.starts_with('"') => Segment::Key(
+                    serde_json::from_str(key).map_err(|e| format!("invalid key {key}: {e}"))?,
+                ),
+                index => Segment::Index(
+                    index
+                        .parse()
+                        .map_err(|_| format!("invalid index {index}"))?,
+                ),
+            });
+            rest = &bracketed[end + 1..];
+        } else {
+            let dotted = match rest.strip_prefix('.') {
+                Some(dotted) => dotted,
+                None if segments.is_empty() => rest,
+                None => return Err(format!("expected . or [ at {rest:?}")),
+            };
+            let end = dotted.find(['.', '[']).unwrap_or(dotted.len());
+            match &dotted[..end] {
+                // `.[0]` is the same as `[0]`
+                "" if dotted.starts_with('[') => {}
+                "" => return Err("empty key".into()),
+                "*" => segments.push(Segment::All),
+                key => segments.push(Segment::Key(key.to_string())),
+            }
+            rest = &dotted[end..];
+        }
+    }
+    Ok(segments)
+}
+
+fn invalid(path: &str, reason: String) -> Report<Error> {
+    Error::new()
+        .attach_printable(format!("Invalid json path {path}: {reason}"))
+        .attach(ErrorCode::BadRequest)
+        .attach(ErrorDetails(
+            serde_json::json!({ "path": path, "reason": reason }),
+        ))
+}
+
+/// Checks that `path` is a valid json path.
+pub fn check(path: &str) -> Result<()> {
+    parse(path)
+        .map(|_| ())
+        .map_err(|reason| invalid(path, reason))
+}
+
+/// The values at `path` in `value`, several when the path has wildcards and
+/// none when nothing is there.
+pub fn select<'a>(value: &'a Value, path: &str) -> Result<Vec<&'a Value>> {
+    let mut values = vec![value];
+    for segment in parse(path).map_err(|reason| invalid(path, reason))? {
+        values = values
+            .into_iter()
+            .flat_map(|value| -> Vec<&Value> {
+                match (&segment, value) {
+                    (Segment::Key(key), Value::Object(map)) => map.get(key).into_iter().collect(),
+                    (Segment::All, Value::Array(array)) => array.iter().collect(),
+                    (Segment::All, Value::Object(map)) => map.values().collect(),
+                    (segment, Value::Array(array)) => segment
+                        .index()
+                        .and_then(|index| array.get(index))
+                        .into_iter()
+                        .collect(),
+                    _ => Vec::new(),
+                }
+            })
+            .collect();
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let key = |key: &str| Segment::Key(key.to_string());
+        assert_eq!(
+            parse("$.monitors[0].name").unwrap(),
+            [key("monitors"), Segment::Index(0), key("name")]
+        );
+        assert_eq!(parse("0.name").unwrap(), [key("0"), key("name")]);
+        assert_eq!(parse(".[*].id").unwrap(), [Segment::All, key("id")]);
+        assert_eq!(parse("[\"a key\"]").unwrap(), [key("a key")]);
+        assert_eq!(parse("$").unwrap(), []);
+        assert!(parse("$.a..b").is_err());
+        assert!(parse("$[x]").is_err());
+        assert!(parse("[0]name").is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        let value = serde_json::json!([
+            { "name": "DP-1", "dpmsStatus": false },
+            { "name": "HDMI-A-1", "dpmsStatus": true }
+        ]);
+        assert_eq!(
+            select(&value, "$[1].name").unwrap(),
+            [&Value::from("HDMI-A-1")]
+        );
+        assert_eq!(select(&value, "0.name").unwrap(), [&Value::from("DP-1")]);
+        assert_eq!(
+            select(&value, "[0][\"name\"]").unwrap(),
+            [&Value::from("DP-1")]
+        );
+        assert_eq!(select(&value, "$[*].dpmsStatus").unwrap().len(), 2);
+        assert!(select(&value, "$[5].name").unwrap().is_empty());
+        assert!(select(&value, "$[x]").is_err());
+    }
+}
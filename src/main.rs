@@ -2,11 +2,32 @@ mod cli;
 mod errors;
 use errors::*;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+mod access;
 mod app;
+mod approval;
+mod audit;
+mod auth;
+mod cache;
+mod client;
 mod command;
+mod condition;
 mod config;
+mod dashboard;
 mod database;
+mod idempotency;
+mod json_path;
+mod notify;
+mod openapi;
+mod pipeline;
+mod ratelimit;
 mod routes;
+mod schedule;
+mod secret;
+mod telemetry;
+mod tls;
+mod transform;
+mod users;
+mod webhook;
 
 #[tokio::main]
 pub async fn main() -> Result<()> {
@@ -26,52 +47,458 @@ pub async fn main() -> Result<()> {
             let database_path = dunce::simplified(&config.database);
             app::App::new(
                 database_path.display().to_string(),
-                config.host,
-                config.port,
+                config.endpoints()?,
+                config.settings(),
             )
             .await?
             .serve()
             .await?;
         }
         cli::SubCommand::Add(ref add) => {
-            let config = config::Config::try_new(&args)?;
-            let database_path = dunce::simplified(&config.database);
-            let database = database::connect(database_path.display().to_string()).await?;
+            let target = client::Target::new(&args).await?;
             let command =
-                command::Command::new(add.name.clone(), add.command.clone(), add.args.clone());
+                command::Command::new(add.name.clone(), add.command.clone(), add.args.clone())
+                    .with_tags(add.tags.clone())
+                    .with_metadata(add.to_metadata());
             let mode = match (add.ignore, add.replace) {
                 (true, false) => command::CommandAddMode::Ignore,
                 (false, true) => command::CommandAddMode::Replace,
                 _ => command::CommandAddMode::Error,
             };
-            command.add(&database, mode).await?;
+            target.add(command, mode).await?;
         }
         cli::SubCommand::List(ref list) => {
-            let config = config::Config::try_new(&args)?;
-            let database_path = dunce::simplified(&config.database);
-            let database = database::connect(database_path.display().to_string()).await?;
-            let cmds = if let Some(like) = list.name.clone().or(list.command.clone()) {
-                command::Command::like(&database, like).await?
-            } else {
-                command::Command::list(&database).await?
-            };
+            let target = client::Target::new(&args).await?;
+            let cmds = target
+                .list(list.name.clone().or(list.command.clone()), list.tag.clone())
+                .await?;
             cmds.iter().for_each(|cmd| {
                 if list.verbose {
                     print!("{}: ", cmd.id);
                 }
-                println!("{}: {} {}", cmd.name, cmd.command, cmd.args.join(" "));
+                print!("{}: {} {}", cmd.name, cmd.command, cmd.args.join(" "));
+                if cmd.tags.is_empty() {
+                    println!();
+                } else {
+                    println!(" [{}]", cmd.tags.join(", "));
+                }
+                if list.verbose {
+                    let metadata = &cmd.metadata;
+                    if let Some(description) = &metadata.description {
+                        println!("    {description}");
+                    }
+                    if let Some(owner) = &metadata.owner {
+                        println!("    owner: {owner}");
+                    }
+                    if let Some(icon) = &metadata.icon {
+                        println!("    icon: {icon}");
+                    }
+                    if metadata.requires_approval {
+                        println!("    requires approval");
+                    }
+                    if !metadata.transforms.is_empty() {
+                        println!(
+                            "    transforms: {}",
+                            metadata
+                                .transforms
+                                .iter()
+                                .map(ToString::to_string)
+                                .collect::<Vec<_>>()
+                                .join(" | ")
+                        );
+                    }
+                    if let Some(seconds) = metadata.cache_seconds {
+                        println!("    output cached for {seconds}s");
+                    }
+                    if let Some(retry) = &metadata.retry {
+                        print!(
+                            "    retries: up to {} attempts, backoff {}ms",
+                            retry.max_attempts, retry.backoff_ms
+                        );
+                        if retry.exit_codes.is_empty() {
+                            println!();
+                        } else {
+                            println!(
+                                ", on exit codes {}",
+                                retry
+                                    .exit_codes
+                                    .iter()
+                                    .map(i32::to_string)
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            );
+                        }
+                    }
+                    cmd.placeholders().for_each(|placeholder| {
+                        let secret = if cmd.is_secret(placeholder) {
+                            " (secret)"
+                        } else {
+                            ""
+                        };
+                        match cmd.placeholder_help(placeholder) {
+                            Some(help) => println!("    {placeholder}{secret}: {help}"),
+                            None => println!("    {placeholder}{secret}"),
+                        }
+                    });
+                }
             });
         }
         cli::SubCommand::Rm(ref rm) => {
+            let target = client::Target::new(&args).await?;
+            if rm.all {
+                target.delete_all().await?;
+            } else if let Some(tag) = &rm.tag {
+                let deleted = target.delete_tagged(tag).await?;
+                println!("Deleted {deleted} commands tagged {tag}");
+            } else {
+                target.delete(rm.to_identifier()?).await?;
+            }
+        }
+        cli::SubCommand::Exec(ref exec) => {
+            let target = client::Target::new(&args).await?;
+            let (identifier, placeholders) = exec.to_invocation()?;
+            if exec.dry_run {
+                let dry_run = target.dry_run(identifier, placeholders).await?;
+                println!("program: {}", dry_run.program);
+                println!(
+                    "args: {}",
+                    serde_json::to_string(&dry_run.args).change_context(Error)?
+                );
+                println!("cwd: {}", dry_run.cwd.as_deref().unwrap_or("unknown"));
+                dry_run.errors.iter().for_each(|error| {
+                    eprintln!(
+                        "error: {} ({:?}){}",
+                        error.message,
+                        error.code,
+                        error
+                            .details
+                            .as_ref()
+                            .map_or_else(String::new, |details| format!(" {details}"))
+                    )
+                });
+                if !dry_run.errors.is_empty() {
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
+            let output = target
+                .run(identifier, placeholders, !exec.no_history)
+                .await?;
+            // Local commands have already streamed their output
+            match (&target, &output) {
+                (client::Target::Local(..), client::RunOutput::Command(output)) => {
+                    output.print_attempts()
+                }
+                _ => output.print()?,
+            }
+            match output.exit_code() {
+                0 => {}
+                code => std::process::exit(code),
+            }
+        }
+        cli::SubCommand::Pipeline(ref pipeline) => {
+            let target = client::Target::new(&args).await?;
+            match pipeline {
+                cli::Pipeline::Add(add) => {
+                    target.add_pipeline(add.to_new_pipeline()?).await?;
+                }
+                cli::Pipeline::List => {
+                    target.pipelines().await?.iter().for_each(|pipeline| {
+                        println!("{} ({:?} on failure)", pipeline.name, pipeline.on_failure);
+                        pipeline.steps.iter().for_each(|step| {
+                            print!("    {}{}", if step.stdin { "| " } else { "" }, step.command);
+                            step.placeholders
+                                .iter()
+                                .for_each(|(key, value)| print!(" {key}={value}"));
+                            if let Some(when) = &step.when {
+                                print!(" when {}", serde_json::to_string(when).unwrap_or_default());
+                            }
+                            println!();
+                        });
+                    });
+                }
+                cli::Pipeline::Rm { name } => {
+                    target
+                        .delete_pipeline(command::Identifier::Name(name.clone()))
+                        .await?;
+                }
+            }
+        }
+        cli::SubCommand::Schedule(ref schedule) => {
+            let target = client::Target::new(&args).await?;
+            let print = |schedule: &schedule::Schedule| {
+                println!(
+                    "{}: {} \"{}\" {}{}",
+                    schedule.id,
+                    schedule.command,
+                    schedule.cron,
+                    schedule.timezone,
+                    if schedule.paused { " (paused)" } else { "" }
+                );
+                schedule
+                    .placeholders
+                    .iter()
+                    .for_each(|(key, value)| println!("    {key}={value}"));
+                if let Some(next_run) = &schedule.next_run {
+                    println!("    next run: {next_run}");
+                }
+                if let Some(last_run_at) = &schedule.last_run_at {
+                    println!("    last run: {last_run_at}");
+                }
+            };
+            match schedule {
+                cli::Schedule::Add {
+                    command,
+                    cron,
+                    timezone,
+                    placeholders,
+                } => {
+                    print(
+                        &target
+                            .add_schedule(schedule::NewSchedule {
+                                command_id: None,
+                                command: Some(command.clone()),
+                                cron: cron.clone(),
+                                timezone: timezone.clone(),
+                                placeholders: placeholders.iter().cloned().collect(),
+                            })
+                            .await?,
+                    );
+                }
+                cli::Schedule::List => target.schedules().await?.iter().for_each(print),
+                cli::Schedule::Pause { id } => print(&target.set_schedule_paused(*id, true).await?),
+                cli::Schedule::Resume { id } => {
+                    print(&target.set_schedule_paused(*id, false).await?)
+                }
+                cli::Schedule::Rm { id } => target.delete_schedule(*id).await?,
+            }
+        }
+        cli::SubCommand::Webhook(ref webhook) => {
+            let target = client::Target::new(&args).await?;
+            let print = |webhook: &webhook::Webhook| {
+                println!(
+                    "{}: {} at /hooks/{}",
+                    webhook.id, webhook.command, webhook.id
+                );
+                webhook
+                    .placeholders
+                    .iter()
+                    .for_each(|(key, path)| println!("    {key}={path}"));
+                if let Some(secret) = &webhook.secret {
+                    println!("    secret: {secret}");
+                }
+            };
+            match webhook {
+                cli::Webhook::Add {
+                    command,
+                    secret,
+                    placeholders,
+                } => {
+                    print(
+                        &target
+                            .add_webhook(webhook::NewWebhook {
+                                command_id: None,
+                                command: Some(command.clone()),
+                                secret: secret.clone(),
+                                placeholders: placeholders.iter().cloned().collect(),
+                            })
+                            .await?,
+                    );
+                }
+                cli::Webhook::List => target.webhooks().await?.iter().for_each(print),
+                cli::Webhook::Rm { id } => target.delete_webhook(*id).await?,
+            }
+        }
+        cli::SubCommand::Approval(ref approval) => {
+            let target = client::Target::new(&args).await?;
+            let print = |approval: &approval::Approval| {
+                println!(
+                    "{}: {} {:?} requested by {}{} at {}",
+                    approval.id,
+                    approval.command,
+                    approval.status,
+                    approval.requested_by.as_deref().unwrap_or("anonymous"),
+                    approval
+                        .requested_from
+                        .as_ref()
+                        .map_or_else(String::new, |ip| format!(" from {ip}")),
+                    approval.created_at
+                );
+                approval
+                    .placeholders
+                    .iter()
+                    .for_each(|(key, value)| println!("    {key}={value}"));
+                match (&approval.decided_by, &approval.decided_at) {
+                    (Some(decided_by), Some(decided_at)) => {
+                        println!("    decided by {decided_by} at {decided_at}")
+                    }
+                    _ => println!("    expires at {}", approval.expires_at),
+                }
+            };
+            match approval {
+                cli::Approval::List { all } => {
+                    let status = (!all).then_some(approval::Status::Pending);
+                    target.approvals(status).await?.iter().for_each(print);
+                }
+                cli::Approval::Approve { id } => {
+                    let output = client::RunOutput::Command(target.approve(*id).await?);
+                    output.print()?;
+                    match output.exit_code() {
+                        0 => {}
+                        code => std::process::exit(code),
+                    }
+                }
+                cli::Approval::Reject { id } => print(&target.reject(*id).await?),
+            }
+        }
+        cli::SubCommand::History(ref history) => {
+            let target = client::Target::new(&args).await?;
+            target
+                .history(history.to_identifier(), history.limit)
+                .await?
+                .iter()
+                .for_each(|entry| {
+                    if entry
+                        .decision
+                        .as_ref()
+                        .is_some_and(|decision| !decision.result)
+                    {
+                        println!("{} {} skipped", entry.created_at, entry.name);
+                        return;
+                    }
+                    println!(
+                        "{} {} exit={}{}{}",
+                        entry.created_at,
+                        entry.name,
+                        entry
+                            .exit_code
+                            .map_or_else(|| "signal".to_string(), |code| code.to_string()),
+                        match entry.triggered_by {
+                            command::Trigger::Manual => "",
+                            command::Trigger::Scheduled => " (scheduled)",
+                            command::Trigger::Webhook => " (webhook)",
+                        },
+                        if entry.attempt > 1 {
+                            format!(" (attempt {})", entry.attempt)
+                        } else {
+                            String::new()
+                        }
+                    );
+                    print!("{}", entry.stdout);
+                    eprint!("{}", entry.stderr);
+                });
+        }
+        cli::SubCommand::Audit(ref audit) => {
+            let target = client::Target::new(&args).await?;
+            target
+                .audit(&audit.to_filter())
+                .await?
+                .iter()
+                .for_each(|entry| {
+                    println!(
+                        "{} {:?}{}{} {:?} {}{}",
+                        entry.created_at,
+                        entry.actor,
+                        entry
+                            .actor_name
+                            .as_ref()
+                            .map_or_else(String::new, |name| format!(" {name}")),
+                        entry
+                            .source_ip
+                            .as_ref()
+                            .map_or_else(String::new, |ip| format!(" from {ip}")),
+                        entry.action,
+                        entry.command_name.as_deref().unwrap_or("-"),
+                        entry
+                            .placeholders
+                            .iter()
+                            .map(|(key, value)| format!(" {key}={value}"))
+                            .collect::<String>()
+                    );
+                    if let Some(details) = &entry.details {
+                        println!("    {details}");
+                    }
+                });
+        }
+        cli::SubCommand::User(ref user) => {
+            if args.remote.is_some() {
+                return Err(Error::new()
+                    .attach_printable("Users can only be managed on the server's database"));
+            }
             let config = config::Config::try_new(&args)?;
             let database_path = dunce::simplified(&config.database);
             let database = database::connect(database_path.display().to_string()).await?;
-            if rm.all {
-                command::Command::delete_all(&database).await?;
-            } else {
-                let identifier = rm.to_identifier()?;
-                let command = command::Command::identifier(&database, identifier).await?;
-                command.delete(&database).await?;
+            let find_user = async |username: &str| {
+                users::User::username(&database, username)
+                    .await?
+                    .ok_or_else(|| {
+                        Error::new().attach_printable(format!("No such user: {username}"))
+                    })
+            };
+            match user {
+                cli::User::Add { username, password } => {
+                    let password = match password {
+                        Some(password) => password.clone(),
+                        None => {
+                            let mut password = String::new();
+                            std::io::stdin()
+                                .read_line(&mut password)
+                                .change_context(Error)
+                                .attach_printable("Failed to read password from stdin")?;
+                            password.trim_end_matches(['\r', '\n']).to_string()
+                        }
+                    };
+                    users::User::add(&database, username.clone(), password).await?;
+                }
+                cli::User::List => {
+                    users::User::list(&database)
+                        .await?
+                        .iter()
+                        .for_each(|user| println!("{}: {}", user.id, user.username));
+                }
+                cli::User::Rm { username } => {
+                    find_user(username).await?.delete(&database).await?;
+                }
+                cli::User::Allow { username, command } => {
+                    let user = find_user(username).await?;
+                    let command = command::Command::identifier(
+                        &database,
+                        command::Identifier::Name(command.clone()),
+                    )
+                    .await?;
+                    auth::allow(&database, &command, &user).await?;
+                }
+                cli::User::Revoke { username, command } => {
+                    let user = find_user(username).await?;
+                    let command = command::Command::identifier(
+                        &database,
+                        command::Identifier::Name(command.clone()),
+                    )
+                    .await?;
+                    auth::revoke(&database, &command, &user).await?;
+                }
+                cli::User::Token(cli::Token::Create { username, name }) => {
+                    let user = find_user(username).await?;
+                    println!("{}", auth::ApiToken::create(&database, &user, name).await?);
+                }
+                cli::User::Token(cli::Token::List) => {
+                    auth::ApiToken::list(&database)
+                        .await?
+                        .iter()
+                        .for_each(|token| {
+                            println!(
+                                "{}: {} {} (created {}, last used {})",
+                                token.id,
+                                token.username,
+                                token.name,
+                                token.created_at,
+                                token.last_used_at.as_deref().unwrap_or("never")
+                            )
+                        });
+                }
+                cli::User::Token(cli::Token::Revoke { id }) => {
+                    auth::ApiToken::revoke(&database, *id).await?;
+                }
             }
         }
         cli::SubCommand::Completions { shell } => {
@@ -0,0 +1,237 @@
+//! Notifications sent by the server when runs complete, configured as rules
+//! in the config file.
+
+use crate::{
+    command::{Command, Identifier, Origin, Output},
+    *,
+};
+use sqlx::SqlitePool;
+use std::{collections::BTreeMap, io::Write, path::PathBuf, sync::Arc};
+
+/// Where and when to send a notification.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct Notification {
+    pub sink: Sink,
+    #[serde(default)]
+    pub on: On,
+    /// Names of the commands the rule applies to, every command when empty
+    #[serde(default)]
+    pub commands: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum On {
+    #[default]
+    Failure,
+    Always,
+    /// The run succeeded where the previous run of the command failed, or
+    /// the other way around
+    Change,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Sink {
+    /// Posts the payload as json
+    Http {
+        url: String,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+    /// Mails the payload with a local `sendmail`
+    Sendmail {
+        to: String,
+        #[serde(default = "default_sendmail")]
+        path: PathBuf,
+    },
+    /// Appends the payload as a line of json, to a file or a FIFO
+    File { path: PathBuf },
+}
+
+fn default_sendmail() -> PathBuf {
+    PathBuf::from("/usr/sbin/sendmail")
+}
+
+impl Notification {
+    fn matches(&self, command: &str, success: bool, previous: Option<bool>) -> bool {
+        if !self.commands.is_empty() && !self.commands.iter().any(|name| name == command) {
+            return false;
+        }
+        match self.on {
+            On::Failure => !success,
+            On::Always => true,
+            On::Change => previous.is_some_and(|previous| previous != success),
+        }
+    }
+}
+
+/// Whether the previous run of the command succeeded, looked up before the
+/// output of the new run is saved. `None` without rules on changes.
+pub async fn previous_success(
+    database: &SqlitePool,
+    rules: &[Notification],
+    command_id: uuid::Uuid,
+) -> Option<bool> {
+    if !rules.iter().any(|rule| rule.on == On::Change) {
+        return None;
+    }
+    sqlx::query_scalar(
+        "SELECT success FROM history WHERE command_id = ?
+        AND (decision IS NULL OR json_extract(decision, '$.result'))
+        ORDER BY created_at DESC, rowid DESC LIMIT 1",
+    )
+    .bind(command_id.as_simple())
+    .fetch_optional(database)
+    .await
+    .inspect_err(|e| tracing::error!("Failed to query the previous run: {e}"))
+    .ok()
+    .flatten()
+}
+
+/// Sends the notifications of `rules` matching a saved run in the background.
+pub fn completed(
+    database: &SqlitePool,
+    rules: &Arc<[Notification]>,
+    command_id: uuid::Uuid,
+    output: &Output,
+    origin: &Origin,
+    previous: Option<bool>,
+) {
+    if rules.is_empty() {
+        return;
+    }
+    // Skipped pipeline steps didn't run
+    if origin
+        .decision
+        .as_ref()
+        .is_some_and(|decision| !decision.result)
+    {
+        return;
+    }
+    let database = database.clone();
+    let rules = rules.clone();
+    let output = output.clone();
+    let origin = origin.clone();
+    tokio::spawn(async move {
+        let command = match Command::identifier(&database, Identifier::Id(command_id)).await {
+            Ok(command) => command,
+            Err(e) => {
+                tracing::error!("Failed to notify about a run of {command_id}: {e:?}");
+                return;
+            }
+        };
+        let payload = serde_json::json!({
+            "command": { "id": command.id, "name": command.name },
+            "output": output,
+            "status": output.status,
+            "previous_success": previous,
+            "triggered_by": origin.trigger,
+            "schedule_id": origin.schedule,
+            "webhook_id": origin.webhook,
+            "pipeline_id": origin.pipeline.map(|(pipeline, _)| pipeline),
+        });
+        for rule in rules
+            .iter()
+            .filter(|rule| rule.matches(&command.name, output.status.success(), previous))
+        {
+            if let Err(e) = rule.sink.send(&command.name, &payload).await {
+                tracing::error!("Failed to send notification for {}: {e:?}", command.name);
+            }
+        }
+    });
+}
+
+impl Sink {
+    async fn send(&self, command: &str, payload: &serde_json::Value) -> Result<()> {
+        match self {
+            Sink::Http { url, headers } => {
+                let request = headers.iter().fold(
+                    reqwest::Client::new().post(url).json(payload),
+                    |request, (name, value)| request.header(name, value),
+                );
+                request
+                    .send()
+                    .await
+                    .and_then(reqwest::Response::error_for_status)
+                    .change_context(Error)
+                    .attach_printable(format!("Failed to post notification to {url}"))?;
+            }
+            Sink::Sendmail { to, path } => {
+                use tokio::io::AsyncWriteExt;
+                let mut child = tokio::process::Command::new(path)
+                    .args(["-t", "-i"])
+                    .stdin(std::process::Stdio::piped())
+                    .spawn()
+                    .change_context(Error)
+                    .attach_printable(format!("Failed to run {}", path.display()))?;
+                let success = payload["status"]["success"].as_bool().unwrap_or_default();
+                let mail = format!(
+                    "To: {to}\nSubject: {command} {}\nContent-Type: application/json\n\n{:#}\n",
+                    if success { "succeeded" } else { "failed" },
+                    payload
+                );
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin
+                        .write_all(mail.as_bytes())
+                        .await
+                        .change_context(Error)
+                        .attach_printable("Failed to write the mail to sendmail")?;
+                }
+                let status = child.wait().await.change_context(Error)?;
+                if !status.success() {
+                    return Err(
+                        Error::new().attach_printable(format!("sendmail exited with {status}"))
+                    );
+                }
+            }
+            Sink::File { path } => {
+                let path = path.clone();
+                let line = format!("{payload}\n");
+                // Opening a FIFO blocks until there is a reader
+                tokio::task::spawn_blocking(move || {
+                    std::fs::OpenOptions::new()
+                        .append(true)
+                        .create(true)
+                        .open(&path)
+                        .and_then(|mut file| file.write_all(line.as_bytes()))
+                        .change_context(Error)
+                        .attach_printable(format!("Failed to write to {}", path.display()))
+                })
+                .await
+                .change_context(Error)??;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules() {
+        let rules: BTreeMap<String, Vec<Notification>> = toml::from_str(
+            r#"
+            [[notifications]]
+            sink = { type = "file", path = "/tmp/runs" }
+
+            [[notifications]]
+            sink = { type = "http", url = "http://localhost/hook" }
+            on = "change"
+            commands = ["backup"]
+            "#,
+        )
+        .unwrap();
+        let [failure, change] = &rules["notifications"][..] else {
+            panic!("expected two rules");
+        };
+        assert!(failure.matches("greet", false, None));
+        assert!(!failure.matches("greet", true, Some(false)));
+        assert!(change.matches("backup", true, Some(false)));
+        assert!(!change.matches("backup", true, Some(true)));
+        assert!(!change.matches("backup", false, None));
+        assert!(!change.matches("greet", true, Some(false)));
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{command::Command, *};
+use axum::Extension;
+use utoipa::{
+    OpenApi,
+    openapi::{
+        RefOr,
+        schema::{ObjectBuilder, OneOfBuilder, Schema, Type},
+    },
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(description = "Run preset commands over http"),
+    paths(
+        routes::root,
+        routes::login,
+        routes::logout,
+        routes::list_commands,
+        routes::add_command,
+        routes::identifier_command,
+        routes::run_identifier_command,
+        routes::delete_identifier_command,
+        routes::delete_all_commands,
+        routes::delete_tagged_commands,
+        routes::history,
+        routes::audit_log,
+        routes::list_pipelines,
+        routes::add_pipeline,
+        routes::delete_pipeline,
+        routes::list_schedules,
+        routes::add_schedule,
+        routes::delete_schedule,
+        routes::pause_schedule,
+        routes::resume_schedule,
+        routes::metrics,
+        routes::list_webhooks,
+        routes::add_webhook,
+        routes::delete_webhook,
+        routes::trigger_webhook,
+        routes::list_approvals,
+        routes::approve,
+        routes::reject,
+        openapi,
+    ),
+    components(schemas(
+        Command,
+        command::Output,
+        command::DryRun,
+        command::ExitStatus,
+        command::History,
+        command::Metadata,
+        command::Retry,
+        routes::Deleted,
+        pipeline::Pipeline,
+        pipeline::PipelineOutput,
+        schedule::Schedule,
+        webhook::Webhook,
+        approval::Approval,
+        ErrorResponse
+    ))
+)]
+pub struct ApiDoc;
+
+/// Name of the component schema describing the placeholders of `command`.
+fn placeholders_schema_name(command: &Command) -> String {
+    let name: String = command
+        .name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("Placeholders.{name}")
+}
+
+fn placeholders_schema(command: &Command) -> Schema {
+    command
+        .placeholders()
+        .fold(
+            ObjectBuilder::new()
+                .title(Some(format!("Placeholders for {}", command.name)))
+                .description(Some(match &command.metadata.description {
+                    Some(description) => format!(
+                        "{description}\n\nRequest body for `POST /commands/run?name={}`",
+                        command.name
+                    ),
+                    None => format!(
+                        "Request body for `POST /commands/run?name={}`",
+                        command.name
+                    ),
+                })),
+            |object, placeholder| {
+                object
+                    .property(
+                        placeholder,
+                        ObjectBuilder::new()
+                            .schema_type(Type::String)
+                            .description(command.placeholder_help(placeholder)),
+                    )
+                    .required(placeholder)
+            },
+        )
+        .into()
+}
+
+/// The static document extended with a schema for every registered command's
+/// placeholders, so that clients can be generated against the current set of
+/// commands.
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    responses((status = 200, description = "OpenAPI 3 document of this API"))
+)]
+pub async fn openapi(
+    Extension(db): Extension<sqlx::SqlitePool>,
+) -> Result<axum::Json<utoipa::openapi::OpenApi>, ErrorResponse> {
+    let mut document = ApiDoc::openapi();
+    let commands = Command::list(&db).await?;
+
+    let components = document.components.get_or_insert_with(Default::default);
+    let references = commands
+        .iter()
+        .map(|command| {
+            let name = placeholders_schema_name(command);
+            components
+                .schemas
+                .insert(name.clone(), placeholders_schema(command).into());
+            RefOr::Ref(utoipa::openapi::Ref::from_schema_name(name))
+        })
+        .collect::<Vec<_>>();
+
+    if !references.is_empty()
+        && let Some(body) = document
+            .paths
+            .paths
+            .get_mut("/commands/run")
+            .and_then(|item| item.post.as_mut())
+            .and_then(|operation| operation.request_body.as_mut())
+    {
+        for content in body.content.values_mut() {
+            content.schema = Some(
+                references
+                    .iter()
+                    .cloned()
+                    .fold(OneOfBuilder::new(), |one_of, reference| {
+                        one_of.item(reference)
+                    })
+                    .into(),
+            );
+        }
+    }
+    Ok(axum::Json(document))
+}
@@ -0,0 +1,515 @@
+//! Pipelines run registered commands one after another, optionally feeding
+//! the output of one step into the next.
+
+use crate::{
+    command::{Command, History, Identifier, Origin, Output, UuidWrapper},
+    condition::When,
+    *,
+};
+use regex::Regex;
+use sqlx::SqlitePool;
+use std::{collections::BTreeMap, sync::LazyLock};
+
+/// `{name}` in the placeholder values of a step.
+static VARIABLE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{([^{}]+)\}").expect("Failed to compile regex"));
+
+/// What to do with the remaining steps once a step fails.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    sqlx::Type,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum OnFailure {
+    #[default]
+    Stop,
+    Continue,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, sqlx::FromRow, utoipa::ToSchema)]
+pub struct Step {
+    #[sqlx(try_from = "UuidWrapper")]
+    pub command_id: uuid::Uuid,
+    /// Name of the command
+    pub command: String,
+    /// Values for the command's placeholders. `{stdout}` is replaced with the
+    /// output of the previous step and any other `{name}` with the argument
+    /// `name` the pipeline was run with
+    #[sqlx(json)]
+    #[serde(default)]
+    pub placeholders: BTreeMap<String, String>,
+    /// Pass the output of the previous step to the command's stdin
+    #[serde(default)]
+    pub stdin: bool,
+    /// Only run the step when this holds
+    #[sqlx(json(nullable))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<When>,
+}
+
+impl Step {
+    /// The placeholder values for this step's command.
+    fn values(
+        &self,
+        args: &BTreeMap<String, String>,
+        previous: &str,
+    ) -> Result<BTreeMap<String, String>> {
+        self.placeholders
+            .iter()
+            .map(|(placeholder, template)| {
+                let mut missing = None;
+                let value = VARIABLE.replace_all(template, |captures: &regex::Captures| {
+                    let name = &captures[1];
+                    if name == "stdout" {
+                        previous.trim_end_matches(['\r', '\n']).to_owned()
+                    } else {
+                        args.get(name).cloned().unwrap_or_else(|| {
+                            missing.get_or_insert_with(|| name.to_owned());
+                            String::new()
+                        })
+                    }
+                });
+                match missing {
+                    Some(name) => Err(Error::new()
+                        .attach_printable(format!(
+                            "No value provided for {name} used by step {}",
+                            self.command
+                        ))
+                        .attach(ErrorCode::PlaceholderMissing)
+                        .attach(ErrorDetails(serde_json::json!({ "placeholder": name })))),
+                    None => Ok((placeholder.clone(), value.into_owned())),
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, sqlx::FromRow, utoipa::ToSchema)]
+pub struct Pipeline {
+    #[sqlx(try_from = "UuidWrapper")]
+    pub id: uuid::Uuid,
+    pub name: String,
+    pub on_failure: OnFailure,
+    #[sqlx(skip)]
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct NewStep {
+    /// Id of the command to run
+    #[serde(default)]
+    pub command_id: Option<uuid::Uuid>,
+    /// Name of the command to run, when no id is given
+    #[serde(default)]
+    pub command: Option<String>,
+    #[serde(default)]
+    pub placeholders: BTreeMap<String, String>,
+    #[serde(default)]
+    pub stdin: bool,
+    #[serde(default)]
+    pub when: Option<When>,
+}
+
+impl NewStep {
+    /// The command the step runs.
+    pub fn identifier(&self) -> Result<Identifier> {
+        match (self.command_id, &self.command) {
+            (Some(id), _) => Ok(Identifier::Id(id)),
+            (None, Some(name)) => Ok(Identifier::Name(name.clone())),
+            (None, None) => Err(Error::new()
+                .attach_printable("Pipeline step without a command")
+                .attach(ErrorCode::BadRequest)
+                .attach(ErrorDetails(serde_json::json!({
+                    "reason": "Every step needs a command_id or command"
+                })))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct NewPipeline {
+    pub name: String,
+    #[serde(default)]
+    pub on_failure: OnFailure,
+    pub steps: Vec<NewStep>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct StepOutput {
+    pub command: String,
+    pub output: Output,
+}
+
+/// Whether a step with a condition ran.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct Decision {
+    /// Position of the step the decision is about
+    pub step: usize,
+    pub when: When,
+    /// Position of the step whose output was tested, none if no step to test
+    /// had run
+    pub tested: Option<usize>,
+    /// Whether the condition held and the step ran
+    pub result: bool,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct PipelineOutput {
+    pub pipeline: String,
+    /// Links the history entries of the steps of this run
+    pub run_id: uuid::Uuid,
+    /// Whether every step ran and succeeded
+    pub success: bool,
+    pub steps: Vec<StepOutput>,
+    /// Number of steps not run after a failure
+    pub skipped: usize,
+    /// Conditions evaluated during the run
+    #[serde(default)]
+    pub decisions: Vec<Decision>,
+}
+
+impl PipelineOutput {
+    /// Output of the last step that ran.
+    pub fn last(&self) -> Option<&Output> {
+        self.steps.last().map(|step| &step.output)
+    }
+}
+
+impl Pipeline {
+    pub async fn add(database: &SqlitePool, new: NewPipeline) -> Result<Pipeline> {
+        if new.steps.is_empty() {
+            return Err(Error::new()
+                .attach_printable(format!("Pipeline {} has no steps", new.name))
+                .attach(ErrorCode::BadRequest)
+                .attach(ErrorDetails(
+                    serde_json::json!({ "reason": "A pipeline needs at least one step" }),
+                )));
+        }
+        let mut commands = Vec::with_capacity(new.steps.len());
+        for (position, step) in new.steps.iter().enumerate() {
+            if let Some(when) = &step.when {
+                when.condition.validate()?;
+                let tested = match when.step {
+                    Some(tested) => tested,
+                    None => position.checked_sub(1).unwrap_or(position),
+                };
+                if tested >= position {
+                    return Err(Error::new()
+                        .attach_printable(format!(
+                            "Condition of step {position} doesn't test an earlier step"
+                        ))
+                        .attach(ErrorCode::BadRequest)
+                        .attach(ErrorDetails(serde_json::json!({
+                            "reason": "A condition can only test the output of an earlier step",
+                            "step": position,
+                        }))));
+                }
+            }
+            commands.push(Command::identifier(database, step.identifier()?).await?);
+        }
+        // Pipelines are run by name like commands, so the names must not clash
+        if Command::identifier(database, Identifier::Name(new.name.clone()))
+            .await
+            .is_ok()
+        {
+            return Err(Error::new()
+                .attach_printable(format!("A command named {} already exists", new.name))
+                .attach(ErrorCode::PipelineExists)
+                .attach(ErrorDetails(serde_json::json!({ "name": new.name }))));
+        }
+
+        let id = uuid::Uuid::new_v4();
+        let mut transaction = database
+            .begin()
+            .await
+            .change_context(Error)
+            .attach(ErrorCode::Database)?;
+        sqlx::query("INSERT INTO pipelines (id, name, on_failure) VALUES (?, ?, ?)")
+            .bind(id.as_simple())
+            .bind(&new.name)
+            .bind(new.on_failure)
+            .execute(&mut *transaction)
+            .await
+            .map_err(|e| {
+                let code = match &e {
+                    sqlx::Error::Database(e) if e.is_unique_violation() => {
+                        ErrorCode::PipelineExists
+                    }
+                    _ => ErrorCode::Database,
+                };
+                Report::new(e)
+                    .change_context(Error)
+                    .attach_printable(format!("Failed to add pipeline: {}", new.name))
+                    .attach(code)
+                    .attach(ErrorDetails(serde_json::json!({ "name": new.name })))
+            })?;
+        for (position, (step, command)) in new.steps.iter().zip(&commands).enumerate() {
+            sqlx::query(
+                "INSERT INTO pipeline_steps (pipeline_id, position, command_id, placeholders, stdin, condition)
+                VALUES (?, ?, ?, ?, ?, ?)",
+            )
+            .bind(id.as_simple())
+            .bind(position as i64)
+            .bind(command.id.as_simple())
+            .bind(sqlx::types::Json(&step.placeholders))
+            .bind(step.stdin)
+            .bind(step.when.as_ref().map(sqlx::types::Json))
+            .execute(&mut *transaction)
+            .await
+            .change_context(Error)
+            .attach_printable(format!("Failed to add step {position} of {}", new.name))
+            .attach(ErrorCode::Database)?;
+        }
+        transaction
+            .commit()
+            .await
+            .change_context(Error)
+            .attach_printable(format!("Failed to add pipeline: {}", new.name))
+            .attach(ErrorCode::Database)?;
+        Self::identifier(database, &Identifier::Id(id)).await
+    }
+
+    async fn with_steps(mut self, database: &SqlitePool) -> Result<Pipeline> {
+        self.steps = sqlx::query_as(
+            "SELECT s.command_id, c.name AS command, s.placeholders, s.stdin, s.condition AS \"when\"
+            FROM pipeline_steps s JOIN commands c ON c.id = s.command_id
+            WHERE s.pipeline_id = ? ORDER BY s.position",
+        )
+        .bind(self.id.as_simple())
+        .fetch_all(database)
+        .await
+        .change_context(Error)
+        .attach_printable(format!("Failed to query steps of pipeline: {}", self.name))
+        .attach(ErrorCode::Database)?;
+        Ok(self)
+    }
+
+    pub async fn list(database: &SqlitePool) -> Result<Vec<Pipeline>> {
+        let pipelines: Vec<Pipeline> =
+            sqlx::query_as("SELECT id, name, on_failure FROM pipelines ORDER BY name")
+                .fetch_all(database)
+                .await
+                .change_context(Error)
+                .attach_printable("Failed to list pipelines")
+                .attach(ErrorCode::Database)?;
+        let mut out = Vec::with_capacity(pipelines.len());
+        for pipeline in pipelines {
+            out.push(pipeline.with_steps(database).await?);
+        }
+        Ok(out)
+    }
+
+    /// The pipeline with the id or name in `identifier`. Pipelines can't be
+    /// looked up with [`Identifier::Like`].
+    pub async fn find(database: &SqlitePool, identifier: &Identifier) -> Result<Option<Pipeline>> {
+        let pipeline: Option<Pipeline> = match identifier {
+            Identifier::Id(id) => {
+                sqlx::query_as("SELECT id, name, on_failure FROM pipelines WHERE id = ?")
+                    .bind(id.as_simple())
+                    .fetch_optional(database)
+                    .await
+            }
+            Identifier::Name(name) => {
+                sqlx::query_as("SELECT id, name, on_failure FROM pipelines WHERE name = ?")
+                    .bind(name)
+                    .fetch_optional(database)
+                    .await
+            }
+            Identifier::Like(_) => Ok(None),
+        }
+        .change_context(Error)
+        .attach_printable(format!("Failed to query pipeline: {:?}", identifier))
+        .attach(ErrorCode::Database)?;
+        match pipeline {
+            Some(pipeline) => Ok(Some(pipeline.with_steps(database).await?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn identifier(database: &SqlitePool, identifier: &Identifier) -> Result<Pipeline> {
+        Self::find(database, identifier).await?.ok_or_else(|| {
+            let (key, value) = identifier.query_pair();
+            Error::new()
+                .attach_printable(format!("No pipeline found with {key}: {value}"))
+                .attach(ErrorCode::PipelineNotFound)
+                .attach(ErrorDetails(serde_json::json!({ key: value })))
+        })
+    }
+
+    pub async fn delete(&self, database: &SqlitePool) -> Result<()> {
+        sqlx::query("DELETE FROM pipelines WHERE id = ?")
+            .bind(self.id.as_simple())
+            .execute(database)
+            .await
+            .change_context(Error)
+            .attach_printable(format!("Failed to delete pipeline: {}", self.name))
+            .attach(ErrorCode::Database)?;
+        Ok(())
+    }
+
+    /// Checks that `caller` may run every step from `peer`.
+    pub async fn authorize(
+        &self,
+        database: &SqlitePool,
+        settings: &config::Settings,
+        caller: &auth::Caller,
+        peer: &app::Peer,
+    ) -> Result<()> {
+        for step in &self.steps {
+            let command = Command::identifier(database, Identifier::Id(step.command_id)).await?;
+            auth::authorize(database, &command, caller).await?;
+            settings
+                .access
+                .check_command(&command.name, peer.addr.ip())?;
+        }
+        Ok(())
+    }
+
+    /// Runs the steps in order, skipping those whose condition doesn't hold.
+    /// Every step is saved to the history with the same run id when `history`
+    /// is set, including the skipped ones with the decision.
+    pub async fn run(
+        &self,
+        database: &SqlitePool,
+        settings: &config::Settings,
+        args: &BTreeMap<String, String>,
+        history: bool,
+    ) -> Result<PipelineOutput> {
+        // A pipeline can't wait for the approval of a step halfway through
+        for step in &self.steps {
+            let command = Command::identifier(database, Identifier::Id(step.command_id)).await?;
+            if command.metadata.requires_approval {
+                let reason = format!(
+                    "Pipeline {} can't run {}, it requires approval",
+                    self.name, command.name
+                );
+                return Err(Error::new()
+                    .attach_printable(reason.clone())
+                    .attach(ErrorCode::BadRequest)
+                    .attach(ErrorDetails(serde_json::json!({ "reason": reason }))));
+            }
+        }
+        let run_id = uuid::Uuid::new_v4();
+        let mut steps: Vec<StepOutput> = Vec::with_capacity(self.steps.len());
+        let mut decisions = Vec::new();
+        // Position of each step that ran and its index in `steps`
+        let mut ran: Vec<(usize, usize)> = Vec::new();
+        let mut success = true;
+        let mut previous = String::new();
+        for (position, step) in self.steps.iter().enumerate() {
+            let command = Command::identifier(database, Identifier::Id(step.command_id)).await?;
+            let mut origin = Origin {
+                pipeline: Some((self.id, run_id)),
+                ..Origin::default()
+            };
+            if let Some(when) = &step.when {
+                let tested = match when.step {
+                    Some(tested) => ran.iter().find(|(ran, _)| *ran == tested),
+                    None => ran.last(),
+                };
+                let result = match tested {
+                    Some((_, index)) => when.condition.evaluate(&steps[*index].output)?,
+                    None => false,
+                };
+                let decision = Decision {
+                    step: position,
+                    when: when.clone(),
+                    tested: tested.map(|(position, _)| *position),
+                    result,
+                };
+                decisions.push(decision.clone());
+                origin.decision = Some(decision);
+                if !result {
+                    if history {
+                        History::skipped(database, settings, command.id, &origin).await?;
+                    }
+                    continue;
+                }
+            }
+            settings.limiter.check_command(&command.name)?;
+            let values = step.values(args, &previous)?;
+            let output = command
+                .run_with_input(settings, values, step.stdin.then_some(previous.as_bytes()))
+                .await?;
+            if history {
+                output.save(database, settings, command.id, &origin).await?;
+            }
+            let failed = !output.status.success();
+            success &= !failed;
+            previous = output.stdout.clone();
+            ran.push((position, steps.len()));
+            steps.push(StepOutput {
+                command: command.name,
+                output,
+            });
+            if failed && self.on_failure == OnFailure::Stop {
+                break;
+            }
+        }
+        let skipped = self.steps.len()
+            - steps.len()
+            - decisions.iter().filter(|decision| !decision.result).count();
+        Ok(PipelineOutput {
+            pipeline: self.name.clone(),
+            run_id,
+            success: success && skipped == 0,
+            steps,
+            skipped,
+            decisions,
+        })
+    }
+}
+
+/// What an identifier given to `/commands/run` refers to. Commands take
+/// precedence over pipelines.
+pub enum Runnable {
+    Command(Box<Command>),
+    Pipeline(Pipeline),
+}
+
+impl Runnable {
+    pub async fn identifier(database: &SqlitePool, identifier: Identifier) -> Result<Runnable> {
+        match Command::identifier(database, identifier.clone()).await {
+            Err(report) if report.downcast_ref() == Some(&ErrorCode::CommandNotFound) => {
+                match Pipeline::find(database, &identifier).await? {
+                    Some(pipeline) => Ok(Runnable::Pipeline(pipeline)),
+                    None => Err(report),
+                }
+            }
+            command => command.map(|command| Runnable::Command(Box::new(command))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_step_values() {
+        let step = Step {
+            command_id: uuid::Uuid::nil(),
+            command: "brightness".into(),
+            placeholders: BTreeMap::from([
+                ("{level}".into(), "{level}%".into()),
+                ("file".into(), "{stdout}".into()),
+            ]),
+            stdin: false,
+            when: None,
+        };
+        let args = BTreeMap::from([("level".to_string(), "80".to_string())]);
+        let values = step.values(&args, "/tmp/song.mp3\n").unwrap();
+        assert_eq!(values["{level}"], "80%");
+        assert_eq!(values["file"], "/tmp/song.mp3");
+        assert!(step.values(&BTreeMap::new(), "").is_err());
+    }
+}
@@ -0,0 +1,382 @@
+//! Token bucket rate limits for requests and command runs, and lockouts after
+//! repeated failed logins. State is kept in memory and lost on restart.
+
+use crate::*;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Buckets and failed logins are pruned once there are this many, dropping
+/// those unused for [`IDLE`].
+const MAX_BUCKETS: usize = 10_000;
+const IDLE: Duration = Duration::from_secs(3600);
+
+/// Allows `requests` every `seconds`, in bursts of up to `requests`.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "UncheckedLimit")]
+pub struct Limit {
+    pub requests: u32,
+    pub seconds: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct UncheckedLimit {
+    requests: u32,
+    seconds: u64,
+}
+
+impl TryFrom<UncheckedLimit> for Limit {
+    type Error = String;
+
+    fn try_from(limit: UncheckedLimit) -> Result<Self, String> {
+        if limit.requests == 0 {
+            return Err("a rate limit has to allow at least one request".to_string());
+        }
+        Ok(Limit {
+            requests: limit.requests,
+            seconds: limit.seconds,
+        })
+    }
+}
+
+impl Limit {
+    fn rate(self) -> f64 {
+        f64::from(self.requests) / self.seconds.max(1) as f64
+    }
+}
+
+/// Failed logins allowed before a username is locked out. Every further
+/// failure doubles the lockout, up to `max_seconds`.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Lockout {
+    pub attempts: u32,
+    pub seconds: u64,
+    pub max_seconds: u64,
+}
+
+impl Default for Lockout {
+    fn default() -> Self {
+        Lockout {
+            attempts: 5,
+            seconds: 30,
+            max_seconds: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct RateLimits {
+    /// Requests to the server as a whole
+    pub global: Option<Limit>,
+    /// Requests of each user or api token
+    pub user: Option<Limit>,
+    /// Requests from each source address
+    pub ip: Option<Limit>,
+    /// Runs of each command
+    pub command: Option<Limit>,
+    /// Runs of specific commands, instead of `command`
+    pub commands: BTreeMap<String, Limit>,
+    pub login: Lockout,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Global,
+    User(String),
+    Ip(IpAddr),
+    Command(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Adds the tokens earned since the last update, then returns how long
+    /// until a token is available, if there is none.
+    fn refill(&mut self, limit: Limit, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate()).min(f64::from(limit.requests));
+        self.updated = now;
+        (self.tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - self.tokens) / limit.rate()))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+    updated: Instant,
+}
+
+/// Counts requests, runs and failed logins against the configured limits.
+#[derive(Debug, Default)]
+pub struct Limiter {
+    limits: RateLimits,
+    buckets: Mutex<HashMap<Key, Bucket>>,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl Limiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Limiter {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from every bucket when each has one, or else returns the
+    /// longest wait without taking any, so that requests rejected by one
+    /// limit don't use up the others.
+    fn take(&self, keys: &[(Key, Limit)], now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < IDLE);
+        }
+        let wait = keys
+            .iter()
+            .filter_map(|(key, limit)| {
+                buckets
+                    .entry(key.clone())
+                    .or_insert(Bucket {
+                        tokens: f64::from(limit.requests),
+                        updated: now,
+                    })
+                    .refill(*limit, now)
+            })
+            .max();
+        if let Some(wait) = wait {
+            return Err(wait);
+        }
+        for (key, _) in keys {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    fn locked(&self, username: &str, now: Instant) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        failures
+            .get(username)?
+            .locked_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    fn failed(&self, username: &str, now: Instant) {
+        let lockout = self.limits.login;
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        if failures.len() > MAX_BUCKETS {
+            failures.retain(|_, failures| {
+                failures.locked_until.is_some_and(|until| until > now)
+                    || now.saturating_duration_since(failures.updated) < IDLE
+            });
+        }
+        let entry = failures.entry(username.to_owned()).or_insert(Failures {
+            count: 0,
+            locked_until: None,
+            updated: now,
+        });
+        entry.count += 1;
+        entry.updated = now;
+        if entry.count >= lockout.attempts {
+            let doublings = (entry.count - lockout.attempts).min(16);
+            let seconds = lockout
+                .seconds
+                .saturating_mul(1 << doublings)
+                .min(lockout.max_seconds);
+            entry.locked_until = Some(now + Duration::from_secs(seconds));
+        }
+    }
+
+    fn succeeded(&self, username: &str) {
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        failures.remove(username);
+    }
+
+    /// Takes a run of `command` from its limit.
+    pub fn check_command(&self, command: &str) -> Result<()> {
+        let limits = &self.limits;
+        let Some(limit) = limits.commands.get(command).copied().or(limits.command) else {
+            return Ok(());
+        };
+        self.take(&[(Key::Command(command.to_owned()), limit)], Instant::now())
+            .map_err(|wait| too_many(wait, format!("Rate limit exceeded for command {command}")))
+    }
+
+    /// Fails while `username` is locked out after failed logins.
+    pub fn check_login(&self, username: &str) -> Result<()> {
+        match self.locked(username, Instant::now()) {
+            Some(wait) => Err(too_many(
+                wait,
+                format!("Login of {username} is locked after failed attempts"),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Records the outcome of a login of `username`.
+    pub fn login_attempt(&self, username: &str, success: bool) {
+        if success {
+            self.succeeded(username);
+        } else {
+            self.failed(username, Instant::now());
+        }
+    }
+}
+
+fn too_many(wait: Duration, reason: String) -> Report<Error> {
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    Error::new()
+        .attach_printable(reason)
+        .attach(ErrorCode::TooManyRequests)
+        .attach(RetryAfter(seconds))
+        .attach(ErrorDetails(serde_json::json!({ "retry_after": seconds })))
+}
+
+/// Middleware applying the global, per user and per address limits.
+pub async fn limit_requests(
+    axum::Extension(settings): axum::Extension<config::Settings>,
+    caller: core::result::Result<auth::Caller, ErrorResponse>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+    let limiter = &settings.limiter;
+    let limits = &limiter.limits;
+    let user = match caller {
+        Ok(auth::Caller::User { user, .. }) => Some(user.username),
+        _ => None,
+    };
+    let keys: Vec<(Key, Limit)> = [
+        limits.global.map(|limit| (Key::Global, limit)),
+        limits
+            .user
+            .zip(user)
+            .map(|(limit, user)| (Key::User(user), limit)),
+        limits.ip.map(|limit| (Key::Ip(peer.addr.ip()), limit)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    match limiter.take(&keys, Instant::now()) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => ErrorResponse::from(too_many(
+            wait,
+            format!("Rate limit exceeded for {}", peer.addr.ip()),
+        ))
+        .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let limit = Limit {
+            requests: 2,
+            seconds: 10,
+        };
+        let limiter = Limiter::new(RateLimits::default());
+        let keys = [(Key::Global, limit)];
+        let start = Instant::now();
+        assert!(limiter.take(&keys, start).is_ok());
+        assert!(limiter.take(&keys, start).is_ok());
+        assert_eq!(limiter.take(&keys, start), Err(Duration::from_secs(5)));
+        assert!(limiter.take(&keys, start + Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn test_rejected_take() {
+        let limiter = Limiter::new(RateLimits::default());
+        let global = Limit {
+            requests: 5,
+            seconds: 10,
+        };
+        let ip = Limit {
+            requests: 1,
+            seconds: 10,
+        };
+        let keys = [
+            (Key::Global, global),
+            (Key::Ip(IpAddr::from([10, 0, 0, 1])), ip),
+        ];
+        let now = Instant::now();
+        assert!(limiter.take(&keys, now).is_ok());
+        for _ in 0..10 {
+            assert!(limiter.take(&keys, now).is_err());
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets[&Key::Global].tokens, 4.0);
+        assert_eq!(buckets[&keys[1].0].tokens, 0.0);
+    }
+
+    #[test]
+    fn test_failures_pruned() {
+        let lockout = 2 * IDLE.as_secs();
+        let limiter = Limiter::new(RateLimits {
+            login: Lockout {
+                attempts: 2,
+                seconds: lockout,
+                max_seconds: lockout,
+            },
+            ..RateLimits::default()
+        });
+        let now = Instant::now();
+        limiter.failed("alice", now);
+        limiter.failed("alice", now);
+        for index in 0..=MAX_BUCKETS {
+            limiter.failed(&format!("user{index}"), now);
+        }
+        let later = now + IDLE;
+        limiter.failed("bob", later);
+        let failures = limiter.failures.lock().unwrap();
+        assert_eq!(failures.len(), 2);
+        assert!(failures.contains_key("bob"));
+        drop(failures);
+        assert!(limiter.locked("alice", later).is_some());
+    }
+
+    #[test]
+    fn test_limit_without_requests() {
+        assert!(toml::from_str::<Limit>("requests = 0\nseconds = 10").is_err());
+        assert_eq!(
+            toml::from_str::<Limit>("requests = 1\nseconds = 10").unwrap(),
+            Limit {
+                requests: 1,
+                seconds: 10
+            }
+        );
+    }
+
+    #[test]
+    fn test_lockout() {
+        let limiter = Limiter::new(RateLimits::default());
+        let now = Instant::now();
+        for _ in 0..4 {
+            limiter.failed("alice", now);
+        }
+        assert_eq!(limiter.locked("alice", now), None);
+        limiter.failed("alice", now);
+        assert_eq!(limiter.locked("alice", now), Some(Duration::from_secs(30)));
+        limiter.failed("alice", now);
+        assert_eq!(limiter.locked("alice", now), Some(Duration::from_secs(60)));
+        assert_eq!(limiter.locked("alice", now + Duration::from_secs(61)), None);
+        limiter.succeeded("alice");
+        limiter.failed("alice", now);
+        assert_eq!(limiter.locked("alice", now), None);
+    }
+}
@@ -1,31 +1,182 @@
 use std::collections::BTreeMap;
 
-use crate::{command::Command, *};
+use crate::{
+    command::{Command, History, Output},
+    *,
+};
 use axum::Extension;
 
+/// Set on run responses served from the output cache.
+const CACHE_STATUS: &str = "x-cache";
+
 pub fn routes() -> axum::Router {
     axum::Router::new()
         .route("/", axum::routing::get(root))
+        .route("/login", axum::routing::post(login))
+        .route("/logout", axum::routing::post(logout))
+        .route("/openapi.json", axum::routing::get(openapi::openapi))
+        .route("/metrics", axum::routing::get(metrics))
+        .route("/history", axum::routing::get(history))
+        .route("/audit", axum::routing::get(audit_log))
+        .nest("/ui", dashboard::routes())
         .nest("/commands", commands())
+        .nest("/pipelines", pipelines())
+        .nest("/schedules", schedules())
+        .nest("/webhooks", webhooks())
+        .nest("/approvals", approvals())
+        .route("/hooks/{id}", axum::routing::post(trigger_webhook))
 }
 type Result<T> = std::result::Result<T, ErrorResponse>;
 
+/// [`axum::extract::Query`] that rejects with an [`ErrorResponse`].
+#[derive(axum::extract::FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ErrorResponse))]
+pub struct Query<T>(pub T);
+
+/// [`axum::Json`] that rejects with an [`ErrorResponse`].
+#[derive(axum::extract::FromRequest)]
+#[from_request(via(axum::Json), rejection(ErrorResponse))]
+pub struct Json<T>(pub T);
+
+/// [`axum::extract::Form`] that rejects with an [`ErrorResponse`].
+#[derive(axum::extract::FromRequest)]
+#[from_request(via(axum::extract::Form), rejection(ErrorResponse))]
+pub struct Form<T>(pub T);
+
+/// Metrics in the Prometheus text format.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses((status = 200, body = String, content_type = "text/plain"), (status = 500, body = ErrorResponse))
+)]
+pub async fn metrics(
+    Extension(db): Extension<sqlx::SqlitePool>,
+) -> Result<([(http::HeaderName, &'static str); 1], String)> {
+    Ok((
+        [(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        telemetry::render(&db).await?,
+    ))
+}
+
+#[utoipa::path(get, path = "/", responses((status = 200, body = String)))]
 pub async fn root() -> &'static str {
     "Command runner API"
 }
 
+#[utoipa::path(
+    post,
+    path = "/login",
+    request_body(content = users::Credentials, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 204, description = "Logged in"),
+        (status = 303, description = "Logged in, redirecting to `next`"),
+        (status = 400, description = "`next` isn't a path on this server", body = ErrorResponse),
+        (status = 401, description = "Invalid username or password", body = ErrorResponse),
+        (status = 429, description = "Locked out after failed logins", body = ErrorResponse),
+    )
+)]
+pub async fn login(
+    Extension(settings): Extension<config::Settings>,
+    mut auth_session: users::AuthSession,
+    Form(credentials): Form<users::Credentials>,
+) -> Result<axum::response::Response> {
+    use axum::response::IntoResponse;
+    settings.limiter.check_login(&credentials.username)?;
+    let next = credentials.next.clone();
+    if let Some(next) = next.as_deref().filter(|next| !is_local_path(next)) {
+        return Err(Error::new()
+            .attach_printable(format!("Refusing to redirect to {next}"))
+            .attach(ErrorCode::BadRequest)
+            .attach(ErrorDetails(serde_json::json!({ "next": next })))
+            .into());
+    }
+    let user = auth_session
+        .authenticate(credentials)
+        .await
+        .change_context(Error)
+        .attach_printable("Failed to authenticate user")?
+        .ok_or_else(|| {
+            Error::new()
+                .attach_printable("Invalid username or password")
+                .attach(ErrorCode::InvalidCredentials)
+        })?;
+    auth_session
+        .login(&user)
+        .await
+        .change_context(Error)
+        .attach_printable("Failed to log in user")?;
+    Ok(match next {
+        Some(next) => axum::response::Redirect::to(&next).into_response(),
+        None => http::StatusCode::NO_CONTENT.into_response(),
+    })
+}
+
+/// Whether `next` is a path on this server rather than another origin, which
+/// `//host` and `/\host` are to browsers.
+fn is_local_path(next: &str) -> bool {
+    next.starts_with('/') && !next.starts_with("//") && !next.contains('\\')
+}
+
+#[utoipa::path(post, path = "/logout", responses((status = 204, description = "Logged out")))]
+pub async fn logout(mut auth_session: users::AuthSession) -> Result<http::StatusCode> {
+    auth_session
+        .logout()
+        .await
+        .change_context(Error)
+        .attach_printable("Failed to log out user")?;
+    Ok(http::StatusCode::NO_CONTENT)
+}
+
 pub fn commands() -> axum::Router {
     axum::Router::new()
         .route("/", axum::routing::get(list_commands))
+        .route("/", axum::routing::post(add_command))
         .route("/search", axum::routing::get(identifier_command))
-        .route("/run", axum::routing::post(run_identifier_command))
+        .route(
+            "/run",
+            axum::routing::post(run_identifier_command)
+                .route_layer(axum::middleware::from_fn(idempotency::replay)),
+        )
         .route("/", axum::routing::delete(delete_identifier_command))
+        .route("/all", axum::routing::delete(delete_all_commands))
+        .route(
+            "/tagged/{tag}",
+            axum::routing::delete(delete_tagged_commands),
+        )
+}
+pub fn pipelines() -> axum::Router {
+    axum::Router::new()
+        .route("/", axum::routing::get(list_pipelines))
+        .route("/", axum::routing::post(add_pipeline))
+        .route("/", axum::routing::delete(delete_pipeline))
+}
+pub fn webhooks() -> axum::Router {
+    axum::Router::new()
+        .route("/", axum::routing::get(list_webhooks))
+        .route("/", axum::routing::post(add_webhook))
+        .route("/{id}", axum::routing::delete(delete_webhook))
 }
+pub fn approvals() -> axum::Router {
+    axum::Router::new()
+        .route("/", axum::routing::get(list_approvals))
+        .route("/{id}/approve", axum::routing::post(approve))
+        .route("/{id}/reject", axum::routing::post(reject))
+}
+pub fn schedules() -> axum::Router {
+    axum::Router::new()
+        .route("/", axum::routing::get(list_schedules))
+        .route("/", axum::routing::post(add_schedule))
+        .route("/{id}", axum::routing::delete(delete_schedule))
+        .route("/{id}/pause", axum::routing::post(pause_schedule))
+        .route("/{id}/resume", axum::routing::post(resume_schedule))
+}
+
 pub async fn handler_404(uri: http::Uri) -> Result<()> {
     Err(Error)
         .change_context(Error)
         .attach_printable_lazy(|| format!("The specified route: {uri} doesn't exist"))
-        .attach(http::StatusCode::NOT_FOUND)?;
+        .attach(ErrorCode::RouteNotFound)
+        .attach(ErrorDetails(serde_json::json!({ "route": uri.path() })))?;
     Ok(())
 }
 
@@ -44,7 +195,10 @@ pub async fn handler_405(
                 .attach_printable({
                     format!("The specified route: {uri} doesn't use the {method} method")
                 })
-                .attach(http::StatusCode::METHOD_NOT_ALLOWED),
+                .attach(ErrorCode::MethodNotAllowed)
+                .attach(ErrorDetails(
+                    serde_json::json!({ "route": uri.path(), "method": method.as_str() }),
+                )),
         )
         .into_response()
     } else {
@@ -52,18 +206,101 @@ pub async fn handler_405(
     }
 }
 
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize, utoipa::IntoParams)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+pub struct ListArgs {
+    /// Only list commands whose name or program contains this
+    like: Option<String>,
+    /// Only list commands with this tag
+    tag: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/commands",
+    params(ListArgs),
+    responses((status = 200, body = Vec<Command>), (status = 500, body = ErrorResponse))
+)]
 pub async fn list_commands(
+    Query(list_args): Query<ListArgs>,
     Extension(db): Extension<sqlx::SqlitePool>,
 ) -> Result<axum::Json<Vec<Command>>> {
-    Ok(axum::Json(Command::list(&db).await?))
+    Ok(axum::Json(
+        Command::filter(&db, list_args.like, list_args.tag).await?,
+    ))
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct NewCommand {
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub mode: command::CommandAddMode,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(flatten)]
+    pub metadata: command::Metadata,
+}
+
+#[utoipa::path(
+    post,
+    path = "/commands",
+    request_body = NewCommand,
+    responses(
+        (status = 200, body = Command),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+    )
+)]
+pub async fn add_command(
+    Extension(db): Extension<sqlx::SqlitePool>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+    Json(new): Json<NewCommand>,
+) -> Result<axum::Json<Command>> {
+    caller.authenticated("add commands")?;
+    let name = new.name.clone();
+    // Replacing a command needs the same permission as running it
+    match Command::identifier(&db, command::Identifier::Name(name.clone())).await {
+        Ok(existing) => auth::authorize(&db, &existing, &caller).await?,
+        Err(e) if e.downcast_ref() == Some(&ErrorCode::CommandNotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+    Command::new(new.name, new.command, new.args)
+        .with_tags(new.tags)
+        .with_metadata(new.metadata)
+        .add(&db, new.mode)
+        .await?;
+    let command = Command::identifier(&db, command::Identifier::Name(name)).await?;
+    audit::Event::caller(&caller, &peer, audit::Action::Add)
+        .with_command(&command)
+        .record(&db)
+        .await?;
+    Ok(axum::Json(command))
 }
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, utoipa::IntoParams)]
 #[serde(default)]
+#[into_params(parameter_in = Query)]
 pub struct RunArgs {
+    /// Save the output to the command's history (default: true)
     history: bool,
+    /// Respond with stdout, stderr and the exit status as json
     full: bool,
+    /// Respond with stdout as json
     json: bool,
+    /// Respond with what the command would run and why it would fail,
+    /// without running it
+    dry_run: bool,
+    /// Transforms of stdout applied after the command's own, chained with
+    /// ` | `, e.g. `lines | regex:^(?<name>\S+) connected`. The response is
+    /// the resulting json
+    transform: Option<String>,
 }
 
 impl Default for RunArgs {
@@ -72,47 +309,896 @@ impl Default for RunArgs {
             history: true,
             full: false,
             json: false,
+            dry_run: false,
+            transform: None,
         }
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/commands/search",
+    params(command::IdentifierQuery),
+    responses((status = 200, body = Command), (status = 404, body = ErrorResponse))
+)]
 pub async fn identifier_command(
-    axum::extract::Query(identifier): axum::extract::Query<command::Identifier>,
+    Query(identifier): Query<command::Identifier>,
     Extension(db): Extension<sqlx::SqlitePool>,
 ) -> Result<axum::Json<Command>> {
     Ok(axum::Json(Command::identifier(&db, identifier).await?))
 }
+#[utoipa::path(
+    post,
+    path = "/commands/run",
+    params(
+        command::IdentifierQuery,
+        RunArgs,
+        ("Idempotency-Key" = Option<String>, Header, description = "Responds to a retried \
+            request with the stored response of the first one instead of running again"),
+    ),
+    request_body(
+        content = BTreeMap<String, String>,
+        description = "Values for the command's placeholders, keyed by placeholder"
+    ),
+    responses(
+        (status = 200, description = "Output of the command, or of a pipeline's last step, \
+            as json when transformed. With `full` the untransformed output, and a pipeline \
+            responds with a `PipelineOutput`. With `dry_run` a command responds with a `DryRun`",
+            content(
+            (String = "text/plain"),
+            (Output = "application/json"),
+        ), headers(
+            ("x-cache" = String, description = "`hit` when the output of an earlier run was reused"),
+            ("idempotent-replayed" = String, description = "`true` when the response is the \
+                stored one of an earlier request with the same `Idempotency-Key`"),
+        )),
+        (status = 202, body = approval::Approval, description = "The command requires approval, \
+            the run waits for a second user to approve it"),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, body = ErrorResponse, description = "A request with the same \
+            `Idempotency-Key` is still running"),
+        (status = 422, body = ErrorResponse, description = "The `Idempotency-Key` was used \
+            for another request"),
+        (status = 500, body = ErrorResponse),
+    )
+)]
 pub async fn run_identifier_command(
-    axum::extract::Query(identifier): axum::extract::Query<command::Identifier>,
-    axum::extract::Query(run_args): axum::extract::Query<RunArgs>,
+    Query(identifier): Query<command::Identifier>,
+    Query(run_args): Query<RunArgs>,
     Extension(db): Extension<sqlx::SqlitePool>,
-    axum::extract::Json(args): axum::extract::Json<BTreeMap<String, String>>,
+    Extension(settings): Extension<config::Settings>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+    Json(args): Json<BTreeMap<String, String>>,
 ) -> Result<axum::response::Response> {
     use axum::response::IntoResponse;
-    let command = Command::identifier(&db, identifier).await?;
-    let output = command.run_with_placeholder(args).await?;
-    if run_args.history {
-        output.save(&db, command.id).await?;
-    }
-    if run_args.full {
-        Ok(axum::Json(output).into_response())
+    let mut transforms = run_args
+        .transform
+        .as_deref()
+        .map(transform::parse)
+        .transpose()?
+        .unwrap_or_default();
+    let mut cached = false;
+    let (stdout, full, secrets) = match pipeline::Runnable::identifier(&db, identifier).await? {
+        pipeline::Runnable::Command(command) if run_args.dry_run => {
+            auth::authorize(&db, &command, &caller).await?;
+            settings
+                .access
+                .check_command(&command.name, peer.addr.ip())?;
+            return Ok(axum::Json(command.dry_run(&settings, args)).into_response());
+        }
+        pipeline::Runnable::Pipeline(pipeline) if run_args.dry_run => {
+            let reason = format!(
+                "Dry runs of pipelines like {} aren't supported",
+                pipeline.name
+            );
+            return Err(Error::new()
+                .attach_printable(reason.clone())
+                .attach(ErrorCode::BadRequest)
+                .attach(ErrorDetails(serde_json::json!({ "reason": reason })))
+                .into());
+        }
+        pipeline::Runnable::Command(command) => {
+            transforms.splice(0..0, command.metadata.transforms.iter().cloned());
+            match execute(
+                &db,
+                &settings,
+                &command,
+                &caller,
+                &peer,
+                args,
+                run_args.history,
+            )
+            .await?
+            {
+                Executed::Output(output) => {
+                    let secrets = output.secrets.clone();
+                    (
+                        output.stdout.clone(),
+                        axum::Json(output).into_response(),
+                        secrets,
+                    )
+                }
+                Executed::Cached(output) => {
+                    cached = true;
+                    let secrets = output.secrets.clone();
+                    (
+                        output.stdout.clone(),
+                        axum::Json(output).into_response(),
+                        secrets,
+                    )
+                }
+                Executed::Pending(approval) => {
+                    return Ok((http::StatusCode::ACCEPTED, axum::Json(approval)).into_response());
+                }
+            }
+        }
+        pipeline::Runnable::Pipeline(pipeline) => {
+            pipeline.authorize(&db, &settings, &caller, &peer).await?;
+            audit::Event::caller(&caller, &peer, audit::Action::Run)
+                .with_placeholders(&args)
+                .with_details(serde_json::json!({ "pipeline": pipeline.name }))
+                .record(&db)
+                .await?;
+            tracing::info!(
+                "Running pipeline {} for {} from {}",
+                pipeline.name,
+                caller,
+                peer.addr
+            );
+            let output = pipeline
+                .run(&db, &settings, &args, run_args.history)
+                .await?;
+            let stdout = output
+                .last()
+                .map(|output| output.stdout.clone())
+                .unwrap_or_default();
+            let secrets = output
+                .steps
+                .iter()
+                .flat_map(|step| step.output.secrets.clone())
+                .collect();
+            (stdout, axum::Json(output).into_response(), secrets)
+        }
+    };
+    let mut response = if run_args.full {
+        full
+    } else if !transforms.is_empty() {
+        axum::Json(transform::apply(&transforms, stdout)?).into_response()
     } else if run_args.json {
-        Ok(axum::Json(
-            serde_json::value::RawValue::from_string(output.stdout)
+        axum::Json(
+            serde_json::value::RawValue::from_string(stdout)
                 .change_context(Error)
-                .attach_printable("Failed to parse output.stdout as json")?,
+                .attach_printable("Failed to parse output.stdout as json")
+                .attach(ErrorCode::InvalidJsonOutput)?,
         )
-        .into_response())
+        .into_response()
     } else {
-        Ok(output.stdout.into_response())
+        stdout.into_response()
+    };
+    if cached {
+        response
+            .headers_mut()
+            .insert(CACHE_STATUS, http::HeaderValue::from_static("hit"));
+    }
+    response
+        .extensions_mut()
+        .insert(idempotency::Secrets(secrets));
+    Ok(response)
+}
+
+/// What asking to run a command led to.
+pub enum Executed {
+    Output(Output),
+    /// Output of an earlier run with the same values, the command didn't run
+    Cached(Output),
+    /// The command requires approval and waits for it
+    Pending(approval::Approval),
+}
+
+/// Runs `command` on behalf of `caller` once they are allowed to, saving the
+/// output to the history when `history` is set. Commands that require
+/// approval are only requested.
+pub async fn execute(
+    db: &sqlx::SqlitePool,
+    settings: &config::Settings,
+    command: &Command,
+    caller: &auth::Caller,
+    peer: &app::Peer,
+    args: BTreeMap<String, String>,
+    history: bool,
+) -> crate::Result<Executed> {
+    auth::authorize(db, command, caller).await?;
+    settings
+        .access
+        .check_command(&command.name, peer.addr.ip())?;
+    settings.limiter.check_command(&command.name)?;
+    if command.metadata.requires_approval {
+        let approval = approval::Approval::request(
+            db,
+            settings,
+            command,
+            &args,
+            audit::Event::caller(caller, peer, audit::Action::Request),
+        )
+        .await?;
+        return Ok(Executed::Pending(approval));
+    }
+    if let Some(output) = cache::get(command, &args) {
+        audit::Event::caller(caller, peer, audit::Action::Run)
+            .with_command(command)
+            .with_placeholders(&args)
+            .with_details(serde_json::json!({ "cached": true }))
+            .record(db)
+            .await?;
+        tracing::debug!("Using cached output of {} for {}", command.name, caller);
+        return Ok(Executed::Cached(output));
     }
+    audit::Event::caller(caller, peer, audit::Action::Run)
+        .with_command(command)
+        .with_placeholders(&args)
+        .record(db)
+        .await?;
+    tracing::info!(
+        "Running command {} for {} from {}",
+        command.name,
+        caller,
+        peer.addr
+    );
+    let output = command.run_with_placeholder(settings, args.clone()).await?;
+    if history {
+        output
+            .save(db, settings, command.id, &command::Origin::default())
+            .await?;
+    }
+    cache::put(command, &args, &output);
+    Ok(Executed::Output(output))
 }
 
+#[utoipa::path(
+    delete,
+    path = "/commands",
+    params(command::IdentifierQuery),
+    responses(
+        (status = 200),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
 pub async fn delete_identifier_command(
-    axum::extract::Query(id): axum::extract::Query<command::Identifier>,
+    Query(id): Query<command::Identifier>,
     Extension(db): Extension<sqlx::SqlitePool>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
 ) -> Result<()> {
+    caller.authenticated("delete commands")?;
     let command = Command::identifier(&db, id).await?;
+    auth::authorize(&db, &command, &caller).await?;
     command.delete(&db).await.change_context(Error)?;
+    audit::Event::caller(&caller, &peer, audit::Action::Delete)
+        .with_command(&command)
+        .record(&db)
+        .await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/commands/all",
+    responses(
+        (status = 200),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+    )
+)]
+pub async fn delete_all_commands(
+    Extension(db): Extension<sqlx::SqlitePool>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+) -> Result<()> {
+    caller.authenticated("delete commands")?;
+    for command in Command::list(&db).await? {
+        auth::authorize(&db, &command, &caller).await?;
+    }
+    Command::delete_all(&db).await?;
+    audit::Event::caller(&caller, &peer, audit::Action::Delete)
+        .with_details(serde_json::json!({ "all": true }))
+        .record(&db)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct Deleted {
+    /// Number of deleted commands
+    pub deleted: u64,
+}
+
+#[utoipa::path(
+    delete,
+    path = "/commands/tagged/{tag}",
+    params(("tag" = String, Path, description = "Tag of the commands to delete")),
+    responses(
+        (status = 200, body = Deleted),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+    )
+)]
+pub async fn delete_tagged_commands(
+    axum::extract::Path(tag): axum::extract::Path<String>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+) -> Result<axum::Json<Deleted>> {
+    caller.authenticated("delete commands")?;
+    for command in Command::filter(&db, None, Some(tag.clone())).await? {
+        auth::authorize(&db, &command, &caller).await?;
+    }
+    let deleted = Command::delete_tagged(&db, &tag).await?;
+    audit::Event::caller(&caller, &peer, audit::Action::Delete)
+        .with_details(serde_json::json!({ "tag": tag, "deleted": deleted }))
+        .record(&db)
+        .await?;
+    Ok(axum::Json(Deleted { deleted }))
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, utoipa::IntoParams)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryArgs {
+    /// Id of the command to show the history of
+    id: Option<uuid::Uuid>,
+    /// Exact name of the command to show the history of
+    name: Option<String>,
+    /// Substring of the name or program of the command to show the history of
+    like: Option<String>,
+    /// Maximum number of entries to return (default: 20)
+    limit: u32,
+}
+
+impl Default for HistoryArgs {
+    fn default() -> Self {
+        HistoryArgs {
+            id: None,
+            name: None,
+            like: None,
+            limit: 20,
+        }
+    }
+}
+
+impl HistoryArgs {
+    fn identifier(&self) -> Option<command::Identifier> {
+        if let Some(id) = self.id {
+            Some(command::Identifier::Id(id))
+        } else if let Some(name) = &self.name {
+            Some(command::Identifier::Name(name.clone()))
+        } else {
+            self.like.clone().map(command::Identifier::Like)
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/history",
+    params(HistoryArgs),
+    responses(
+        (status = 200, body = Vec<History>),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+pub async fn history(
+    Query(history_args): Query<HistoryArgs>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+    caller: auth::Caller,
+) -> Result<axum::Json<Vec<History>>> {
+    let command = match history_args.identifier() {
+        Some(identifier) => {
+            let command = Command::identifier(&db, identifier).await?;
+            auth::authorize(&db, &command, &caller).await?;
+            Some(command)
+        }
+        None => None,
+    };
+    Ok(axum::Json(
+        History::list(&db, command.as_ref(), Some(&caller), history_args.limit).await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/audit",
+    params(audit::Filter),
+    responses(
+        (status = 200, body = Vec<audit::Entry>),
+        (status = 401, body = ErrorResponse),
+        (status = 500, body = ErrorResponse)
+    )
+)]
+pub async fn audit_log(
+    Query(filter): Query<audit::Filter>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+    caller: auth::Caller,
+) -> Result<axum::Json<Vec<audit::Entry>>> {
+    let user = caller.authenticated("read the audit log")?;
+    Ok(axum::Json(audit::list(&db, &filter, Some(user)).await?))
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize, utoipa::IntoParams)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+pub struct ApprovalArgs {
+    /// Only list requests with this status
+    pub status: Option<approval::Status>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/approvals",
+    params(ApprovalArgs),
+    responses(
+        (status = 200, body = Vec<approval::Approval>, description = "Requests for commands the caller may run"),
+        (status = 401, body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+    )
+)]
+pub async fn list_approvals(
+    Query(args): Query<ApprovalArgs>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+) -> Result<axum::Json<Vec<approval::Approval>>> {
+    caller.authenticated("list approval requests")?;
+    let mut approvals = Vec::new();
+    for approval in approval::Approval::list(&db, args.status).await? {
+        let identifier = command::Identifier::Id(approval.command_id);
+        if runnable(&db, &settings, identifier, &caller, &peer)
+            .await
+            .is_ok()
+        {
+            approvals.push(approval);
+        }
+    }
+    Ok(axum::Json(approvals))
+}
+
+/// The approval request with `id`, and its command once `caller` may run it
+/// from `peer`.
+async fn decidable(
+    db: &sqlx::SqlitePool,
+    settings: &config::Settings,
+    id: uuid::Uuid,
+    caller: &auth::Caller,
+    peer: &app::Peer,
+) -> crate::Result<(approval::Approval, String)> {
+    let user = caller.authenticated("decide approval requests")?;
+    let approval = approval::Approval::id(db, id).await?;
+    let command = Command::identifier(db, command::Identifier::Id(approval.command_id)).await?;
+    auth::authorize(db, &command, caller).await?;
+    settings
+        .access
+        .check_command(&command.name, peer.addr.ip())?;
+    Ok((approval, user.username.clone()))
+}
+
+/// The command with `identifier` once `caller` may run it from `peer`, since
+/// scheduling it, adding a webhook for it, using it in a pipeline or seeing
+/// its approval requests needs the same permission.
+async fn runnable(
+    db: &sqlx::SqlitePool,
+    settings: &config::Settings,
+    identifier: command::Identifier,
+    caller: &auth::Caller,
+    peer: &app::Peer,
+) -> crate::Result<Command> {
+    caller.authenticated("manage schedules, webhooks and pipelines")?;
+    let command = Command::identifier(db, identifier).await?;
+    auth::authorize(db, &command, caller).await?;
+    settings
+        .access
+        .check_command(&command.name, peer.addr.ip())?;
+    Ok(command)
+}
+
+/// Approves a pending run and runs the command, responding with its output.
+#[utoipa::path(
+    post,
+    path = "/approvals/{id}/approve",
+    params(("id" = uuid::Uuid, Path, description = "Id of the approval request")),
+    responses(
+        (status = 200, body = Output),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+    )
+)]
+pub async fn approve(
+    axum::extract::Path(id): axum::extract::Path<uuid::Uuid>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+) -> Result<axum::Json<Output>> {
+    let (approval, username) = decidable(&db, &settings, id, &caller, &peer).await?;
+    Ok(axum::Json(
+        approval
+            .approve(
+                &db,
+                &settings,
+                &username,
+                audit::Event::caller(&caller, &peer, audit::Action::Approve),
+            )
+            .await?,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/approvals/{id}/reject",
+    params(("id" = uuid::Uuid, Path, description = "Id of the approval request")),
+    responses(
+        (status = 200, body = approval::Approval),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+    )
+)]
+pub async fn reject(
+    axum::extract::Path(id): axum::extract::Path<uuid::Uuid>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+) -> Result<axum::Json<approval::Approval>> {
+    let (approval, username) = decidable(&db, &settings, id, &caller, &peer).await?;
+    Ok(axum::Json(
+        approval
+            .reject(
+                &db,
+                &username,
+                audit::Event::caller(&caller, &peer, audit::Action::Reject),
+            )
+            .await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/pipelines",
+    responses(
+        (status = 200, body = Vec<pipeline::Pipeline>, description = "Pipelines whose steps the caller may run"),
+        (status = 401, body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+    )
+)]
+pub async fn list_pipelines(
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+) -> Result<axum::Json<Vec<pipeline::Pipeline>>> {
+    caller.authenticated("list pipelines")?;
+    let mut pipelines = Vec::new();
+    for pipeline in pipeline::Pipeline::list(&db).await? {
+        if pipeline
+            .authorize(&db, &settings, &caller, &peer)
+            .await
+            .is_ok()
+        {
+            pipelines.push(pipeline);
+        }
+    }
+    Ok(axum::Json(pipelines))
+}
+
+#[utoipa::path(
+    post,
+    path = "/pipelines",
+    request_body = pipeline::NewPipeline,
+    responses(
+        (status = 200, body = pipeline::Pipeline),
+        (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, body = ErrorResponse),
+    )
+)]
+pub async fn add_pipeline(
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+    Json(new): Json<pipeline::NewPipeline>,
+) -> Result<axum::Json<pipeline::Pipeline>> {
+    caller.authenticated("add pipelines")?;
+    for step in &new.steps {
+        runnable(&db, &settings, step.identifier()?, &caller, &peer).await?;
+    }
+    Ok(axum::Json(pipeline::Pipeline::add(&db, new).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/pipelines",
+    params(command::IdentifierQuery),
+    responses(
+        (status = 200),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+pub async fn delete_pipeline(
+    Query(identifier): Query<command::Identifier>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+) -> Result<()> {
+    caller.authenticated("delete pipelines")?;
+    let pipeline = pipeline::Pipeline::identifier(&db, &identifier).await?;
+    pipeline.authorize(&db, &settings, &caller, &peer).await?;
+    pipeline.delete(&db).await?;
     Ok(())
 }
+
+#[utoipa::path(
+    get,
+    path = "/schedules",
+    responses(
+        (status = 200, body = Vec<schedule::Schedule>, description = "Schedules of the commands the caller may run"),
+        (status = 401, body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+    )
+)]
+pub async fn list_schedules(
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+) -> Result<axum::Json<Vec<schedule::Schedule>>> {
+    caller.authenticated("list schedules")?;
+    let mut schedules = Vec::new();
+    for schedule in schedule::Schedule::list(&db).await? {
+        let identifier = command::Identifier::Id(schedule.command_id);
+        if runnable(&db, &settings, identifier, &caller, &peer)
+            .await
+            .is_ok()
+        {
+            schedules.push(schedule);
+        }
+    }
+    Ok(axum::Json(schedules))
+}
+
+#[utoipa::path(
+    post,
+    path = "/schedules",
+    request_body = schedule::NewSchedule,
+    responses(
+        (status = 200, body = schedule::Schedule),
+        (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+pub async fn add_schedule(
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+    Json(new): Json<schedule::NewSchedule>,
+) -> Result<axum::Json<schedule::Schedule>> {
+    runnable(&db, &settings, new.identifier()?, &caller, &peer).await?;
+    Ok(axum::Json(schedule::Schedule::add(&db, new).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/schedules/{id}",
+    params(("id" = uuid::Uuid, Path, description = "Id of the schedule")),
+    responses(
+        (status = 200),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+pub async fn delete_schedule(
+    axum::extract::Path(id): axum::extract::Path<uuid::Uuid>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+) -> Result<()> {
+    let schedule = schedule::Schedule::id(&db, id).await?;
+    let identifier = command::Identifier::Id(schedule.command_id);
+    runnable(&db, &settings, identifier, &caller, &peer).await?;
+    schedule.delete(&db).await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/schedules/{id}/pause",
+    params(("id" = uuid::Uuid, Path, description = "Id of the schedule")),
+    responses(
+        (status = 200, body = schedule::Schedule),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+pub async fn pause_schedule(
+    axum::extract::Path(id): axum::extract::Path<uuid::Uuid>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+) -> Result<axum::Json<schedule::Schedule>> {
+    let schedule = schedule::Schedule::id(&db, id).await?;
+    let identifier = command::Identifier::Id(schedule.command_id);
+    runnable(&db, &settings, identifier, &caller, &peer).await?;
+    Ok(axum::Json(schedule.set_paused(&db, true).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/schedules/{id}/resume",
+    params(("id" = uuid::Uuid, Path, description = "Id of the schedule")),
+    responses(
+        (status = 200, body = schedule::Schedule),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+pub async fn resume_schedule(
+    axum::extract::Path(id): axum::extract::Path<uuid::Uuid>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+) -> Result<axum::Json<schedule::Schedule>> {
+    let schedule = schedule::Schedule::id(&db, id).await?;
+    let identifier = command::Identifier::Id(schedule.command_id);
+    runnable(&db, &settings, identifier, &caller, &peer).await?;
+    Ok(axum::Json(schedule.set_paused(&db, false).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/webhooks",
+    responses(
+        (status = 200, body = Vec<webhook::Webhook>, description = "Webhooks of the commands the caller may run"),
+        (status = 401, body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+    )
+)]
+pub async fn list_webhooks(
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+) -> Result<axum::Json<Vec<webhook::Webhook>>> {
+    caller.authenticated("list webhooks")?;
+    let mut webhooks = Vec::new();
+    for webhook in webhook::Webhook::list(&db).await? {
+        let identifier = command::Identifier::Id(webhook.command_id);
+        if runnable(&db, &settings, identifier, &caller, &peer)
+            .await
+            .is_ok()
+        {
+            webhooks.push(webhook);
+        }
+    }
+    Ok(axum::Json(webhooks))
+}
+
+#[utoipa::path(
+    post,
+    path = "/webhooks",
+    request_body = webhook::NewWebhook,
+    responses(
+        (status = 200, body = webhook::Webhook, description = "The webhook with its secret"),
+        (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+pub async fn add_webhook(
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+    Json(new): Json<webhook::NewWebhook>,
+) -> Result<axum::Json<webhook::Webhook>> {
+    runnable(&db, &settings, new.identifier()?, &caller, &peer).await?;
+    Ok(axum::Json(webhook::Webhook::add(&db, new).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/webhooks/{id}",
+    params(("id" = uuid::Uuid, Path, description = "Id of the webhook")),
+    responses(
+        (status = 200),
+        (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+pub async fn delete_webhook(
+    axum::extract::Path(id): axum::extract::Path<uuid::Uuid>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    caller: auth::Caller,
+) -> Result<()> {
+    let webhook = webhook::Webhook::id(&db, id).await?;
+    let identifier = command::Identifier::Id(webhook.command_id);
+    runnable(&db, &settings, identifier, &caller, &peer).await?;
+    webhook.delete(&db).await?;
+    Ok(())
+}
+
+/// Runs the webhook's command in the background once the signature of the
+/// payload checks out.
+#[utoipa::path(
+    post,
+    path = "/hooks/{id}",
+    params(
+        ("id" = uuid::Uuid, Path, description = "Id of the webhook"),
+        ("X-Hub-Signature-256" = Option<String>, Header, description = "sha256=HMAC of the body, or X-Gitea-Signature without the prefix"),
+    ),
+    request_body(content = serde_json::Value, description = "Payload the placeholders are picked from"),
+    responses(
+        (status = 202, description = "The command was started, or waits for approval"),
+        (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+pub async fn trigger_webhook(
+    axum::extract::Path(id): axum::extract::Path<uuid::Uuid>,
+    axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<app::Peer>,
+    Extension(db): Extension<sqlx::SqlitePool>,
+    Extension(settings): Extension<config::Settings>,
+    headers: http::HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<http::StatusCode> {
+    webhook::Webhook::id(&db, id)
+        .await?
+        .trigger(&db, &settings, &headers, &body, &peer)
+        .await?;
+    Ok(http::StatusCode::ACCEPTED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_local_path() {
+        assert!(is_local_path("/ui/command?name=a"));
+        assert!(is_local_path("/"));
+        assert!(!is_local_path("https://evil.example"));
+        assert!(!is_local_path("//evil.example"));
+        assert!(!is_local_path("/\\evil.example"));
+        assert!(!is_local_path("ui"));
+    }
+}
@@ -0,0 +1,328 @@
+//! Schedules run a command with fixed placeholder values at the times given
+//! by a cron expression, from a task running next to the server.
+
+use crate::{
+    command::{Command, Identifier, Origin, Trigger, UuidWrapper},
+    *,
+};
+use chrono::{DateTime, Utc};
+use sqlx::SqlitePool;
+use std::{collections::BTreeMap, str::FromStr, sync::LazyLock, time::Duration};
+
+/// Wakes the scheduler when schedules change, so it doesn't sleep past a run
+/// of a schedule added in the meantime.
+static CHANGED: LazyLock<tokio::sync::Notify> = LazyLock::new(tokio::sync::Notify::new);
+
+/// The scheduler looks at the database at least this often, to notice
+/// schedules changed by another process.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+const SCHEDULE_COLUMNS: &str = "s.id, s.command_id, c.name AS command, s.cron, s.timezone, \
+    s.placeholders, s.paused, s.last_run_at";
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, sqlx::FromRow, utoipa::ToSchema)]
+pub struct Schedule {
+    #[sqlx(try_from = "UuidWrapper")]
+    pub id: uuid::Uuid,
+    #[sqlx(try_from = "UuidWrapper")]
+    pub command_id: uuid::Uuid,
+    /// Name of the command
+    pub command: String,
+    /// Cron expression, with an optional leading seconds field
+    pub cron: String,
+    /// IANA name of the timezone the cron expression is evaluated in
+    pub timezone: String,
+    #[sqlx(json)]
+    pub placeholders: BTreeMap<String, String>,
+    pub paused: bool,
+    pub last_run_at: Option<String>,
+    /// When the schedule runs next, none while paused
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub next_run: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct NewSchedule {
+    /// Id of the command to run
+    #[serde(default)]
+    pub command_id: Option<uuid::Uuid>,
+    /// Name of the command to run, when no id is given
+    #[serde(default)]
+    pub command: Option<String>,
+    pub cron: String,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    #[serde(default)]
+    pub placeholders: BTreeMap<String, String>,
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+/// A parsed cron expression and timezone.
+struct When {
+    cron: cron::Schedule,
+    timezone: chrono_tz::Tz,
+}
+
+impl When {
+    fn parse(cron: &str, timezone: &str) -> Result<When> {
+        let invalid = |reason: String| {
+            Error::new()
+                .attach_printable(reason.clone())
+                .attach(ErrorCode::BadRequest)
+                .attach(ErrorDetails(serde_json::json!({ "reason": reason })))
+        };
+        // The cron crate wants seconds, plain five field expressions run at
+        // the start of the minute
+        let expression = match cron.split_whitespace().count() {
+            5 => format!("0 {cron}"),
+            _ => cron.to_string(),
+        };
+        Ok(When {
+            cron: cron::Schedule::from_str(&expression)
+                .map_err(|e| invalid(format!("Invalid cron expression {cron}: {e}")))?,
+            timezone: timezone
+                .parse()
+                .map_err(|_| invalid(format!("Unknown timezone: {timezone}")))?,
+        })
+    }
+
+    /// The first run strictly after `after`.
+    fn next(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.cron
+            .after(&after.with_timezone(&self.timezone))
+            .next()
+            .map(|next| next.with_timezone(&Utc))
+    }
+}
+
+impl NewSchedule {
+    /// The command to run.
+    pub fn identifier(&self) -> Result<Identifier> {
+        match (self.command_id, &self.command) {
+            (Some(id), _) => Ok(Identifier::Id(id)),
+            (None, Some(name)) => Ok(Identifier::Name(name.clone())),
+            (None, None) => Err(Error::new()
+                .attach_printable("Schedule without a command")
+                .attach(ErrorCode::BadRequest)
+                .attach(ErrorDetails(serde_json::json!({
+                    "reason": "A schedule needs a command_id or command"
+                })))),
+        }
+    }
+}
+
+impl Schedule {
+    fn when(&self) -> Result<When> {
+        When::parse(&self.cron, &self.timezone)
+    }
+
+    fn with_next_run(mut self) -> Schedule {
+        self.next_run = match (self.paused, self.when()) {
+            (false, Ok(when)) => when
+                .next(Utc::now())
+                .map(|next| next.with_timezone(&when.timezone).to_rfc3339()),
+            _ => None,
+        };
+        self
+    }
+
+    pub async fn add(database: &SqlitePool, new: NewSchedule) -> Result<Schedule> {
+        When::parse(&new.cron, &new.timezone)?;
+        let command = Command::identifier(database, new.identifier()?).await?;
+        let id = uuid::Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO schedules (id, command_id, cron, timezone, placeholders)
+            VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(id.as_simple())
+        .bind(command.id.as_simple())
+        .bind(&new.cron)
+        .bind(&new.timezone)
+        .bind(sqlx::types::Json(&new.placeholders))
+        .execute(database)
+        .await
+        .change_context(Error)
+        .attach_printable(format!("Failed to add schedule for: {}", command.name))
+        .attach(ErrorCode::Database)?;
+        CHANGED.notify_one();
+        Self::id(database, id).await
+    }
+
+    pub async fn list(database: &SqlitePool) -> Result<Vec<Schedule>> {
+        let schedules: Vec<Schedule> = sqlx::query_as(&format!(
+            "SELECT {SCHEDULE_COLUMNS} FROM schedules s JOIN commands c ON c.id = s.command_id
+            ORDER BY c.name, s.created_at"
+        ))
+        .fetch_all(database)
+        .await
+        .change_context(Error)
+        .attach_printable("Failed to list schedules")
+        .attach(ErrorCode::Database)?;
+        Ok(schedules.into_iter().map(Schedule::with_next_run).collect())
+    }
+
+    pub async fn id(database: &SqlitePool, id: uuid::Uuid) -> Result<Schedule> {
+        let schedule: Option<Schedule> = sqlx::query_as(&format!(
+            "SELECT {SCHEDULE_COLUMNS} FROM schedules s JOIN commands c ON c.id = s.command_id
+            WHERE s.id = ?"
+        ))
+        .bind(id.as_simple())
+        .fetch_optional(database)
+        .await
+        .change_context(Error)
+        .attach_printable(format!("Failed to query schedule: {id}"))
+        .attach(ErrorCode::Database)?;
+        schedule.map(Schedule::with_next_run).ok_or_else(|| {
+            Error::new()
+                .attach_printable(format!("No schedule found with id: {id}"))
+                .attach(ErrorCode::ScheduleNotFound)
+                .attach(ErrorDetails(serde_json::json!({ "id": id })))
+        })
+    }
+
+    /// Pauses or resumes the schedule.
+    pub async fn set_paused(self, database: &SqlitePool, paused: bool) -> Result<Schedule> {
+        sqlx::query("UPDATE schedules SET paused = ? WHERE id = ?")
+            .bind(paused)
+            .bind(self.id.as_simple())
+            .execute(database)
+            .await
+            .change_context(Error)
+            .attach_printable(format!("Failed to update schedule: {}", self.id))
+            .attach(ErrorCode::Database)?;
+        CHANGED.notify_one();
+        Self::id(database, self.id).await
+    }
+
+    pub async fn delete(&self, database: &SqlitePool) -> Result<()> {
+        sqlx::query("DELETE FROM schedules WHERE id = ?")
+            .bind(self.id.as_simple())
+            .execute(database)
+            .await
+            .change_context(Error)
+            .attach_printable(format!("Failed to delete schedule: {}", self.id))
+            .attach(ErrorCode::Database)?;
+        CHANGED.notify_one();
+        Ok(())
+    }
+
+    /// Runs the command and saves the output to the history, or asks for
+    /// approval of the run when the command requires it.
+    async fn run(&self, database: &SqlitePool, settings: &config::Settings) -> Result<()> {
+        sqlx::query("UPDATE schedules SET last_run_at = CURRENT_TIMESTAMP WHERE id = ?")
+            .bind(self.id.as_simple())
+            .execute(database)
+            .await
+            .change_context(Error)
+            .attach_printable(format!("Failed to update schedule: {}", self.id))
+            .attach(ErrorCode::Database)?;
+        let command = Command::identifier(database, Identifier::Id(self.command_id)).await?;
+        let event =
+            |action| audit::Event::new(audit::Actor::Scheduler, Some(self.id.to_string()), action);
+        if command.metadata.requires_approval {
+            approval::Approval::request(
+                database,
+                settings,
+                &command,
+                &self.placeholders,
+                event(audit::Action::Request),
+            )
+            .await?;
+            return Ok(());
+        }
+        event(audit::Action::Run)
+            .with_command(&command)
+            .with_placeholders(&self.placeholders)
+            .record(database)
+            .await?;
+        let output = command
+            .run_with_placeholder(settings, self.placeholders.clone())
+            .await?;
+        output
+            .save(
+                database,
+                settings,
+                command.id,
+                &Origin {
+                    trigger: Trigger::Scheduled,
+                    schedule: Some(self.id),
+                    ..Origin::default()
+                },
+            )
+            .await
+    }
+}
+
+/// Runs schedules as they come due, until the process exits. Runs missed
+/// while the server was down are not caught up on.
+pub async fn run(database: SqlitePool, settings: config::Settings) {
+    let mut checked = Utc::now();
+    loop {
+        let schedules = match Schedule::list(&database).await {
+            Ok(schedules) => schedules,
+            Err(e) => {
+                tracing::error!("Failed to load schedules: {e:?}");
+                Vec::new()
+            }
+        };
+        let now = Utc::now();
+        let mut wake = now + MAX_SLEEP;
+        for schedule in schedules.into_iter().filter(|schedule| !schedule.paused) {
+            let Ok(when) = schedule.when() else {
+                continue;
+            };
+            if when.next(checked).is_some_and(|next| next <= now) {
+                tracing::info!(
+                    "Running schedule {} of command {}",
+                    schedule.id,
+                    schedule.command
+                );
+                let database = database.clone();
+                let settings = settings.clone();
+                let schedule = schedule.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = schedule.run(&database, &settings).await {
+                        tracing::error!("Scheduled run of {} failed: {e:?}", schedule.command);
+                    }
+                });
+            }
+            if let Some(next) = when.next(now) {
+                wake = wake.min(next);
+            }
+        }
+        checked = now;
+        let sleep = (wake - Utc::now()).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(sleep) => {}
+            _ = CHANGED.notified() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_run() {
+        let start = DateTime::parse_from_rfc3339("2025-01-01T12:00:30Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let when = When::parse("*/15 * * * *", "UTC").unwrap();
+        assert_eq!(
+            when.next(start).unwrap().to_rfc3339(),
+            "2025-01-01T12:15:00+00:00"
+        );
+        let when = When::parse("0 8 * * *", "Europe/Berlin").unwrap();
+        assert_eq!(
+            when.next(start).unwrap().to_rfc3339(),
+            "2025-01-02T07:00:00+00:00"
+        );
+        assert!(When::parse("not cron", "UTC").is_err());
+        assert!(When::parse("* * * * *", "Mars/Olympus").is_err());
+    }
+}
@@ -0,0 +1,90 @@
+//! Values of secret placeholders read from the environment or files named in
+//! the config, and masking of secret values in saved output and messages.
+
+use crate::{command::Command, *};
+use std::{collections::BTreeMap, path::PathBuf};
+
+/// Shown instead of a secret value.
+pub const MASK: &str = "****";
+
+/// Sources of secret values, keyed by `command.placeholder` or just
+/// `placeholder`.
+pub type Sources = BTreeMap<String, Source>;
+
+/// Where the value of a secret placeholder comes from.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// An environment variable of the server
+    Env(String),
+    /// A file, without its trailing newline
+    File(PathBuf),
+}
+
+impl Source {
+    fn read(&self, name: &str) -> Result<String> {
+        match self {
+            Source::Env(variable) => std::env::var(variable)
+                .change_context(Error)
+                .attach_printable(format!(
+                    "Secret {name} is missing, environment variable {variable} is not set"
+                ))
+                .attach(ErrorCode::PlaceholderMissing)
+                .attach(ErrorDetails(serde_json::json!({ "placeholder": name }))),
+            Source::File(path) => std::fs::read_to_string(path)
+                .map(|value| value.trim_end_matches(['\r', '\n']).to_owned())
+                .change_context(Error)
+                .attach_printable(format!(
+                    "Secret {name} is missing, failed to read {}",
+                    path.display()
+                ))
+                .attach(ErrorCode::PlaceholderMissing)
+                .attach(ErrorDetails(serde_json::json!({ "placeholder": name }))),
+        }
+    }
+}
+
+/// Fills in the secret placeholders of `command` that have a source in
+/// `sources`, replacing any value given with the request.
+pub fn fill(
+    sources: &Sources,
+    command: &Command,
+    values: &mut BTreeMap<String, String>,
+) -> Result<()> {
+    for name in &command.metadata.secret_placeholders {
+        let name = command::placeholder_name(name);
+        let Some(source) = sources
+            .get(&format!("{}.{name}", command.name))
+            .or_else(|| sources.get(name))
+        else {
+            continue;
+        };
+        let value = source.read(name)?;
+        values.retain(|key, _| command::placeholder_name(key) != name);
+        values.insert(name.to_owned(), value);
+    }
+    Ok(())
+}
+
+/// `text` with every secret value replaced by [`MASK`].
+pub fn mask(text: &str, secrets: &[String]) -> String {
+    secrets
+        .iter()
+        .filter(|secret| !secret.is_empty())
+        .fold(text.to_owned(), |text, secret| text.replace(secret, MASK))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask() {
+        let secrets = vec!["hunter2".to_owned(), String::new()];
+        assert_eq!(
+            mask("login with hunter2 failed", &secrets),
+            "login with **** failed"
+        );
+        assert_eq!(mask("nothing secret", &secrets), "nothing secret");
+    }
+}
@@ -0,0 +1,132 @@
+//! Prometheus metrics about requests, command runs and the database, served
+//! at `/metrics`.
+
+use crate::{command::Output, *};
+use axum::extract::MatchedPath;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use sqlx::SqlitePool;
+use std::{sync::OnceLock, time::Instant};
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+const REQUEST_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+const RUN_BUCKETS: &[f64] = &[0.01, 0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 1800.0];
+
+/// Installs the recorder, before that metrics are dropped.
+pub fn install() -> Result<()> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("command_runner_http_request_duration_seconds".into()),
+            REQUEST_BUCKETS,
+        )
+        .and_then(|builder| {
+            builder.set_buckets_for_metric(
+                Matcher::Full("command_runner_command_duration_seconds".into()),
+                RUN_BUCKETS,
+            )
+        })
+        .change_context(Error)?
+        .install_recorder()
+        .change_context(Error)
+        .attach_printable("Failed to install the metrics recorder")?;
+    metrics::describe_counter!(
+        "command_runner_http_requests_total",
+        "Requests by route and status"
+    );
+    metrics::describe_histogram!(
+        "command_runner_http_request_duration_seconds",
+        metrics::Unit::Seconds,
+        "Time taken to answer requests"
+    );
+    metrics::describe_counter!("command_runner_command_runs_total", "Runs of each command");
+    metrics::describe_counter!(
+        "command_runner_command_failures_total",
+        "Runs that failed or couldn't be started"
+    );
+    metrics::describe_histogram!(
+        "command_runner_command_duration_seconds",
+        metrics::Unit::Seconds,
+        "Time commands ran for"
+    );
+    metrics::describe_gauge!(
+        "command_runner_command_running",
+        "Processes of each command running right now"
+    );
+    metrics::describe_gauge!("command_runner_history_rows", "Rows in the history table");
+    metrics::describe_gauge!(
+        "command_runner_database_connections",
+        "Open database connections"
+    );
+    metrics::describe_gauge!(
+        "command_runner_database_idle_connections",
+        "Open database connections not in use"
+    );
+    let _ = HANDLE.set(handle);
+    Ok(())
+}
+
+/// Records a run of `command`.
+pub async fn track_run(command: &str, run: impl Future<Output = Result<Output>>) -> Result<Output> {
+    let command = command.to_owned();
+    let running = metrics::gauge!("command_runner_command_running", "command" => command.clone());
+    running.increment(1);
+    let start = Instant::now();
+    let output = run.await;
+    running.decrement(1);
+    metrics::histogram!("command_runner_command_duration_seconds", "command" => command.clone())
+        .record(start.elapsed());
+    metrics::counter!("command_runner_command_runs_total", "command" => command.clone())
+        .increment(1);
+    if !output.as_ref().is_ok_and(|output| output.status.success()) {
+        metrics::counter!("command_runner_command_failures_total", "command" => command)
+            .increment(1);
+    }
+    output
+}
+
+/// Middleware counting requests by the route they matched.
+pub async fn track_request(
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_owned();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    metrics::histogram!(
+        "command_runner_http_request_duration_seconds",
+        "method" => method.clone(),
+        "route" => route.clone()
+    )
+    .record(start.elapsed());
+    metrics::counter!(
+        "command_runner_http_requests_total",
+        "method" => method,
+        "route" => route,
+        "status" => response.status().as_u16().to_string()
+    )
+    .increment(1);
+    response
+}
+
+/// The metrics in the Prometheus text format, with the database gauges
+/// measured now.
+pub async fn render(database: &SqlitePool) -> Result<String> {
+    let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM history")
+        .fetch_one(database)
+        .await
+        .change_context(Error)
+        .attach_printable("Failed to count history rows")
+        .attach(ErrorCode::Database)?;
+    metrics::gauge!("command_runner_history_rows").set(rows as f64);
+    metrics::gauge!("command_runner_database_connections").set(database.size());
+    metrics::gauge!("command_runner_database_idle_connections").set(database.num_idle() as f64);
+    Ok(HANDLE
+        .get()
+        .map(PrometheusHandle::render)
+        .unwrap_or_default())
+}
@@ -0,0 +1,247 @@
+use crate::*;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+use tokio_rustls::rustls::{
+    self, RootCertStore,
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+};
+
+/// How often the certificate files are checked for changes on disk.
+const RELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+/// How long a client may take to complete the handshake before it's dropped.
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA used to verify client certificates. Clients without a certificate
+    /// are still accepted and fall back to the other authentication methods.
+    pub client_ca: Option<PathBuf>,
+}
+
+/// Serves the most recently loaded certificate and swaps it out whenever the
+/// certificate or key file is modified.
+#[derive(Debug)]
+pub struct ReloadingResolver {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|key| key.clone())
+    }
+}
+
+impl ReloadingResolver {
+    pub fn new(config: TlsConfig, provider: Arc<CryptoProvider>) -> Result<Self> {
+        let current = load_certified_key(&config, &provider)?;
+        Ok(Self {
+            config,
+            provider,
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    fn reload(&self) -> Result<()> {
+        let key = load_certified_key(&self.config, &self.provider)?;
+        *self
+            .current
+            .write()
+            .map_err(|_| Error::new().attach_printable("TLS certificate lock was poisoned"))? =
+            Arc::new(key);
+        Ok(())
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(&self.config.cert)?, modified(&self.config.key)?))
+    }
+
+    /// Polls the certificate and key files and reloads them when either one
+    /// changes. A failed reload keeps serving the previous certificate.
+    pub async fn watch(self: Arc<Self>) {
+        let mut last = self.modified();
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let modified = self.modified();
+            if modified.is_none() || modified == last {
+                continue;
+            }
+            match self.reload() {
+                Ok(()) => {
+                    tracing::info!(
+                        "Reloaded TLS certificate from {}",
+                        self.config.cert.display()
+                    );
+                    last = modified;
+                }
+                Err(e) => tracing::error!("Failed to reload TLS certificate: {:?}", e),
+            }
+        }
+    }
+}
+
+fn load_certified_key(config: &TlsConfig, provider: &CryptoProvider) -> Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(&config.cert)
+        .change_context(Error)
+        .attach_printable_lazy(|| format!("Failed to read certificate: {}", config.cert.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .change_context(Error)
+        .attach_printable_lazy(|| {
+            format!("Failed to parse certificate: {}", config.cert.display())
+        })?;
+    if certs.is_empty() {
+        return Err(Error::new().attach_printable(format!(
+            "No certificates found in {}",
+            config.cert.display()
+        )));
+    }
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .change_context(Error)
+        .attach_printable_lazy(|| {
+            format!("Failed to read private key: {}", config.key.display())
+        })?;
+    CertifiedKey::from_der(certs, key, provider)
+        .change_context(Error)
+        .attach_printable("Certificate and private key do not match")
+}
+
+fn load_client_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(path)
+        .change_context(Error)
+        .attach_printable_lazy(|| format!("Failed to read client CA: {}", path.display()))?
+    {
+        let cert = cert
+            .change_context(Error)
+            .attach_printable_lazy(|| format!("Failed to parse client CA: {}", path.display()))?;
+        roots
+            .add(cert)
+            .change_context(Error)
+            .attach_printable_lazy(|| format!("Invalid client CA: {}", path.display()))?;
+    }
+    if roots.is_empty() {
+        return Err(
+            Error::new().attach_printable(format!("No certificates found in {}", path.display()))
+        );
+    }
+    Ok(roots)
+}
+
+pub fn server_config(resolver: Arc<ReloadingResolver>) -> Result<Arc<rustls::ServerConfig>> {
+    let builder = rustls::ServerConfig::builder_with_provider(resolver.provider.clone())
+        .with_safe_default_protocol_versions()
+        .change_context(Error)?;
+    let builder = match &resolver.config.client_ca {
+        Some(client_ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(load_client_roots(client_ca)?),
+                resolver.provider.clone(),
+            )
+            .allow_unauthenticated()
+            .build()
+            .change_context(Error)
+            .attach_printable("Failed to build client certificate verifier")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Returns the common name of a client certificate's subject, which is what
+/// gets mapped onto a username.
+pub fn certificate_subject(cert: &CertificateDer<'_>) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+    cert.subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(ToOwned::to_owned)
+}
+
+pub fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// A listener that terminates TLS before handing connections to axum.
+///
+/// Handshakes are driven on their own tasks so that a slow or misbehaving
+/// client can't hold up the accept loop, and are given up after
+/// [`HANDSHAKE_TIMEOUT`] so that idle clients can't hold on to them.
+pub struct TlsListener {
+    local_addr: std::net::SocketAddr,
+    incoming: tokio::sync::mpsc::Receiver<(
+        tokio_rustls::server::TlsStream<tokio::net::TcpStream>,
+        std::net::SocketAddr,
+    )>,
+}
+
+impl TlsListener {
+    pub fn new(
+        listener: tokio::net::TcpListener,
+        config: Arc<rustls::ServerConfig>,
+    ) -> Result<Self> {
+        let local_addr = listener.local_addr().change_context(Error)?;
+        let acceptor = tokio_rustls::TlsAcceptor::from(config);
+        let (sender, incoming) = tokio::sync::mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::error!("Failed to accept connection: {}", e);
+                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send((stream, addr)).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!("TLS handshake with {} failed: {}", addr, e),
+                        Err(_) => tracing::debug!("TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            local_addr,
+            incoming,
+        })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = tokio_rustls::server::TlsStream<tokio::net::TcpStream>;
+    type Addr = std::net::SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.incoming.recv().await {
+            Some(accepted) => accepted,
+            // The accept loop never exits on its own, so this only happens
+            // during shutdown.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
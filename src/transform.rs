@@ -0,0 +1,282 @@
+//! Transforms turning the stdout of a run into json, for responses like
+//! `{"brightness": 42}` instead of raw text. Transforms are written as
+//! `trim`, `lines`, `json`, `path:.monitors[0].name` or `regex:PATTERN`, and
+//! chained with ` | `. Paths are those of [`json_path`].
+
+use crate::{json_path::Segment, *};
+use regex::Regex;
+use serde_json::Value;
+
+const SEPARATOR: &str = " | ";
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Transform {
+    /// Strips surrounding whitespace
+    Trim,
+    /// Splits text into an array of its non-empty lines
+    Lines,
+    /// Parses text as json
+    Json,
+    /// Picks values out of json, parsing text first
+    Path(String, Vec<Segment>),
+    /// Turns a match into an object of its named groups, or else into its
+    /// first group or the whole match. Arrays keep only the elements matching.
+    Regex(Regex),
+}
+
+fn invalid(transform: &str, reason: impl std::fmt::Display) -> Report<Error> {
+    Error::new()
+        .attach_printable(format!("Invalid transform {transform:?}: {reason}"))
+        .attach(ErrorCode::BadRequest)
+        .attach(ErrorDetails(serde_json::json!({
+            "transform": transform,
+            "reason": reason.to_string(),
+        })))
+}
+
+fn failed(transform: &Transform, reason: impl std::fmt::Display) -> Report<Error> {
+    Error::new()
+        .attach_printable(format!("Transform {transform} failed: {reason}"))
+        .attach(ErrorCode::TransformFailed)
+        .attach(ErrorDetails(serde_json::json!({
+            "transform": transform.to_string(),
+            "reason": reason.to_string(),
+        })))
+}
+
+impl std::str::FromStr for Transform {
+    type Err = Report<Error>;
+
+    fn from_str(transform: &str) -> Result<Self> {
+        let (name, argument) = match transform.split_once(':') {
+            Some((name, argument)) => (name, Some(argument)),
+            None => (transform, None),
+        };
+        match (name.trim(), argument) {
+            ("trim", None) => Ok(Transform::Trim),
+            ("lines", None) => Ok(Transform::Lines),
+            ("json", None) => Ok(Transform::Json),
+            ("path", Some(path)) => json_path::parse(path)
+                .map(|segments| Transform::Path(path.to_string(), segments))
+                .map_err(|reason| invalid(transform, reason)),
+            ("regex", Some(pattern)) => Regex::new(pattern)
+                .map(Transform::Regex)
+                .map_err(|e| invalid(transform, e)),
+            _ => Err(invalid(
+                transform,
+                "expected trim, lines, json, path:PATH or regex:PATTERN",
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Transform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transform::Trim => write!(f, "trim"),
+            Transform::Lines => write!(f, "lines"),
+            Transform::Json => write!(f, "json"),
+            Transform::Path(path, _) => write!(f, "path:{path}"),
+            Transform::Regex(regex) => write!(f, "regex:{}", regex.as_str()),
+        }
+    }
+}
+
+impl TryFrom<String> for Transform {
+    type Error = String;
+
+    fn try_from(transform: String) -> Result<Self, String> {
+        transform.parse().map_err(|e: Report<Error>| {
+            e.downcast_ref::<ErrorDetails>()
+                .and_then(|details| details.0["reason"].as_str())
+                .unwrap_or("invalid transform")
+                .to_string()
+        })
+    }
+}
+
+impl From<Transform> for String {
+    fn from(transform: Transform) -> String {
+        transform.to_string()
+    }
+}
+
+/// Parses transforms chained with ` | `, e.g. `lines | trim`. There is no
+/// escaping, so a regex alternative with spaces around it has to be written
+/// differently, like `a |[ ]b` instead of `a | b`.
+pub fn parse(transforms: &str) -> Result<Vec<Transform>> {
+    transforms
+        .split(SEPARATOR)
+        .filter(|transform| !transform.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Text parsed as a json number, bool or null when it is one, so that
+/// captures like `42` become numbers.
+fn scalar(text: &str) -> Value {
+    match serde_json::from_str(text) {
+        Ok(value @ (Value::Number(_) | Value::Bool(_) | Value::Null)) => value,
+        _ => Value::String(text.to_string()),
+    }
+}
+
+fn captures(regex: &Regex, text: &str) -> Option<Value> {
+    let captures = regex.captures(text)?;
+    let names: Vec<&str> = regex.capture_names().flatten().collect();
+    Some(if !names.is_empty() {
+        names
+            .into_iter()
+            .map(|name| {
+                let value = captures
+                    .name(name)
+                    .map_or(Value::Null, |value| scalar(value.as_str()));
+                (name.to_string(), value)
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    } else {
+        let value = captures.get(1).or_else(|| captures.get(0))?;
+        scalar(value.as_str())
+    })
+}
+
+/// Follows `segments` from `value`, collecting an array at every `All`.
+fn select(transform: &Transform, value: Value, segments: &[Segment]) -> Result<Value> {
+    let Some((segment, rest)) = segments.split_first() else {
+        return Ok(value);
+    };
+    let missing = || failed(transform, format!("nothing at {segment:?}"));
+    match (segment, value) {
+        (Segment::Key(key), Value::Object(mut object)) => {
+            select(transform, object.remove(key).ok_or_else(missing)?, rest)
+        }
+        (Segment::All, Value::Array(array)) => array
+            .into_iter()
+            .map(|value| select(transform, value, rest))
+            .collect::<Result<_>>()
+            .map(Value::Array),
+        (Segment::All, Value::Object(object)) => object
+            .into_iter()
+            .map(|(_, value)| select(transform, value, rest))
+            .collect::<Result<_>>()
+            .map(Value::Array),
+        (segment, Value::Array(mut array)) => match segment.index() {
+            Some(index) if index < array.len() => select(transform, array.swap_remove(index), rest),
+            _ => Err(missing()),
+        },
+        _ => Err(missing()),
+    }
+}
+
+impl Transform {
+    fn apply(&self, value: Value) -> Result<Value> {
+        let parse = |text: &str| {
+            serde_json::from_str(text)
+                .change_context(Error)
+                .attach_printable(format!("Transform {self} expected json"))
+                .attach(ErrorCode::InvalidJsonOutput)
+                .attach(ErrorDetails(
+                    serde_json::json!({ "transform": self.to_string() }),
+                ))
+        };
+        match (self, value) {
+            (Transform::Trim, Value::String(text)) => Ok(Value::String(text.trim().to_string())),
+            (Transform::Lines, Value::String(text)) => Ok(text
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| Value::String(line.to_string()))
+                .collect()),
+            (Transform::Json, Value::String(text)) => parse(&text),
+            (Transform::Path(_, segments), Value::String(text)) => {
+                select(self, parse(&text)?, segments)
+            }
+            (Transform::Path(_, segments), value) => select(self, value, segments),
+            (Transform::Regex(regex), Value::String(text)) => {
+                captures(regex, &text).ok_or_else(|| failed(self, "the output doesn't match"))
+            }
+            (Transform::Regex(regex), Value::Array(values)) => Ok(values
+                .iter()
+                .filter_map(|value| value.as_str().and_then(|text| captures(regex, text)))
+                .collect()),
+            // Text transforms apply to each element of arrays, e.g. from `lines`
+            (Transform::Trim | Transform::Json, Value::Array(values)) => values
+                .into_iter()
+                .map(|value| self.apply(value))
+                .collect::<Result<_>>()
+                .map(Value::Array),
+            (_, value) => Err(failed(self, format!("can't transform {}", kind(&value)))),
+        }
+    }
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a bool",
+        Value::Number(_) => "a number",
+        Value::String(_) => "text",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// Runs `stdout` through `transforms` in order.
+pub fn apply(transforms: &[Transform], stdout: String) -> Result<Value> {
+    transforms
+        .iter()
+        .try_fold(Value::String(stdout), |value, transform| {
+            transform.apply(value)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(transforms: &str, stdout: &str) -> Value {
+        apply(&parse(transforms).unwrap(), stdout.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_transforms() {
+        assert_eq!(run("trim", "  42\n"), "42");
+        assert_eq!(
+            run("regex:brightness: (?<brightness>\\d+)", "brightness: 42\n"),
+            serde_json::json!({ "brightness": 42 })
+        );
+        assert_eq!(
+            run(
+                "lines | regex:^(\\S+) connected",
+                "DP-1 connected\nHDMI-1 disconnected\n"
+            ),
+            serde_json::json!(["DP-1"])
+        );
+        let monitors = r#"[{"name": "DP-1", "modes": {"width": 2560}}, {"name": "HDMI-1"}]"#;
+        assert_eq!(run("path:$[0].name", monitors), "DP-1");
+        assert_eq!(
+            run("path:.[*].name", monitors),
+            serde_json::json!(["DP-1", "HDMI-1"])
+        );
+        assert_eq!(run("path:[0][\"modes\"].width", monitors), 2560);
+        assert_eq!(run("path:0.name", monitors), "DP-1");
+        assert_eq!(run("regex:(DP |[ ]HDMI)", "DP-1 | HDMI-1"), " HDMI");
+        assert_eq!(
+            run("lines | json", "1\n{\"a\": 2}\n"),
+            serde_json::json!([1, {"a": 2}])
+        );
+        assert_eq!(run("", "text"), "text");
+
+        assert!(parse("regex:(").is_err());
+        assert!(parse("path:.a[x]").is_err());
+        assert!(parse("upper").is_err());
+        let missing = apply(&parse("path:[5]").unwrap(), monitors.to_string()).unwrap_err();
+        assert_eq!(
+            missing.downcast_ref::<ErrorCode>(),
+            Some(&ErrorCode::TransformFailed)
+        );
+        let transform: Transform = serde_json::from_str("\"path:.a[0]\"").unwrap();
+        assert_eq!(serde_json::to_string(&transform).unwrap(), "\"path:.a[0]\"");
+    }
+}
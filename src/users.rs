@@ -6,8 +6,10 @@ use tokio::task;
 
 #[derive(Clone, Serialize, Deserialize, FromRow)]
 pub struct User {
-    id: i64,
+    #[sqlx(try_from = "crate::command::UuidWrapper")]
+    pub id: uuid::Uuid,
     pub username: String,
+    #[serde(skip_serializing)]
     password: String,
 }
 
@@ -24,7 +26,7 @@ impl std::fmt::Debug for User {
 }
 
 impl AuthUser for User {
-    type Id = i64;
+    type Id = uuid::Uuid;
 
     fn id(&self) -> Self::Id {
         self.id
@@ -40,21 +42,23 @@ impl AuthUser for User {
 
 // This allows us to extract the authentication fields from forms. We use this
 // to authenticate requests with the backend.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, utoipa::ToSchema)]
 pub struct Credentials {
     pub username: String,
     pub password: String,
+    /// Path on this server to redirect to once logged in
     pub next: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Backend {
     db: SqlitePool,
+    limiter: std::sync::Arc<crate::ratelimit::Limiter>,
 }
 
 impl Backend {
-    pub fn new(db: SqlitePool) -> Self {
-        Self { db }
+    pub fn new(db: SqlitePool, limiter: std::sync::Arc<crate::ratelimit::Limiter>) -> Self {
+        Self { db, limiter }
     }
 }
 
@@ -67,6 +71,7 @@ pub enum Error {
     TaskJoin(#[from] task::JoinError),
 }
 
+#[async_trait::async_trait]
 impl AuthnBackend for Backend {
     type User = User;
     type Credentials = Credentials;
@@ -76,24 +81,30 @@ impl AuthnBackend for Backend {
         &self,
         creds: Self::Credentials,
     ) -> Result<Option<Self::User>, Self::Error> {
+        // Locked out usernames fail without checking the password
+        if self.limiter.check_login(&creds.username).is_err() {
+            return Ok(None);
+        }
         let user: Option<Self::User> = sqlx::query_as("select * from users where username = ? ")
-            .bind(creds.username)
+            .bind(&creds.username)
             .fetch_optional(&self.db)
             .await?;
 
         // Verifying the password is blocking and potentially slow, so we'll do so via
         // `spawn_blocking`.
-        task::spawn_blocking(|| {
+        let user = task::spawn_blocking(|| {
             // We're using password-based authentication--this works by comparing our form
             // input with an argon2 password hash.
-            Ok(user.filter(|user| verify_password(creds.password, &user.password).is_ok()))
+            user.filter(|user| verify_password(creds.password, &user.password).is_ok())
         })
-        .await?
+        .await?;
+        self.limiter.login_attempt(&creds.username, user.is_some());
+        Ok(user)
     }
 
     async fn get_user(&self, user_id: &UserId<Self>) -> Result<Option<Self::User>, Self::Error> {
         let user = sqlx::query_as("select * from users where id = ?")
-            .bind(user_id)
+            .bind(user_id.as_simple())
             .fetch_optional(&self.db)
             .await?;
 
@@ -101,6 +112,59 @@ impl AuthnBackend for Backend {
     }
 }
 
+impl User {
+    pub async fn add(
+        database: &SqlitePool,
+        username: String,
+        password: String,
+    ) -> crate::Result<uuid::Uuid> {
+        use crate::ResultExt;
+        let id = uuid::Uuid::new_v4();
+        let hash = task::spawn_blocking(move || password_auth::generate_hash(password))
+            .await
+            .change_context(crate::Error)?;
+        sqlx::query("INSERT INTO users (id, username, password) VALUES (?, ?, ?)")
+            .bind(id.as_simple())
+            .bind(&username)
+            .bind(hash)
+            .execute(database)
+            .await
+            .change_context(crate::Error)
+            .attach_printable(format!("Failed to add user: {}", username))?;
+        Ok(id)
+    }
+
+    pub async fn delete(&self, database: &SqlitePool) -> crate::Result<()> {
+        use crate::ResultExt;
+        sqlx::query("DELETE FROM users WHERE id = ?")
+            .bind(self.id.as_simple())
+            .execute(database)
+            .await
+            .change_context(crate::Error)
+            .attach_printable(format!("Failed to delete user: {}", self.username))?;
+        Ok(())
+    }
+
+    pub async fn list(database: &SqlitePool) -> crate::Result<Vec<User>> {
+        use crate::ResultExt;
+        sqlx::query_as("SELECT * FROM users")
+            .fetch_all(database)
+            .await
+            .change_context(crate::Error)
+            .attach_printable("Failed to list users")
+    }
+
+    pub async fn username(database: &SqlitePool, username: &str) -> crate::Result<Option<User>> {
+        use crate::ResultExt;
+        sqlx::query_as("SELECT * FROM users WHERE username = ?")
+            .bind(username)
+            .fetch_optional(database)
+            .await
+            .change_context(crate::Error)
+            .attach_printable(format!("Failed to query user with username: {}", username))
+    }
+}
+
 // We use a type alias for convenience.
 //
 // Note that we've supplied our concrete backend here.
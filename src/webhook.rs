@@ -0,0 +1,386 @@
+//! Webhooks let other services run a command by posting to `/hooks/{id}`,
+//! signed with a shared secret the way GitHub and Gitea sign their payloads.
+
+use crate::{
+    command::{Command, Identifier, Origin, Trigger, UuidWrapper},
+    *,
+};
+use hmac::{Hmac, Mac};
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+
+/// Headers carrying the hex encoded HMAC-SHA256 of the body, GitHub's with a
+/// `sha256=` prefix.
+const SIGNATURE_HEADERS: [&str; 2] = ["x-hub-signature-256", "x-gitea-signature"];
+const EVENT_HEADERS: [&str; 2] = ["x-github-event", "x-gitea-event"];
+const DELIVERY_HEADERS: [&str; 2] = ["x-github-delivery", "x-gitea-delivery"];
+/// Shorter secrets are rejected, an empty one would let anyone sign payloads.
+const MIN_SECRET_LENGTH: usize = 16;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, sqlx::FromRow, utoipa::ToSchema)]
+pub struct Webhook {
+    #[sqlx(try_from = "UuidWrapper")]
+    pub id: uuid::Uuid,
+    #[sqlx(try_from = "UuidWrapper")]
+    pub command_id: uuid::Uuid,
+    /// Name of the command
+    pub command: String,
+    /// Json paths into the payload giving the value of each placeholder
+    #[sqlx(json)]
+    pub placeholders: BTreeMap<String, String>,
+    /// The secret payloads are signed with, only returned when the webhook
+    /// is added
+    #[sqlx(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct NewWebhook {
+    /// Id of the command to run
+    #[serde(default)]
+    pub command_id: Option<uuid::Uuid>,
+    /// Name of the command to run, when no id is given
+    #[serde(default)]
+    pub command: Option<String>,
+    /// Secret shared with the sender, at least 16 bytes, generated when not
+    /// given
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Json paths into the payload, like `$.repository.name`, by placeholder
+    #[serde(default)]
+    pub placeholders: BTreeMap<String, String>,
+}
+
+impl NewWebhook {
+    /// The command to run.
+    pub fn identifier(&self) -> Result<Identifier> {
+        match (self.command_id, &self.command) {
+            (Some(id), _) => Ok(Identifier::Id(id)),
+            (None, Some(name)) => Ok(Identifier::Name(name.clone())),
+            (None, None) => Err(Error::new()
+                .attach_printable("Webhook without a command")
+                .attach(ErrorCode::BadRequest)
+                .attach(ErrorDetails(serde_json::json!({
+                    "reason": "A webhook needs a command_id or command"
+                })))),
+        }
+    }
+
+    /// The secret given, or a generated one.
+    pub fn secret(&self) -> Result<String> {
+        match &self.secret {
+            Some(secret) if secret.len() < MIN_SECRET_LENGTH => Err(Error::new()
+                .attach_printable("Webhook secret is too short")
+                .attach(ErrorCode::BadRequest)
+                .attach(ErrorDetails(serde_json::json!({
+                    "reason": format!(
+                        "A webhook secret needs at least {MIN_SECRET_LENGTH} bytes"
+                    )
+                })))),
+            Some(secret) => Ok(secret.clone()),
+            None => Ok(format!(
+                "{}{}",
+                uuid::Uuid::new_v4().simple(),
+                uuid::Uuid::new_v4().simple()
+            )),
+        }
+    }
+}
+
+impl Webhook {
+    pub async fn add(database: &SqlitePool, new: NewWebhook) -> Result<Webhook> {
+        for path in new.placeholders.values() {
+            json_path::check(path)?;
+        }
+        let secret = new.secret()?;
+        let command = Command::identifier(database, new.identifier()?).await?;
+        let id = uuid::Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO webhooks (id, command_id, secret, placeholders) VALUES (?, ?, ?, ?)",
+        )
+        .bind(id.as_simple())
+        .bind(command.id.as_simple())
+        .bind(&secret)
+        .bind(sqlx::types::Json(&new.placeholders))
+        .execute(database)
+        .await
+        .change_context(Error)
+        .attach_printable(format!("Failed to add webhook for: {}", command.name))
+        .attach(ErrorCode::Database)?;
+        let mut webhook = Self::id(database, id).await?;
+        webhook.secret = Some(secret);
+        Ok(webhook)
+    }
+
+    pub async fn list(database: &SqlitePool) -> Result<Vec<Webhook>> {
+        sqlx::query_as(
+            "SELECT w.id, w.command_id, c.name AS command, w.placeholders
+            FROM webhooks w JOIN commands c ON c.id = w.command_id
+            ORDER BY c.name, w.created_at",
+        )
+        .fetch_all(database)
+        .await
+        .change_context(Error)
+        .attach_printable("Failed to list webhooks")
+        .attach(ErrorCode::Database)
+    }
+
+    pub async fn id(database: &SqlitePool, id: uuid::Uuid) -> Result<Webhook> {
+        let webhook: Option<Webhook> = sqlx::query_as(
+            "SELECT w.id, w.command_id, c.name AS command, w.placeholders
+            FROM webhooks w JOIN commands c ON c.id = w.command_id
+            WHERE w.id = ?",
+        )
+        .bind(id.as_simple())
+        .fetch_optional(database)
+        .await
+        .change_context(Error)
+        .attach_printable(format!("Failed to query webhook: {id}"))
+        .attach(ErrorCode::Database)?;
+        webhook.ok_or_else(|| {
+            Error::new()
+                .attach_printable(format!("No webhook found with id: {id}"))
+                .attach(ErrorCode::WebhookNotFound)
+                .attach(ErrorDetails(serde_json::json!({ "id": id })))
+        })
+    }
+
+    pub async fn delete(&self, database: &SqlitePool) -> Result<()> {
+        sqlx::query("DELETE FROM webhooks WHERE id = ?")
+            .bind(self.id.as_simple())
+            .execute(database)
+            .await
+            .change_context(Error)
+            .attach_printable(format!("Failed to delete webhook: {}", self.id))
+            .attach(ErrorCode::Database)?;
+        Ok(())
+    }
+
+    async fn secret(&self, database: &SqlitePool) -> Result<String> {
+        sqlx::query_scalar("SELECT secret FROM webhooks WHERE id = ?")
+            .bind(self.id.as_simple())
+            .fetch_one(database)
+            .await
+            .change_context(Error)
+            .attach_printable(format!("Failed to query webhook: {}", self.id))
+            .attach(ErrorCode::Database)
+    }
+
+    /// The placeholder values picked from the payload.
+    fn values(&self, body: &[u8]) -> Result<BTreeMap<String, String>> {
+        if self.placeholders.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+        let payload: serde_json::Value = serde_json::from_slice(body)
+            .change_context(Error)
+            .attach_printable("Webhook payload is not valid json")
+            .attach(ErrorCode::BadRequest)
+            .attach(ErrorDetails(
+                serde_json::json!({ "reason": "The payload must be json" }),
+            ))?;
+        self.placeholders
+            .iter()
+            .map(|(placeholder, path)| {
+                let value = json_path::select(&payload, path)?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| {
+                        Error::new()
+                            .attach_printable(format!("Nothing at {path} in the payload"))
+                            .attach(ErrorCode::PlaceholderMissing)
+                            .attach(ErrorDetails(
+                                serde_json::json!({ "placeholder": placeholder, "path": path }),
+                            ))
+                    })?;
+                Ok((
+                    placeholder.clone(),
+                    match value {
+                        serde_json::Value::String(value) => value.clone(),
+                        value => value.to_string(),
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    /// Verifies the signature of `body` and starts the command in the
+    /// background, returning once the placeholders have been filled in.
+    /// Commands that require approval are only requested.
+    pub async fn trigger(
+        &self,
+        database: &SqlitePool,
+        settings: &config::Settings,
+        headers: &http::HeaderMap,
+        body: &[u8],
+        peer: &app::Peer,
+    ) -> Result<()> {
+        verify(&self.secret(database).await?, headers, body)?;
+        let values = self.values(body)?;
+        let header = |names: [&str; 2]| {
+            names
+                .iter()
+                .find_map(|name| headers.get(*name)?.to_str().ok())
+                .map(str::to_owned)
+        };
+        let origin = Origin {
+            trigger: Trigger::Webhook,
+            webhook: Some(self.id),
+            trigger_metadata: Some(serde_json::json!({
+                "event": header(EVENT_HEADERS),
+                "delivery": header(DELIVERY_HEADERS),
+                "peer": peer.addr.ip().to_string(),
+            })),
+            ..Origin::default()
+        };
+        let command = Command::identifier(database, Identifier::Id(self.command_id)).await?;
+        settings
+            .access
+            .check_command(&command.name, peer.addr.ip())?;
+        settings.limiter.check_command(&command.name)?;
+        let event = |action| {
+            audit::Event::new(audit::Actor::Webhook, Some(self.id.to_string()), action)
+                .with_source(peer)
+        };
+        if command.metadata.requires_approval {
+            approval::Approval::request(
+                database,
+                settings,
+                &command,
+                &values,
+                event(audit::Action::Request),
+            )
+            .await?;
+            return Ok(());
+        }
+        event(audit::Action::Run)
+            .with_command(&command)
+            .with_placeholders(&values)
+            .with_details(origin.trigger_metadata.clone().unwrap_or_default())
+            .record(database)
+            .await?;
+        tracing::info!(
+            "Webhook {} triggered {} from {}",
+            self.id,
+            command.name,
+            peer.addr
+        );
+        let database = database.clone();
+        let settings = settings.clone();
+        tokio::spawn(async move {
+            let result = match command.run_with_placeholder(&settings, values).await {
+                Ok(output) => output.save(&database, &settings, command.id, &origin).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                tracing::error!("Webhook run of {} failed: {e:?}", command.name);
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Checks the HMAC-SHA256 signature in the headers against `body`.
+fn verify(secret: &str, headers: &http::HeaderMap, body: &[u8]) -> Result<()> {
+    let invalid = |reason: &str| {
+        Error::new()
+            .attach_printable(reason.to_owned())
+            .attach(ErrorCode::InvalidSignature)
+    };
+    let signature = SIGNATURE_HEADERS
+        .iter()
+        .find_map(|name| headers.get(*name))
+        .ok_or_else(|| invalid("Webhook request without a signature"))?
+        .to_str()
+        .map_err(|_| invalid("Invalid webhook signature header"))?;
+    let signature = hex::decode(signature.strip_prefix("sha256=").unwrap_or(signature))
+        .map_err(|_| invalid("Invalid webhook signature header"))?;
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes())
+        .change_context(Error)
+        .attach(ErrorCode::Internal)?;
+    mac.update(body);
+    mac.verify_slice(&signature)
+        .map_err(|_| invalid("Webhook signature doesn't match the payload"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let body = br#"{"ref":"refs/heads/main"}"#;
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(body);
+        let expected = hex::encode(mac.finalize().into_bytes());
+        let headers = |name: &str, value: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert(
+                http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+            headers
+        };
+        assert!(
+            verify(
+                "secret",
+                &headers("x-hub-signature-256", &format!("sha256={expected}")),
+                body
+            )
+            .is_ok()
+        );
+        assert!(verify("secret", &headers("x-gitea-signature", &expected), body).is_ok());
+        assert!(verify("other", &headers("x-gitea-signature", &expected), body).is_err());
+        assert!(
+            verify(
+                "secret",
+                &headers("x-gitea-signature", &"00".repeat(32)),
+                body
+            )
+            .is_err()
+        );
+        assert!(verify("secret", &http::HeaderMap::new(), body).is_err());
+    }
+
+    #[test]
+    fn test_secret() {
+        let new = |secret: Option<&str>| NewWebhook {
+            command_id: None,
+            command: Some("deploy".into()),
+            secret: secret.map(str::to_owned),
+            placeholders: BTreeMap::new(),
+        };
+        let code = |report: Report<Error>| report.downcast_ref::<ErrorCode>().copied();
+        assert_eq!(
+            new(Some("")).secret().map_err(code).unwrap_err(),
+            Some(ErrorCode::BadRequest)
+        );
+        assert!(new(Some("short")).secret().is_err());
+        assert_eq!(
+            new(Some("0123456789abcdef")).secret().unwrap(),
+            "0123456789abcdef"
+        );
+        assert_eq!(new(None).secret().unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_values() {
+        let webhook = Webhook {
+            id: uuid::Uuid::nil(),
+            command_id: uuid::Uuid::nil(),
+            command: "deploy".into(),
+            placeholders: BTreeMap::from([
+                ("repo".into(), "$.repository.name".into()),
+                ("pr".into(), "$.number".into()),
+            ]),
+            secret: None,
+        };
+        let values = webhook
+            .values(br#"{"number": 7, "repository": {"name": "runner"}}"#)
+            .unwrap();
+        assert_eq!(values["repo"], "runner");
+        assert_eq!(values["pr"], "7");
+        assert!(webhook.values(br#"{"number": 7}"#).is_err());
+        assert!(webhook.values(b"not json").is_err());
+    }
+}